@@ -1,31 +1,493 @@
-use rconpro::{Service, EipAddr, ConsumerHint, ConsumerQueue};
-use std::net::{IpAddr, Ipv4Addr};
+use rconpro::{identity, tag, CaptureFormat, CaptureReader, CipRoute, ConsumerHint, ConsumerQueue, ConsumerState, EipAddr, Identity};
+use rconpro::{PlcProfile, Recorder, Replay, ReplaySpeed, Service, ServiceConfig, TagValue};
+use rconpro::daemon::{csv_field, text, timestamp, Decode};
+use rconpro::dissect::parse_hex;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use std::io::{self, Error, ErrorKind, Read, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/*
+rconpro: talk to EtherNet/IP devices from the command line
+*/
+#[derive(Parser)]
+#[command(name = "rconpro", version, about = "Talk to EtherNet/IP devices")]
+struct Cli {
+  /// Slot of the controller in the PLC's chassis
+  #[arg(long, global = true, default_value_t = 0)]
+  slot: u8,
+
+  /// Route to the controller instead of the profile's, as port,link pairs (e.g. 1,0,2,10.0.0.5)
+  #[arg(long, global = true)]
+  route: Option<CipRoute>,
+
+  /// What kind of device the PLC is
+  #[arg(long, global = true, value_enum, default_value_t = PlcProfile::ControlLogix)]
+  profile: PlcProfile,
+
+  /// How to print results
+  #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+  format: Format,
+
+  /// Local address to send from
+  #[arg(long, global = true)]
+  bind: Option<IpAddr>,
+
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Find devices with a ListIdentity broadcast
+  Discover {
+    /// Where to send the broadcast
+    #[arg(long, default_value = "255.255.255.255:44818")]
+    broadcast: SocketAddr,
+    /// How long to wait for replies, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    timeout: u64,
+  },
+  /// Show a device's identity
+  Identity { plc: IpAddr },
+  /// List a controller's tags
+  ListTags {
+    plc: IpAddr,
+    /// Include system tags
+    #[arg(long)]
+    all: bool,
+  },
+  /// Read tags
+  Read {
+    plc: IpAddr,
+    #[arg(required = true)]
+    tags: Vec<String>,
+  },
+  /// Write a tag, as its current type unless one is given
+  Write {
+    plc: IpAddr,
+    tag: String,
+    value: String,
+    /// The tag's type, like DINT or STRING
+    #[arg(long = "type")]
+    data_type: Option<String>,
+  },
+  /// Open a class 1 connection to a produced tag and stream its values
+  Consume(ConsumeArgs),
+  /// Consume a tag while recording the traffic to a capture file
+  Record {
+    #[command(flatten)]
+    consume: ConsumeArgs,
+    /// The capture file
+    #[arg(long)]
+    out: PathBuf,
+    /// pcapng or native; by default, what the file name suggests
+    #[arg(long)]
+    capture_format: Option<CaptureFormat>,
+  },
+  /// Play a capture's I/O data back as if it were live
+  Replay {
+    capture: PathBuf,
+    /// The tag to play back; without one, the capture's connections are listed
+    #[arg(long)]
+    tag: Option<String>,
+    /// A T->O connection ID to play back, for captures without Forward Opens
+    #[arg(long, value_parser = parse_u32)]
+    connection: Option<u32>,
+    /// The PLC to play back from, if the capture talked to several
+    #[arg(long)]
+    plc: Option<IpAddr>,
+    /// Don't wait between packets
+    #[arg(long)]
+    fast: bool,
+    #[command(flatten)]
+    decode: DecodeArgs,
+  },
+  /// Dissect a capture file, or one packet given as hex (on stdin if not given)
+  Dissect { input: Vec<String> },
+}
+
+#[derive(Args)]
+struct ConsumeArgs {
+  plc: IpAddr,
+  tag: String,
+  /// Size of the tag's data in bytes
+  #[arg(long)]
+  size: usize,
+  /// Requested packet interval in milliseconds
+  #[arg(long, default_value_t = 100)]
+  rpi: usize,
+  /// Stop after this many values
+  #[arg(long)]
+  count: Option<usize>,
+  /// Stop after this many seconds
+  #[arg(long)]
+  seconds: Option<u64>,
+  #[command(flatten)]
+  decode: DecodeArgs,
+}
+
+#[derive(Args, Clone)]
+struct DecodeArgs {
+  /// Decode the data as elements of this type (e.g. DINT) instead of printing hex
+  #[arg(long)]
+  decode: Option<String>,
+  /// Skip this many bytes of the data before decoding
+  #[arg(long, default_value_t = 0)]
+  offset: usize,
+}
+
+impl DecodeArgs {
+  fn decoder(&self) -> Result<Decode> {
+    let decode = Decode { data_type: self.decode.clone(), offset: self.offset };
+    decode.check()?;
+    Ok(decode)
+  }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+  Text,
+  Json,
+  Csv,
+}
 
 fn main() {
-  let mut service = Service::new();
-  service.start().unwrap();
+  let cli = Cli::parse();
+  if let Err(e) = run(cli) {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  }
+}
 
-  let addr = EipAddr {
-    addr: IpAddr::V4(Ipv4Addr::new(172, 16, 13, 200)),
-    slot: 0,
+fn run(cli: Cli) -> Result<()> {
+  let mut out = Output::new(cli.format);
+  let connect = |plc: IpAddr| -> Result<(Service, EipAddr)> {
+    let mut config = ServiceConfig::default();
+    if let Some(bind) = cli.bind {
+      config.bind_addr = bind;
+    }
+    let service = Service::with_config(config);
+    let addr = EipAddr { addr: plc, slot: cli.slot };
+    service.set_profile(addr, cli.profile)?;
+    Ok((service, addr))
   };
 
+  match &cli.command {
+    Command::Discover { broadcast, timeout } => {
+      let bind = cli.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+      for identity in identity::discover(bind, *broadcast, Duration::from_millis(*timeout))? {
+        out.row(&identity_columns(&identity));
+      }
+    },
+    Command::Identity { plc } => {
+      // ListIdentity is answered by the device we're connected to
+      if cli.route.is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput, "identity can't be routed"));
+      }
+      let (service, addr) = connect(*plc)?;
+      out.record(&identity_columns(&service.identity(addr)?));
+    },
+    Command::ListTags { plc, all } => {
+      let (service, addr) = connect(*plc)?;
+      let tags = match &cli.route {
+        Some(route) => service.list_tags_routed(addr, route)?,
+        None => service.list_tags(addr)?,
+      };
+      for tag in tags.iter().filter(|tag| *all || !tag.is_system()) {
+        out.row(&[
+          ("name", json!(tag.name)),
+          ("type", json!(tag.type_description())),
+          ("instance", json!(tag.instance)),
+        ]);
+      }
+    },
+    Command::Read { plc, tags } => {
+      let (service, addr) = connect(*plc)?;
+      for name in tags {
+        let value = read_tag(&service, addr, cli.route.as_ref(), name)?;
+        out.row(&value_columns(name, &value));
+      }
+    },
+    Command::Write { plc, tag, value, data_type } => {
+      let (service, addr) = connect(*plc)?;
+      let data_type = match data_type {
+        Some(data_type) => data_type.clone(),
+        None => String::from(read_tag(&service, addr, cli.route.as_ref(), tag)?.type_name()),
+      };
+      let value = TagValue::parse(&data_type, value)?;
+      write_tag(&service, addr, cli.route.as_ref(), tag, &value)?;
+      out.row(&value_columns(tag, &value));
+    },
+    Command::Consume(args) => {
+      let (mut service, addr) = connect(args.plc)?;
+      consume(&mut service, addr, cli.route.as_ref(), args, &mut out)?;
+    },
+    Command::Record { consume: args, out: path, capture_format } => {
+      let (mut service, addr) = connect(args.plc)?;
+      let format = capture_format.unwrap_or_else(|| CaptureFormat::from_path(path));
+      service.start_recording(Recorder::create(path, format)?);
+      consume(&mut service, addr, cli.route.as_ref(), args, &mut out)?;
+      if let Some(recorder) = service.stop_recording() {
+        recorder.flush()?;
+      }
+    },
+    Command::Replay { capture, tag, connection, plc, fast, decode } => {
+      replay(capture, tag.as_deref(), *connection, *plc, *fast, decode, &mut out)?;
+    },
+    Command::Dissect { input } => dissect(input)?,
+  }
+
+  Ok(())
+}
+
+/*
+Read a tag from the profile's controller, or from the one at the end of a route
+*/
+fn read_tag(service: &Service, addr: EipAddr, route: Option<&CipRoute>, name: &str) -> Result<TagValue> {
+  match route {
+    None => service.read_tag(addr, name),
+    Some(route) => {
+      let reply = service.send_routed(addr, route, &tag::build_read_tag(name)?)?;
+      tag::parse_read_tag_reply(&reply, service.profile(addr).tag_dialect())
+    },
+  }
+}
+
+fn write_tag(service: &Service, addr: EipAddr, route: Option<&CipRoute>, name: &str, value: &TagValue) -> Result<()> {
+  match route {
+    None => service.write_tag(addr, name, value),
+    Some(route) => {
+      let request = tag::build_write_tag(name, value, service.profile(addr).tag_dialect())?;
+      tag::parse_write_tag_reply(&service.send_routed(addr, route, &request)?)
+    },
+  }
+}
+
+/*
+Stream a produced tag's values until the count or time runs out, or Ctrl-C
+*/
+fn consume(service: &mut Service, addr: EipAddr, route: Option<&CipRoute>, args: &ConsumeArgs, out: &mut Output) -> Result<()> {
+  let decoder = args.decode.decoder()?;
+  let running = Arc::new(AtomicBool::new(true));
+  let handler_running = Arc::clone(&running);
+  ctrlc::set_handler(move || handler_running.store(false, Ordering::Release))
+    .map_err(Error::other)?;
+
+  service.start()?;
   let hint = ConsumerHint {
-    tag: String::from("test"),
-    data_size: 6,
-    otrpi: 1_100_000,
-    rpi: 1_000_000
+    tag: args.tag.clone(),
+    // The connection also carries the CIP sequence count
+    data_size: args.size + 2,
+    rpi: args.rpi * 1000,
+    otrpi: args.rpi * 1000,
+    route: route.cloned(),
+    ..ConsumerHint::default()
+  };
+  let queue = Arc::new(ConsumerQueue::new());
+  let handle = service.add_consumer(addr, hint, &queue);
+  match handle.wait(Duration::from_secs(10)) {
+    ConsumerState::Connected { .. } => (),
+    ConsumerState::Retrying { error, .. } | ConsumerState::Failed { error } => {
+      service.stop();
+      return Err(Error::other(format!("couldn't consume {}: {}", args.tag, error)));
+    },
+    _ => {
+      service.stop();
+      return Err(Error::new(ErrorKind::TimedOut, format!("couldn't consume {}: no reply from {}", args.tag, addr.addr)));
+    },
+  }
+
+  let deadline = args.seconds.map(|seconds| Instant::now() + Duration::from_secs(seconds));
+  let mut count = 0;
+  // A connection that times out is opened again in the background
+  let result = loop {
+    if !running.load(Ordering::Acquire)
+      || args.count.is_some_and(|limit| count >= limit)
+      || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+      break Ok(());
+    }
+    match queue.pop() {
+      Some(data) => {
+        out.row(&[("time", json!(timestamp(SystemTime::now()))), ("value", decoder.value(&data))]);
+        count += 1;
+      },
+      None => thread::sleep(Duration::from_millis(1)),
+    }
   };
 
-  let data = Arc::new(ConsumerQueue::new());
+  service.stop_consumer(&handle);
+  service.stop();
+  result
+}
 
-  service.add_consumer(addr, hint, &data)
-    .unwrap();
+fn replay(path: &Path, tag: Option<&str>, connection: Option<u32>, plc: Option<IpAddr>, fast: bool, decode: &DecodeArgs, out: &mut Output) -> Result<()> {
+  let decoder = decode.decoder()?;
+  let mut replay = Replay::open(path)?;
+
+  let from = |addr: &EipAddr| plc.is_none_or(|plc| plc == addr.addr);
+  let addr = match (tag, connection) {
+    (None, None) => {
+      for connection in replay.connections().iter().filter(|connection| from(&connection.addr)) {
+        out.row(&[
+          ("plc", json!(connection.addr.addr.to_string())),
+          ("slot", json!(connection.addr.slot)),
+          ("tag", json!(connection.tag)),
+          ("ot_connection_id", json!(format!("{:#010x}", connection.ot_connection_id))),
+          ("to_connection_id", json!(format!("{:#010x}", connection.to_connection_id))),
+        ]);
+      }
+      return Ok(());
+    },
+    (Some(tag), _) => replay.connections().iter()
+      .find(|connection| from(&connection.addr) && connection.tag.as_deref() == Some(tag))
+      .map(|connection| connection.addr)
+      .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the capture has no connection to {}", tag)))?,
+    (None, Some(_)) => EipAddr {
+      addr: plc.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "--connection needs --plc"))?,
+      slot: 0,
+    },
+  };
 
+  let queue = Arc::new(ConsumerQueue::new());
+  match (tag, connection) {
+    (Some(tag), _) => {
+      replay.add_consumer(addr, ConsumerHint { tag: String::from(tag), ..ConsumerHint::default() }, &queue)?;
+    },
+    (None, Some(connection)) => replay.add_connection(addr, connection, &queue),
+    (None, None) => unreachable!(),
+  }
+
+  let speed = if fast { ReplaySpeed::Fast } else { ReplaySpeed::Original };
+  let player = replay.start(speed)?;
   loop {
-    while !data.is_empty() {
-      println!("{:?}", data.pop());
+    match queue.pop() {
+      Some(data) => out.row(&[("time", json!(timestamp(SystemTime::now()))), ("value", decoder.value(&data))]),
+      None if player.is_finished() && queue.is_empty() => break,
+      None => thread::sleep(Duration::from_millis(1)),
     }
   }
-}
\ No newline at end of file
+  player.join().map_err(|_| Error::other("the replay thread panicked"))?;
+
+  Ok(())
+}
+
+/*
+Dissect every packet of a capture file, or one packet given as hex
+The hex comes from the arguments, or from stdin if there are none.
+*/
+fn dissect(args: &[String]) -> Result<()> {
+  if let [path] = args {
+    if Path::new(path).is_file() {
+      for record in CaptureReader::open(path)? {
+        let record = record?;
+        println!(
+          "{} {:?} {:?} {} slot {} connection {:#010x}",
+          timestamp(record.timestamp), record.direction, record.traffic,
+          record.addr.addr, record.addr.slot, record.connection_id
+        );
+        match rconpro::dissect(&record.data) {
+          Ok(dissection) => print!("{}", dissection),
+          Err(e) => println!("Couldn't dissect: {}", e),
+        }
+        println!();
+      }
+      return Ok(());
+    }
+  }
+
+  let text = if args.is_empty() {
+    let mut text = String::new();
+    io::stdin().read_to_string(&mut text)?;
+    text
+  } else {
+    args.join(" ")
+  };
+  print!("{}", rconpro::dissect(&parse_hex(&text)?)?);
+
+  Ok(())
+}
+
+/*
+Prints results as text, JSON or CSV
+Rows are streamed: JSON as one object per line, CSV with the header first.
+*/
+struct Output {
+  format: Format,
+  header_written: bool,
+}
+impl Output {
+  fn new(format: Format) -> Output {
+    Output { format, header_written: false }
+  }
+
+  fn row(&mut self, columns: &[(&str, Value)]) {
+    match self.format {
+      Format::Text => {
+        let fields: Vec<String> = columns.iter().map(|(_, value)| text(value)).collect();
+        println!("{}", fields.join("\t"));
+      },
+      Format::Json => println!("{}", object(columns)),
+      Format::Csv => {
+        if !self.header_written {
+          let names: Vec<String> = columns.iter().map(|(name, _)| csv_field(name)).collect();
+          println!("{}", names.join(","));
+          self.header_written = true;
+        }
+        let fields: Vec<String> = columns.iter().map(|(_, value)| csv_field(&text(value))).collect();
+        println!("{}", fields.join(","));
+      },
+    }
+  }
+
+  /*
+  A single result, which text shows one field per line
+  */
+  fn record(&mut self, columns: &[(&str, Value)]) {
+    match self.format {
+      Format::Text => {
+        for (name, value) in columns {
+          println!("{}: {}", name, text(value));
+        }
+      },
+      _ => self.row(columns),
+    }
+  }
+}
+
+fn object(columns: &[(&str, Value)]) -> Value {
+  Value::Object(columns.iter().map(|(name, value)| (String::from(*name), value.clone())).collect())
+}
+
+fn parse_u32(text: &str) -> std::result::Result<u32, String> {
+  match text.strip_prefix("0x") {
+    Some(hex) => u32::from_str_radix(hex, 16),
+    None => text.parse(),
+  }.map_err(|e| e.to_string())
+}
+
+fn value_columns(name: &str, value: &TagValue) -> [(&'static str, Value); 3] {
+  [
+    ("tag", json!(name)),
+    ("type", json!(value.type_name())),
+    ("value", value.to_json()),
+  ]
+}
+
+fn identity_columns(identity: &Identity) -> [(&'static str, Value); 8] {
+  [
+    ("address", json!(identity.socket_addr.ip().to_string())),
+    ("product_name", json!(identity.product_name)),
+    ("vendor_id", json!(identity.vendor_id)),
+    ("device_type", json!(identity.device_type)),
+    ("product_code", json!(identity.product_code)),
+    ("revision", json!(format!("{}.{}", identity.major_revision, identity.minor_revision))),
+    ("serial_number", json!(format!("{:#010x}", identity.serial_number))),
+    ("state", json!(identity.state)),
+  ]
+}
@@ -0,0 +1,824 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write, Result, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, LittleEndian, BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+
+use crate::EipAddr;
+
+// Native format
+const NATIVE_MAGIC: &[u8; 4] = b"RCAP";
+const NATIVE_VERSION: u16 = 1;
+
+// pcapng blocks and options
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x01;
+const ENHANCED_PACKET_BLOCK: u32 = 0x06;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_EPB_FLAGS: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+
+// Packets are written as UDP on the ports Wireshark dissects them on
+const IO_PORT: u16 = 2222;
+const ENCAP_PORT: u16 = 44818;
+const UDP: u8 = 17;
+
+/*
+Which way a packet went, from our side
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum Direction {
+  Received,
+  Sent,
+}
+
+/*
+Class 1 I/O packets, or explicit messages on a setup stream
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum Traffic {
+  Io,
+  Explicit,
+}
+
+/*
+One recorded packet
+connection_id is the one in the packet's address item, and 0 for explicit
+messages. data is the whole packet: the CPF items of a class 1 packet, or the
+encapsulation frame of an explicit message.
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct Record {
+  pub timestamp: SystemTime,
+  pub direction: Direction,
+  pub traffic: Traffic,
+  pub connection_id: u32,
+  pub addr: EipAddr,
+  pub data: Vec<u8>,
+}
+
+/*
+How a capture file is written
+Pcapng opens in Wireshark. Every packet becomes a raw IP/UDP packet between
+the PLC and the unspecified address: class 1 packets on port 2222, explicit
+messages on 44818 (Wireshark dissects ENIP over UDP the same as over TCP). The
+slot goes in the packet comment. Native is compact and keeps every field as is.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum CaptureFormat {
+  #[default]
+  Pcapng,
+  Native,
+}
+impl CaptureFormat {
+
+  /*
+  The format a file name suggests: .pcapng is pcapng, anything else native
+  */
+  pub fn from_path(path: &Path) -> CaptureFormat {
+    match path.extension().and_then(|extension| extension.to_str()) {
+      Some("pcapng") => CaptureFormat::Pcapng,
+      _ => CaptureFormat::Native,
+    }
+  }
+}
+impl FromStr for CaptureFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<CaptureFormat> {
+    match s {
+      "pcapng" => Ok(CaptureFormat::Pcapng),
+      "native" => Ok(CaptureFormat::Native),
+      _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown capture format {:?}", s))),
+    }
+  }
+}
+impl fmt::Display for CaptureFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CaptureFormat::Pcapng => write!(f, "pcapng"),
+      CaptureFormat::Native => write!(f, "native"),
+    }
+  }
+}
+
+/*
+Writes records to a capture file
+The file header goes out when the recorder is created. Records from different
+threads are written whole, one at a time.
+*/
+pub struct Recorder {
+  format: CaptureFormat,
+  out: Mutex<Box<dyn Write + Send>>,
+}
+impl Recorder {
+  pub fn create<P: AsRef<Path>>(path: P, format: CaptureFormat) -> Result<Recorder> {
+    Recorder::new(BufWriter::new(File::create(path)?), format)
+  }
+
+  pub fn new<W: Write + Send + 'static>(mut out: W, format: CaptureFormat) -> Result<Recorder> {
+    match format {
+      CaptureFormat::Pcapng => {
+        out.write_all(&build_section_header())?;
+        out.write_all(&build_interface_description())?;
+      },
+      CaptureFormat::Native => {
+        out.write_all(NATIVE_MAGIC)?;
+        out.write_u16::<LittleEndian>(NATIVE_VERSION)?;
+      },
+    }
+
+    Ok(Recorder {
+      format,
+      out: Mutex::new(Box::new(out)),
+    })
+  }
+
+  pub fn format(&self) -> CaptureFormat {
+    self.format
+  }
+
+  pub fn record(&self, record: &Record) -> Result<()> {
+    let block = match self.format {
+      CaptureFormat::Pcapng => build_packet_block(record),
+      CaptureFormat::Native => build_native_record(record),
+    };
+
+    self.out.lock().unwrap().write_all(&block)
+  }
+
+  pub fn flush(&self) -> Result<()> {
+    self.out.lock().unwrap().flush()
+  }
+}
+impl Drop for Recorder {
+  fn drop(&mut self) {
+    let _ = self.flush();
+  }
+}
+
+/*
+The recorder a Service's sockets write to, if it's recording
+Recording failures are reported and otherwise ignored, so they never get in the
+way of the traffic itself.
+*/
+#[derive(Clone, Default)]
+pub(crate) struct RecorderHandle(Arc<RwLock<Option<Recorder>>>);
+impl RecorderHandle {
+  pub(crate) fn set(&self, recorder: Option<Recorder>) -> Option<Recorder> {
+    let previous = std::mem::replace(&mut *self.0.write().unwrap(), recorder);
+    if let Some(previous) = previous.as_ref() {
+      if let Err(e) = previous.flush() {
+        eprintln!("Couldn't flush the capture: {}", e);
+      }
+    }
+
+    previous
+  }
+
+  pub(crate) fn record(&self, direction: Direction, traffic: Traffic, addr: EipAddr, data: &[u8]) {
+    if let Some(recorder) = self.0.read().unwrap().as_ref() {
+      let connection_id = match traffic {
+        Traffic::Io => io_connection_id(data).unwrap_or(0),
+        Traffic::Explicit => 0,
+      };
+      let record = Record {
+        timestamp: SystemTime::now(),
+        direction,
+        traffic,
+        connection_id,
+        addr,
+        data: data.to_vec(),
+      };
+      if let Err(e) = recorder.record(&record) {
+        eprintln!("Couldn't record a packet: {}", e);
+      }
+    }
+  }
+}
+
+/*
+The connection ID in a class 1 packet's address item
+*/
+pub(crate) fn io_connection_id(packet: &[u8]) -> Option<u32> {
+  packet.get(6..10).map(LittleEndian::read_u32)
+}
+
+fn micros_since_epoch(timestamp: SystemTime) -> u64 {
+  timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/*
+Native record: timestamp (us since the epoch), direction, traffic, connection
+ID, IP version and address, slot, data length and data; all little endian
+*/
+fn build_native_record(record: &Record) -> Vec<u8> {
+  let mut block = vec![];
+  block.write_u64::<LittleEndian>(micros_since_epoch(record.timestamp)).unwrap();
+  block.write_u8(match record.direction { Direction::Received => 0, Direction::Sent => 1 }).unwrap();
+  block.write_u8(match record.traffic { Traffic::Io => 0, Traffic::Explicit => 1 }).unwrap();
+  block.write_u32::<LittleEndian>(record.connection_id).unwrap();
+  match record.addr.addr {
+    IpAddr::V4(ip) => {
+      block.write_u8(4).unwrap();
+      block.extend_from_slice(&ip.octets());
+    },
+    IpAddr::V6(ip) => {
+      block.write_u8(6).unwrap();
+      block.extend_from_slice(&ip.octets());
+    },
+  }
+  block.write_u8(record.addr.slot).unwrap();
+  block.write_u32::<LittleEndian>(record.data.len() as u32).unwrap();
+  block.extend_from_slice(&record.data);
+
+  return block;
+}
+
+fn read_native_record<R: Read>(input: &mut R) -> Result<Record> {
+  let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("native capture has an invalid {}", what));
+
+  let timestamp = UNIX_EPOCH + Duration::from_micros(input.read_u64::<LittleEndian>()?);
+  let direction = match input.read_u8()? {
+    0 => Direction::Received,
+    1 => Direction::Sent,
+    _ => return Err(invalid("direction")),
+  };
+  let traffic = match input.read_u8()? {
+    0 => Traffic::Io,
+    1 => Traffic::Explicit,
+    _ => return Err(invalid("traffic kind")),
+  };
+  let connection_id = input.read_u32::<LittleEndian>()?;
+  let ip = match input.read_u8()? {
+    4 => {
+      let mut octets = [0u8; 4];
+      input.read_exact(&mut octets)?;
+      IpAddr::V4(Ipv4Addr::from(octets))
+    },
+    6 => {
+      let mut octets = [0u8; 16];
+      input.read_exact(&mut octets)?;
+      IpAddr::V6(Ipv6Addr::from(octets))
+    },
+    _ => return Err(invalid("IP version")),
+  };
+  let slot = input.read_u8()?;
+  let len = input.read_u32::<LittleEndian>()? as usize;
+  let data = read_len(input, len)?;
+
+  Ok(Record {
+    timestamp,
+    direction,
+    traffic,
+    connection_id,
+    addr: EipAddr { addr: ip, slot },
+    data,
+  })
+}
+
+/*
+Wrap a block body in the pcapng block type and (repeated) total length
+*/
+fn build_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+  let total_len = (12 + body.len()) as u32;
+  let mut block = vec![];
+  block.write_u32::<LittleEndian>(block_type).unwrap();
+  block.write_u32::<LittleEndian>(total_len).unwrap();
+  block.extend_from_slice(body);
+  block.write_u32::<LittleEndian>(total_len).unwrap();
+
+  return block;
+}
+
+/*
+Append an option, padded to 32 bits
+*/
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+  body.write_u16::<LittleEndian>(code).unwrap();
+  body.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+  body.extend_from_slice(value);
+  pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+  while !body.len().is_multiple_of(4) {
+    body.push(0);
+  }
+}
+
+fn build_section_header() -> Vec<u8> {
+  let mut body = vec![];
+  body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC).unwrap();
+  body.write_u16::<LittleEndian>(1).unwrap();
+  body.write_u16::<LittleEndian>(0).unwrap();
+  // Section length not given
+  body.write_i64::<LittleEndian>(-1).unwrap();
+
+  return build_block(SECTION_HEADER_BLOCK, &body);
+}
+
+fn build_interface_description() -> Vec<u8> {
+  let mut body = vec![];
+  body.write_u16::<LittleEndian>(LINKTYPE_RAW).unwrap();
+  body.write_u16::<LittleEndian>(0).unwrap();
+  body.write_u32::<LittleEndian>(0).unwrap();
+  // Microsecond timestamps
+  write_option(&mut body, OPT_IF_TSRESOL, &[6]);
+  write_option(&mut body, OPT_END, &[]);
+
+  return build_block(INTERFACE_DESCRIPTION_BLOCK, &body);
+}
+
+fn build_packet_block(record: &Record) -> Vec<u8> {
+  let packet = build_ip_packet(record);
+  let timestamp = micros_since_epoch(record.timestamp);
+
+  let mut body = vec![];
+  body.write_u32::<LittleEndian>(0).unwrap();
+  body.write_u32::<LittleEndian>((timestamp >> 32) as u32).unwrap();
+  body.write_u32::<LittleEndian>(timestamp as u32).unwrap();
+  body.write_u32::<LittleEndian>(packet.len() as u32).unwrap();
+  body.write_u32::<LittleEndian>(packet.len() as u32).unwrap();
+  body.extend_from_slice(&packet);
+  pad(&mut body);
+
+  // Inbound is 01, outbound 10
+  let flags: u32 = match record.direction {
+    Direction::Received => 0b01,
+    Direction::Sent => 0b10,
+  };
+  write_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+  write_option(&mut body, OPT_COMMENT, format!("slot {}", record.addr.slot).as_bytes());
+  write_option(&mut body, OPT_END, &[]);
+
+  return build_block(ENHANCED_PACKET_BLOCK, &body);
+}
+
+/*
+An IP/UDP packet carrying a record's data between the PLC and us
+*/
+fn build_ip_packet(record: &Record) -> Vec<u8> {
+  let port = match record.traffic {
+    Traffic::Io => IO_PORT,
+    Traffic::Explicit => ENCAP_PORT,
+  };
+  let udp_len = 8 + record.data.len();
+
+  let mut udp = vec![];
+  udp.write_u16::<BigEndian>(port).unwrap();
+  udp.write_u16::<BigEndian>(port).unwrap();
+  udp.write_u16::<BigEndian>(udp_len as u16).unwrap();
+  // No checksum
+  udp.write_u16::<BigEndian>(0).unwrap();
+  udp.extend_from_slice(&record.data);
+
+  let mut packet = vec![];
+  match record.addr.addr {
+    IpAddr::V4(plc) => {
+      let (src, dst) = match record.direction {
+        Direction::Received => (plc, Ipv4Addr::UNSPECIFIED),
+        Direction::Sent => (Ipv4Addr::UNSPECIFIED, plc),
+      };
+      packet.write_u8(0x45).unwrap();
+      packet.write_u8(0).unwrap();
+      packet.write_u16::<BigEndian>((20 + udp_len) as u16).unwrap();
+      packet.write_u16::<BigEndian>(0).unwrap();
+      // Don't fragment
+      packet.write_u16::<BigEndian>(0x4000).unwrap();
+      packet.write_u8(64).unwrap();
+      packet.write_u8(UDP).unwrap();
+      packet.write_u16::<BigEndian>(0).unwrap();
+      packet.extend_from_slice(&src.octets());
+      packet.extend_from_slice(&dst.octets());
+      let checksum = ipv4_checksum(&packet);
+      BigEndian::write_u16(&mut packet[10..12], checksum);
+    },
+    IpAddr::V6(plc) => {
+      let (src, dst) = match record.direction {
+        Direction::Received => (plc, Ipv6Addr::UNSPECIFIED),
+        Direction::Sent => (Ipv6Addr::UNSPECIFIED, plc),
+      };
+      packet.write_u32::<BigEndian>(0x6000_0000).unwrap();
+      packet.write_u16::<BigEndian>(udp_len as u16).unwrap();
+      packet.write_u8(UDP).unwrap();
+      packet.write_u8(64).unwrap();
+      packet.extend_from_slice(&src.octets());
+      packet.extend_from_slice(&dst.octets());
+    },
+  }
+  packet.extend_from_slice(&udp);
+
+  return packet;
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+  let mut sum: u32 = header.chunks(2).map(|word| u32::from(BigEndian::read_u16(word))).sum();
+  while sum > 0xFFFF {
+    sum = (sum & 0xFFFF) + (sum >> 16);
+  }
+
+  !(sum as u16)
+}
+
+/*
+Reads records back from a capture file of either format
+The format is told from the file's first bytes. Pcapng captures can also come
+from Wireshark or tcpdump, on raw IP or Ethernet links: UDP packets on port
+44818 are taken as explicit messages and any other UDP packet as class 1 I/O,
+while everything else (including explicit messages over TCP) is skipped.
+Packets without a direction flag count as received.
+*/
+pub struct CaptureReader<R: Read> {
+  input: R,
+  format: CaptureFormat,
+  // Per interface in the current pcapng section: link type and timestamp units per second
+  interfaces: Vec<(u16, u64)>,
+}
+impl CaptureReader<BufReader<File>> {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<CaptureReader<BufReader<File>>> {
+    CaptureReader::new(BufReader::new(File::open(path)?))
+  }
+}
+impl<R: Read> CaptureReader<R> {
+  pub fn new(mut input: R) -> Result<CaptureReader<R>> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+
+    let format = if &magic == NATIVE_MAGIC {
+      let version = input.read_u16::<LittleEndian>()?;
+      if version != NATIVE_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("native capture version {} isn't supported", version)));
+      }
+      CaptureFormat::Native
+    } else if LittleEndian::read_u32(&magic) == SECTION_HEADER_BLOCK {
+      let mut reader = CaptureReader { input, format: CaptureFormat::Pcapng, interfaces: vec![] };
+      let body = reader.read_block_body()?;
+      reader.section_header(&body)?;
+      return Ok(reader);
+    } else {
+      return Err(Error::new(ErrorKind::InvalidData, "not a pcapng or native capture"));
+    };
+
+    Ok(CaptureReader { input, format, interfaces: vec![] })
+  }
+
+  pub fn format(&self) -> CaptureFormat {
+    self.format
+  }
+
+  /*
+  The next record, or None at the end of the capture
+  */
+  pub fn next_record(&mut self) -> Result<Option<Record>> {
+    match self.format {
+      CaptureFormat::Native => {
+        // A clean end of file is only allowed between records
+        let mut first = [0u8; 1];
+        if self.input.read(&mut first)? == 0 {
+          return Ok(None);
+        }
+        read_native_record(&mut (&first[..]).chain(&mut self.input)).map(Some)
+      },
+      CaptureFormat::Pcapng => self.next_packet(),
+    }
+  }
+
+  fn next_packet(&mut self) -> Result<Option<Record>> {
+    loop {
+      let mut block_type = [0u8; 4];
+      if self.input.read(&mut block_type[..1])? == 0 {
+        return Ok(None);
+      }
+      self.input.read_exact(&mut block_type[1..])?;
+      let body = self.read_block_body()?;
+
+      match LittleEndian::read_u32(&block_type) {
+        SECTION_HEADER_BLOCK => self.section_header(&body)?,
+        INTERFACE_DESCRIPTION_BLOCK => self.interfaces.push(parse_interface_description(&body)?),
+        ENHANCED_PACKET_BLOCK => {
+          if let Some(record) = self.parse_packet_block(&body)? {
+            return Ok(Some(record));
+          }
+        },
+        _ => {},
+      }
+    }
+  }
+
+  /*
+  Read the rest of a block after its type: the body without the trailing length
+  */
+  fn read_block_body(&mut self) -> Result<Vec<u8>> {
+    let total_len = self.input.read_u32::<LittleEndian>()? as usize;
+    if total_len < 12 || !total_len.is_multiple_of(4) {
+      return Err(Error::new(ErrorKind::InvalidData, format!("pcapng block length {} is invalid", total_len)));
+    }
+    let mut body = read_len(&mut self.input, total_len - 8)?;
+    body.truncate(total_len - 12);
+
+    Ok(body)
+  }
+
+  fn section_header(&mut self, body: &[u8]) -> Result<()> {
+    if body.len() < 4 || LittleEndian::read_u32(&body[0..4]) != BYTE_ORDER_MAGIC {
+      return Err(Error::new(ErrorKind::InvalidData, "only little endian pcapng is supported"));
+    }
+    self.interfaces.clear();
+
+    Ok(())
+  }
+
+  fn parse_packet_block(&self, body: &[u8]) -> Result<Option<Record>> {
+    let too_short = || Error::new(ErrorKind::UnexpectedEof, "pcapng packet block is too short");
+    if body.len() < 20 {
+      return Err(too_short());
+    }
+
+    let interface = LittleEndian::read_u32(&body[0..4]) as usize;
+    let (link_type, units) = *self.interfaces.get(interface)
+      .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("packet on undescribed interface {}", interface)))?;
+    let ticks = (u64::from(LittleEndian::read_u32(&body[4..8])) << 32) | u64::from(LittleEndian::read_u32(&body[8..12]));
+    let timestamp = pcapng_timestamp(ticks, units)?;
+    let captured_len = LittleEndian::read_u32(&body[12..16]) as usize;
+    let padded_len = (captured_len + 3) & !3;
+    if body.len() < 20 + padded_len {
+      return Err(too_short());
+    }
+    let packet = &body[20..20 + captured_len];
+
+    let mut direction = Direction::Received;
+    let mut slot = 0;
+    for (code, value) in parse_options(&body[20 + padded_len..]) {
+      match code {
+        OPT_EPB_FLAGS if value.len() == 4 && LittleEndian::read_u32(value) & 0b11 == 0b10 => {
+          direction = Direction::Sent;
+        },
+        OPT_COMMENT => {
+          if let Some(comment_slot) = std::str::from_utf8(value).ok()
+            .and_then(|comment| comment.strip_prefix("slot "))
+            .and_then(|comment_slot| comment_slot.parse().ok()) {
+            slot = comment_slot;
+          }
+        },
+        _ => {},
+      }
+    }
+
+    let ip_packet = match link_type {
+      LINKTYPE_RAW => packet,
+      LINKTYPE_ETHERNET => match strip_ethernet(packet) {
+        Some(ip_packet) => ip_packet,
+        None => return Ok(None),
+      },
+      _ => return Ok(None),
+    };
+    let (src, dst, port, data) = match parse_udp(ip_packet) {
+      Some(udp) => udp,
+      None => return Ok(None),
+    };
+
+    let traffic = if port == ENCAP_PORT { Traffic::Explicit } else { Traffic::Io };
+    let connection_id = match traffic {
+      Traffic::Io => io_connection_id(data).unwrap_or(0),
+      Traffic::Explicit => 0,
+    };
+    let plc = match direction {
+      Direction::Received => src,
+      Direction::Sent => dst,
+    };
+
+    Ok(Some(Record {
+      timestamp,
+      direction,
+      traffic,
+      connection_id,
+      addr: EipAddr { addr: plc, slot },
+      data: data.to_vec(),
+    }))
+  }
+}
+impl<R: Read> Iterator for CaptureReader<R> {
+  type Item = Result<Record>;
+
+  fn next(&mut self) -> Option<Result<Record>> {
+    self.next_record().transpose()
+  }
+}
+
+/*
+Read a length-prefixed field
+The length comes from the file, so the buffer only grows as the bytes actually
+arrive; a corrupt length runs into the end of the file instead of allocating
+gigabytes.
+*/
+fn read_len<R: Read>(input: &mut R, len: usize) -> Result<Vec<u8>> {
+  let mut data = vec![];
+  input.take(len as u64).read_to_end(&mut data)?;
+  if data.len() < len {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "capture ends in the middle of a record"));
+  }
+
+  Ok(data)
+}
+
+/*
+A pcapng timestamp in some number of units per second
+*/
+fn pcapng_timestamp(ticks: u64, units: u64) -> Result<SystemTime> {
+  const NANOS: u64 = 1_000_000_000;
+
+  // Finer than nanoseconds can overflow, so divide first
+  let fraction = ticks % units;
+  let nanos = fraction.checked_mul(NANOS)
+    .map(|scaled| scaled / units)
+    .unwrap_or_else(|| fraction / (units / NANOS));
+  UNIX_EPOCH.checked_add(Duration::new(ticks / units, nanos as u32))
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "pcapng timestamp is out of range"))
+}
+
+/*
+Link type and timestamp units per second of an interface
+*/
+fn parse_interface_description(body: &[u8]) -> Result<(u16, u64)> {
+  if body.len() < 8 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "pcapng interface block is too short"));
+  }
+  let link_type = LittleEndian::read_u16(&body[0..2]);
+
+  // Microseconds unless the interface says otherwise
+  let mut units = 1_000_000;
+  for (code, value) in parse_options(&body[8..]) {
+    if code == OPT_IF_TSRESOL && value.len() == 1 {
+      let exponent = u32::from(value[0] & 0x7F);
+      units = if value[0] & 0x80 == 0 { 10u64.checked_pow(exponent) } else { 2u64.checked_pow(exponent) }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "pcapng timestamp resolution is too fine"))?;
+    }
+  }
+
+  Ok((link_type, units))
+}
+
+fn parse_options(mut options: &[u8]) -> Vec<(u16, &[u8])> {
+  let mut parsed = vec![];
+  while options.len() >= 4 {
+    let code = LittleEndian::read_u16(&options[0..2]);
+    let len = LittleEndian::read_u16(&options[2..4]) as usize;
+    let padded_len = (len + 3) & !3;
+    if code == OPT_END || options.len() < 4 + len {
+      break;
+    }
+    parsed.push((code, &options[4..4 + len]));
+    options = &options[(4 + padded_len).min(options.len())..];
+  }
+
+  parsed
+}
+
+/*
+The IP packet in an Ethernet frame, past any VLAN tags
+*/
+fn strip_ethernet(frame: &[u8]) -> Option<&[u8]> {
+  const VLAN: u16 = 0x8100;
+  const IPV4: u16 = 0x0800;
+  const IPV6: u16 = 0x86DD;
+
+  let mut pos = 12;
+  loop {
+    let ether_type = BigEndian::read_u16(frame.get(pos..pos + 2)?);
+    pos += 2;
+    match ether_type {
+      VLAN => pos += 2,
+      IPV4 | IPV6 => return frame.get(pos..),
+      _ => return None,
+    }
+  }
+}
+
+/*
+Source, destination, the lower of the two ports, and the payload of a UDP packet
+*/
+fn parse_udp(packet: &[u8]) -> Option<(IpAddr, IpAddr, u16, &[u8])> {
+  let (src, dst, udp) = match packet.first()? >> 4 {
+    4 => {
+      let header_len = usize::from(packet[0] & 0x0F) * 4;
+      if *packet.get(9)? != UDP {
+        return None;
+      }
+      let total_len = usize::from(BigEndian::read_u16(packet.get(2..4)?));
+      let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+      let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+      (IpAddr::from(src), IpAddr::from(dst), packet.get(header_len..total_len.min(packet.len()))?)
+    },
+    6 => {
+      if *packet.get(6)? != UDP {
+        return None;
+      }
+      let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+      let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+      (IpAddr::from(src), IpAddr::from(dst), packet.get(40..)?)
+    },
+    _ => return None,
+  };
+
+  let src_port = BigEndian::read_u16(udp.get(0..2)?);
+  let dst_port = BigEndian::read_u16(udp.get(2..4)?);
+  let udp_len = usize::from(BigEndian::read_u16(udp.get(4..6)?));
+  let data = udp.get(8..udp_len.min(udp.len()))?;
+
+  Some((src, dst, src_port.min(dst_port), data))
+}
+
+#[cfg(test)]
+fn records_for_test() -> Vec<Record> {
+  let io = vec![0x02, 0x00, 0x02, 0x80, 0x08, 0x00, 0x44, 0x33, 0x22, 0x11, 0x07, 0, 0, 0,
+                0xB1, 0x00, 0x04, 0x00, 0x01, 0x00, 0xAA, 0xBB];
+  vec![
+    Record {
+      timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_123_456),
+      direction: Direction::Received,
+      traffic: Traffic::Io,
+      connection_id: 0x1122_3344,
+      addr: EipAddr { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), slot: 3 },
+      data: io,
+    },
+    Record {
+      timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_223_456),
+      direction: Direction::Sent,
+      traffic: Traffic::Explicit,
+      connection_id: 0,
+      addr: EipAddr { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), slot: 0 },
+      data: crate::eip::build_register_session(),
+    },
+  ]
+}
+
+#[test]
+fn test_capture_round_trip() {
+  for format in [CaptureFormat::Native, CaptureFormat::Pcapng] {
+    let path = std::env::temp_dir().join(format!("rconpro-test-{}-{}", std::process::id(), format));
+    let recorder = Recorder::create(&path, format).unwrap();
+    for record in records_for_test() {
+      recorder.record(&record).unwrap();
+    }
+    drop(recorder);
+
+    let reader = CaptureReader::open(&path).unwrap();
+    assert_eq!(reader.format(), format);
+    let records: Vec<Record> = reader.collect::<Result<_>>().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records, records_for_test());
+  }
+}
+
+#[test]
+fn test_pcapng_packet() {
+  let block = build_packet_block(&records_for_test()[0]);
+  // 28 bytes of block header, then IPv4 from the PLC to us
+  assert_eq!(&block[28..30], &[0x45, 0x00]);
+  assert_eq!(&block[40..48], &[10, 0, 0, 1, 0, 0, 0, 0]);
+  assert_eq!(ipv4_checksum(&block[28..48]), 0);
+  assert_eq!(BigEndian::read_u16(&block[50..52]), IO_PORT);
+}
+
+#[test]
+fn test_corrupt_capture() {
+  use std::io::Cursor;
+
+  // A data length far past the end of the file
+  let mut native = NATIVE_MAGIC.to_vec();
+  native.write_u16::<LittleEndian>(NATIVE_VERSION).unwrap();
+  let record = build_native_record(&records_for_test()[0]);
+  let len_at = record.len() - records_for_test()[0].data.len() - 4;
+  native.extend_from_slice(&record[..len_at]);
+  native.write_u32::<LittleEndian>(0xFFFF_FFF0).unwrap();
+  native.extend_from_slice(&[0; 8]);
+  let error = CaptureReader::new(Cursor::new(native)).unwrap().next().unwrap().unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+  let mut pcapng = build_section_header();
+  pcapng.write_u32::<LittleEndian>(ENHANCED_PACKET_BLOCK).unwrap();
+  pcapng.write_u32::<LittleEndian>(0xFFFF_FFF0).unwrap();
+  let error = CaptureReader::new(Cursor::new(pcapng)).unwrap().next().unwrap().unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+  // Picoseconds
+  assert_eq!(
+    pcapng_timestamp(12_345_678_901_234_567, 1_000_000_000_000).unwrap(),
+    UNIX_EPOCH + Duration::new(12_345, 678_901_234)
+  );
+  assert_eq!(pcapng_timestamp(1_500_000, 1_000_000).unwrap(), UNIX_EPOCH + Duration::from_millis(1_500));
+
+  // Whole seconds, too many of them
+  assert_eq!(pcapng_timestamp(u64::MAX, 1).unwrap_err().kind(), ErrorKind::InvalidData);
+}
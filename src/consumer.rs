@@ -1,23 +1,57 @@
-use std::io::{Result, Error, ErrorKind, Cursor};
+use std::io::{Result, Error, ErrorKind};
+use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU32, AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::convert::TryInto;
 use crossbeam::queue::SegQueue;
-use byteorder::{ReadBytesExt, LittleEndian};
+use serde::{Deserialize, Serialize};
 
-use crate::eip;
-use crate::sockets::{EipAddr, CPSocket, SetupStream};
+use crate::eip::{self, ForwardOpenSpec, ConnectionTriple};
+use crate::Plc;
+use crate::{AssemblyPath, CipRoute, ForwardOpenParams, Keying, ConnectionKind, ConnectionType};
+use crate::sockets::{EipAddr, CPSocket};
 
 /*
 A struct specifying consumer parameters
+data_size counts the CIP sequence count (2 bytes) as well as the tag's data.
+Fields left out of a config file take their defaults.
+Data only flows T->O: the O->T side is a 2-byte heartbeat. An adapter whose
+output assembly needs data is scanned with Service::add_io_connection instead.
 */
+#[derive(Debug, PartialEq, Eq, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
 pub struct ConsumerHint {
   pub tag: String,
   pub data_size: usize,
   pub rpi: usize,
   pub otrpi: usize,
+  pub params: ForwardOpenParams,
+  pub assembly: Option<AssemblyPath>,
+  pub route: Option<CipRoute>,
+  pub keying: Keying,
+  pub kind: ConnectionKind,
+}
+
+impl ConsumerHint {
+  pub(crate) fn forward_open_spec(&self) -> ForwardOpenSpec<'_> {
+    // The O->T side only carries the CIP sequence count
+    const OT_CONNECTION_SIZE: usize = 2;
+
+    ForwardOpenSpec {
+      tag: &self.tag,
+      assembly: self.assembly.as_ref(),
+      route: self.route.as_ref(),
+      keying: self.keying,
+      kind: self.kind,
+      params: &self.params,
+      ot_rpi: self.otrpi,
+      ot_size: OT_CONNECTION_SIZE,
+      to_rpi: self.rpi,
+      to_size: self.data_size,
+    }
+  }
 }
 
 /*
@@ -35,7 +69,10 @@ pub(crate) struct Consumer {
 
   pub(crate) ot_connection_id: u32,
   pub(crate) to_connection_id: u32,
+  pub(crate) triple: Option<ConnectionTriple>,
+  pub(crate) to_multicast: Option<Ipv4Addr>,
 
+  last_heard: Mutex<Instant>,
   alive: Arc<AtomicBool>,
 }
 impl Consumer {
@@ -49,6 +86,9 @@ impl Consumer {
       queue: queue.clone(),
       ot_connection_id: 0,
       to_connection_id: 0,
+      triple: None,
+      to_multicast: None,
+      last_heard: Mutex::new(Instant::now()),
       alive: Arc::new(AtomicBool::new(true))
     }
   }
@@ -57,35 +97,24 @@ impl Consumer {
   Send a forward open to the producer
   This effectively starts the consumer connection
   */
-  pub(crate) fn send_forward_open(&mut self, setup_stream: &mut SetupStream, session_handle: u32, slot: u8) -> Result<u32> {
-    // Send forward open and get response
-    let msg = eip::build_forward_open_packet(slot, session_handle, &self.hint);
-    let response = setup_stream.send_recieve(&msg.as_slice()).unwrap();
-
-    // Check response
-    if response.len() < 52 {
-      return Err(Error::new(ErrorKind::Other, "unable to parse response"));
-      // Probably should reset the socket after this...
-    } else {
-
-      // Parse response
-      let mut cursor = Cursor::new(response);
-      cursor.set_position(42);
-
-      let sts = cursor.read_i8().unwrap();
-      if sts == 0 {
-
-        // Parse IDs
-        cursor.set_position(44);
-        self.ot_connection_id = cursor.read_u32::<LittleEndian>().unwrap();
-        self.to_connection_id = cursor.read_u32::<LittleEndian>().unwrap();
-
-      } else {
-        return Err(Error::new(ErrorKind::Other, "Forward open failed"))
-      }
+  pub(crate) fn send_forward_open(&mut self, plc: &Plc) -> Result<u32> {
+    if self.hint.kind == ConnectionKind::ListenOnly && self.hint.params.to.connection_type != ConnectionType::Multicast {
+      return Err(Error::new(ErrorKind::InvalidInput, "a listen-only connection needs a multicast T->O connection"));
     }
 
-    Ok(self.to_connection_id)
+    // Send forward open and get response
+    let reply = plc.forward_open(&self.hint.forward_open_spec())?;
+    self.ot_connection_id = reply.ot_connection_id;
+    self.to_connection_id = reply.to_connection_id;
+    self.triple = Some(reply.triple());
+
+    // A multicast T->O connection tells us which group it produces to
+    self.to_multicast = reply.to_sockaddr
+      .map(|sockaddr| *sockaddr.ip())
+      .filter(|ip| ip.is_multicast());
+    self.heard();
+
+    Ok(self.ot_connection_id)
   }
 
   /*
@@ -131,6 +160,28 @@ impl Consumer {
     }).unwrap();
   }
 
+  /*
+  Note that a T->O packet arrived
+  */
+  pub(crate) fn heard(&self) {
+    *self.last_heard.lock().unwrap() = Instant::now();
+  }
+
+  /*
+  True once the connection has been quiet for longer than its watchdog allows
+  A change-of-state connection may go a whole RPI without data, but no longer.
+  */
+  pub(crate) fn timed_out(&self) -> bool {
+    self.last_heard.lock().unwrap().elapsed() > self.hint.params.watchdog_timeout(self.hint.rpi)
+  }
+
+  /*
+  The route this connection was opened over, if it wasn't the PLC's own slot
+  */
+  pub(crate) fn route(&self) -> Option<&CipRoute> {
+    self.hint.route.as_ref()
+  }
+
   pub(crate) fn stop(&mut self) {
     self.alive.store(false, Ordering::Release);
   }
@@ -0,0 +1,1018 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Result, Error, ErrorKind, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::dissect::hex;
+use crate::{tag, CaptureFormat, ConsumerHandle, ConsumerHint, ConsumerQueue, ConsumerState, EipAddr, PlcProfile, Recorder, Service, ServiceConfig};
+
+/*
+Everything a daemon runs: the service's sockets, the PLCs, the tags consumed
+from them, and where their values go
+PLCs, consumers and sinks are referred to by name. A consumer with no sinks
+listed goes to every value sink. Fields left out take their defaults; how
+often connections are retried is part of the service section.
+*/
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+  pub service: ServiceConfig,
+  pub plcs: Vec<PlcConfig>,
+  pub consumers: Vec<ConsumerConfig>,
+  pub sinks: Vec<SinkConfig>,
+}
+impl DaemonConfig {
+
+  /*
+  Read a config file: .yaml and .yml are YAML, anything else TOML
+  */
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<DaemonConfig> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    match path.extension().and_then(|extension| extension.to_str()) {
+      Some("yaml") | Some("yml") => DaemonConfig::from_yaml(&text),
+      _ => DaemonConfig::from_toml(&text),
+    }
+  }
+
+  pub fn from_toml(text: &str) -> Result<DaemonConfig> {
+    let config: DaemonConfig = toml::from_str(text)
+      .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  pub fn from_yaml(text: &str) -> Result<DaemonConfig> {
+    let config: DaemonConfig = serde_yaml::from_str(text)
+      .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  /*
+  Check names are unique and everything referred to exists
+  */
+  pub fn validate(&self) -> Result<()> {
+    let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, message));
+
+    let mut plcs = HashSet::new();
+    let mut addrs = HashSet::new();
+    for plc in &self.plcs {
+      if !plcs.insert(plc.name.as_str()) {
+        return invalid(format!("PLC {:?} is defined twice", plc.name));
+      }
+      if !addrs.insert(plc.eip_addr()) {
+        return invalid(format!("PLC {:?} has the same address and slot as another", plc.name));
+      }
+      plc.profile.check_addr(&plc.eip_addr())?;
+    }
+
+    let mut sinks = HashSet::new();
+    let mut captures = 0;
+    for sink in &self.sinks {
+      if !sinks.insert(sink.name.as_str()) {
+        return invalid(format!("sink {:?} is defined twice", sink.name));
+      }
+      if let SinkKind::Capture { .. } = sink.kind {
+        captures += 1;
+      }
+    }
+    if captures > 1 {
+      return invalid(String::from("only one capture sink can record at a time"));
+    }
+
+    let mut consumers = HashSet::new();
+    for consumer in &self.consumers {
+      if !consumers.insert(consumer.name.as_str()) {
+        return invalid(format!("consumer {:?} is defined twice", consumer.name));
+      }
+      if !plcs.contains(consumer.plc.as_str()) {
+        return invalid(format!("consumer {:?} is on PLC {:?}, which isn't defined", consumer.name, consumer.plc));
+      }
+      if consumer.hint.rpi == 0 || consumer.hint.otrpi == 0 || consumer.hint.data_size == 0 {
+        return invalid(format!("consumer {:?} needs an rpi, an otrpi and a data_size", consumer.name));
+      }
+      for sink in &consumer.sinks {
+        match self.sinks.iter().find(|known| &known.name == sink) {
+          None => return invalid(format!("consumer {:?} goes to sink {:?}, which isn't defined", consumer.name, sink)),
+          Some(SinkConfig { kind: SinkKind::Capture { .. }, .. }) =>
+            return invalid(format!("consumer {:?} can't send values to capture sink {:?}", consumer.name, sink)),
+          Some(_) => (),
+        }
+      }
+      consumer.decode.check()?;
+    }
+
+    Ok(())
+  }
+
+  fn plc(&self, name: &str) -> Option<&PlcConfig> {
+    self.plcs.iter().find(|plc| plc.name == name)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PlcConfig {
+  pub name: String,
+  pub addr: IpAddr,
+  #[serde(default)]
+  pub slot: u8,
+  #[serde(default)]
+  pub profile: PlcProfile,
+}
+impl PlcConfig {
+  pub fn eip_addr(&self) -> EipAddr {
+    EipAddr { addr: self.addr, slot: self.slot }
+  }
+}
+
+/*
+A tag to consume: the ConsumerHint's fields, plus how to decode its data
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConsumerConfig {
+  pub name: String,
+  pub plc: String,
+  #[serde(flatten)]
+  pub hint: ConsumerHint,
+  #[serde(flatten)]
+  pub decode: Decode,
+  #[serde(default)]
+  pub sinks: Vec<String>,
+}
+
+/*
+How to turn class 1 data into a value
+With a type, the data from the offset on is decoded as elements of that
+elementary type: one element is a value, several are an array. Without one,
+it's hex.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Decode {
+  #[serde(rename = "decode")]
+  pub data_type: Option<String>,
+  pub offset: usize,
+}
+impl Decode {
+  pub fn check(&self) -> Result<()> {
+    self.element().map(|_| ())
+  }
+
+  pub fn value(&self, data: &[u8]) -> Value {
+    let data = data.get(self.offset..).unwrap_or_default();
+    let (data_type, size) = match self.element() {
+      Ok(Some(element)) => element,
+      _ => return json!(hex(data)),
+    };
+
+    let mut values: Vec<Value> = data.chunks_exact(size)
+      .map(|element| tag::decode_atomic(data_type, element).map_or(Value::Null, |value| value.to_json()))
+      .collect();
+    if values.len() == 1 {
+      values.remove(0)
+    } else {
+      Value::Array(values)
+    }
+  }
+
+  fn element(&self) -> Result<Option<(u16, usize)>> {
+    let name = match &self.data_type {
+      Some(name) => name,
+      None => return Ok(None),
+    };
+    let data_type = tag::type_code(name)
+      .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown type {}", name)))?;
+    let size = tag::atomic_size(data_type)
+      .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("can't decode {} from I/O data", name)))?;
+
+    Ok(Some((data_type, size)))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SinkConfig {
+  pub name: String,
+  #[serde(flatten)]
+  pub kind: SinkKind,
+}
+
+/*
+Where values go
+Stdout and File write a row per value, as JSON lines or CSV; files are appended
+to. Capture records the service's traffic instead (see Recorder), in the format
+the file name suggests unless one is given.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum SinkKind {
+  Stdout {
+    #[serde(default)]
+    format: SinkFormat,
+  },
+  File {
+    path: PathBuf,
+    #[serde(default)]
+    format: SinkFormat,
+  },
+  Capture {
+    path: PathBuf,
+    #[serde(default)]
+    format: Option<CaptureFormat>,
+  },
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum SinkFormat {
+  #[default]
+  Json,
+  Csv,
+}
+
+/*
+Structured events, one JSON object per line
+Every event has a time, a level and a name; the rest depends on the event.
+*/
+pub struct EventLog {
+  out: Box<dyn Write + Send>,
+}
+impl EventLog {
+  pub fn new<W: Write + Send + 'static>(out: W) -> EventLog {
+    EventLog { out: Box::new(out) }
+  }
+
+  pub fn stderr() -> EventLog {
+    EventLog::new(io::stderr())
+  }
+
+  pub fn info(&mut self, event: &str, fields: &[(&str, Value)]) {
+    self.log("info", event, fields);
+  }
+
+  pub fn warn(&mut self, event: &str, fields: &[(&str, Value)]) {
+    self.log("warn", event, fields);
+  }
+
+  pub fn error(&mut self, event: &str, fields: &[(&str, Value)]) {
+    self.log("error", event, fields);
+  }
+
+  fn log(&mut self, level: &str, event: &str, fields: &[(&str, Value)]) {
+    let mut line = serde_json::Map::new();
+    line.insert(String::from("time"), json!(timestamp(SystemTime::now())));
+    line.insert(String::from("level"), json!(level));
+    line.insert(String::from("event"), json!(event));
+    for (name, value) in fields {
+      line.insert(String::from(*name), value.clone());
+    }
+
+    // Nowhere left to report a failure to log
+    let _ = writeln!(self.out, "{}", Value::Object(line));
+    let _ = self.out.flush();
+  }
+}
+
+struct Sink {
+  format: SinkFormat,
+  out: Box<dyn Write + Send>,
+  header_written: bool,
+}
+impl Sink {
+  fn open(kind: &SinkKind) -> Result<Option<Sink>> {
+    let (format, out): (SinkFormat, Box<dyn Write + Send>) = match kind {
+      SinkKind::Stdout { format } => (*format, Box::new(io::stdout())),
+      SinkKind::File { path, format } => {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // An existing CSV file already has its header
+        let header_written = file.metadata()?.len() > 0;
+        return Ok(Some(Sink { format: *format, out: Box::new(BufWriter::new(file)), header_written }));
+      },
+      SinkKind::Capture { .. } => return Ok(None),
+    };
+
+    Ok(Some(Sink { format, out, header_written: false }))
+  }
+
+  fn write(&mut self, row: &[(&str, Value)]) -> Result<()> {
+    match self.format {
+      SinkFormat::Json => {
+        let object: serde_json::Map<String, Value> = row.iter()
+          .map(|(name, value)| (String::from(*name), value.clone()))
+          .collect();
+        writeln!(self.out, "{}", Value::Object(object))
+      },
+      SinkFormat::Csv => {
+        if !self.header_written {
+          let names: Vec<String> = row.iter().map(|(name, _)| csv_field(name)).collect();
+          writeln!(self.out, "{}", names.join(","))?;
+          self.header_written = true;
+        }
+        let fields: Vec<String> = row.iter().map(|(_, value)| csv_field(&text(value))).collect();
+        writeln!(self.out, "{}", fields.join(","))
+      },
+    }
+  }
+}
+
+struct RunningConsumer {
+  config: ConsumerConfig,
+  queue: Arc<ConsumerQueue>,
+  handle: ConsumerHandle,
+  // The state last logged
+  state: ConsumerState,
+}
+impl RunningConsumer {
+  fn add(service: &mut Service, addr: EipAddr, consumer: &ConsumerConfig) -> RunningConsumer {
+    let queue = Arc::new(ConsumerQueue::new());
+    RunningConsumer {
+      config: consumer.clone(),
+      handle: service.add_consumer(addr, consumer.hint.clone(), &queue),
+      queue,
+      state: ConsumerState::Pending,
+    }
+  }
+}
+
+/*
+Runs a DaemonConfig on a Service
+The service opens the connections in the background, retrying any that fail
+or time out. start starts it, poll hands the values that arrived to the sinks
+and logs how the connections are doing, and stop closes everything. run does
+all three until told to stop.
+*/
+pub struct Daemon {
+  config: DaemonConfig,
+  service: Service,
+  sinks: HashMap<String, Sink>,
+  consumers: Vec<RunningConsumer>,
+  log: EventLog,
+  watched: Option<(PathBuf, Option<SystemTime>)>,
+  last_checked: Instant,
+  reload_requested: Arc<AtomicBool>,
+}
+impl Daemon {
+  pub fn new(config: DaemonConfig, log: EventLog) -> Result<Daemon> {
+    config.validate()?;
+
+    let mut sinks = HashMap::new();
+    for sink in &config.sinks {
+      if let Some(opened) = Sink::open(&sink.kind)? {
+        sinks.insert(sink.name.clone(), opened);
+      }
+    }
+
+    // Profiles first, so no consumer is ever tried with the wrong one
+    let mut service = Service::with_config(config.service.clone());
+    for plc in &config.plcs {
+      service.set_profile(plc.eip_addr(), plc.profile)?;
+    }
+    let consumers = config.consumers.iter()
+      .map(|consumer| RunningConsumer::add(&mut service, config.plc(&consumer.plc).unwrap().eip_addr(), consumer))
+      .collect();
+
+    Ok(Daemon {
+      service,
+      config,
+      sinks,
+      consumers,
+      log,
+      watched: None,
+      last_checked: Instant::now(),
+      reload_requested: Arc::new(AtomicBool::new(false)),
+    })
+  }
+
+  pub fn config(&self) -> &DaemonConfig {
+    &self.config
+  }
+
+  pub fn service(&self) -> &Service {
+    &self.service
+  }
+
+  /*
+  The handle of a consumer, for its state
+  */
+  pub fn consumer(&self, consumer: &str) -> Option<&ConsumerHandle> {
+    self.consumers.iter()
+      .find(|running| running.config.name == consumer)
+      .map(|running| &running.handle)
+  }
+
+  /*
+  The O->T connection ID of a consumer, while its connection is open
+  */
+  pub fn connection_id(&self, consumer: &str) -> Option<u32> {
+    self.consumer(consumer).and_then(ConsumerHandle::connection_id)
+  }
+
+  /*
+  Reload the config from a file whenever it changes
+  The file is checked by run, about once a second.
+  */
+  pub fn watch<P: Into<PathBuf>>(&mut self, path: P) {
+    let path = path.into();
+    let modified = modified(&path);
+    self.watched = Some((path, modified));
+  }
+
+  /*
+  Set this to reload the watched file now, as rconprod does on SIGHUP
+  */
+  pub fn reload_trigger(&self) -> Arc<AtomicBool> {
+    Arc::clone(&self.reload_requested)
+  }
+
+  /*
+  Switch to a new config, keeping whatever didn't change running
+  Only consumers whose PLC (address or profile) or ConsumerHint changed are
+  re-opened; a new decode or list of sinks applies to the open connection. A
+  sink that writes to the same place in the same format is kept, even if it was
+  renamed. If the new config is invalid, or a new sink can't be opened, nothing
+  changes. The service section can't change without a restart, so changes to
+  it are ignored (and logged).
+  */
+  pub fn reload(&mut self, mut config: DaemonConfig) -> Result<()> {
+    config.validate()?;
+
+    // Open what's new before touching what's running, so a failure changes nothing
+    let mut kept = HashMap::new();
+    let mut opened = HashMap::new();
+    for sink in config.sinks.iter().filter(|sink| !matches!(sink.kind, SinkKind::Capture { .. })) {
+      let old = self.config.sinks.iter()
+        .find(|old| old.kind == sink.kind && !kept.values().any(|name| name == &old.name));
+      match old {
+        Some(old) => {
+          kept.insert(sink.name.clone(), old.name.clone());
+        },
+        None => if let Some(new_sink) = Sink::open(&sink.kind)? {
+          opened.insert(sink.name.clone(), new_sink);
+        },
+      }
+    }
+    let (old_capture, new_capture) = (capture_key(&self.config), capture_key(&config));
+    let capture = match &new_capture {
+      Some((path, format)) if new_capture != old_capture => Some(create_capture(path, Some(*format))?),
+      _ => None,
+    };
+    let stop_capture = old_capture.is_some() && new_capture.is_none();
+
+    // Values that already arrived go where the old config said
+    self.poll();
+
+    if config.service != self.config.service {
+      self.log.warn("service_config_ignored", &[("reason", json!("the service section only changes on restart"))]);
+      config.service = self.config.service.clone();
+    }
+
+    let mut old_sinks = std::mem::take(&mut self.sinks);
+    for (name, old_name) in kept {
+      if let Some(sink) = old_sinks.remove(&old_name) {
+        if name != old_name {
+          self.log.info("sink_renamed", &[("sink", json!(old_name)), ("name", json!(name))]);
+        }
+        self.sinks.insert(name, sink);
+      }
+    }
+    for (name, mut old) in old_sinks {
+      let _ = old.out.flush();
+      self.log.info("sink_closed", &[("sink", json!(name))]);
+    }
+    for (name, new_sink) in opened {
+      self.log.info("sink_opened", &[("sink", json!(name))]);
+      self.sinks.insert(name, new_sink);
+    }
+    let old_recorder = match capture {
+      Some(recorder) => self.service.start_recording(recorder),
+      None if stop_capture => self.service.stop_recording(),
+      None => None,
+    };
+    if let Some(Err(e)) = old_recorder.map(|recorder| recorder.flush()) {
+      self.log.error("capture_failed", &[("error", json!(e.to_string()))]);
+    }
+
+    for plc in config.plcs.iter().filter(|plc| !self.config.plcs.contains(plc)) {
+      self.service.set_profile(plc.eip_addr(), plc.profile)?;
+    }
+
+    let mut running: HashMap<String, RunningConsumer> = self.consumers.drain(..)
+      .map(|running| (running.config.name.clone(), running))
+      .collect();
+    for consumer in &config.consumers {
+      let plc = config.plc(&consumer.plc).unwrap();
+      let addr = plc.eip_addr();
+      match running.remove(&consumer.name) {
+        Some(mut old) if old.handle.addr() == addr
+          && self.config.plc(&old.config.plc).map(|old_plc| old_plc.profile) == Some(plc.profile)
+          && old.config.hint == consumer.hint => {
+          old.config = consumer.clone();
+          self.consumers.push(old);
+        },
+        old => {
+          if let Some(old) = old {
+            stop(&mut self.service, &mut self.log, old, "changed");
+          }
+          self.consumers.push(RunningConsumer::add(&mut self.service, addr, consumer));
+        },
+      }
+    }
+    for (_, old) in running {
+      stop(&mut self.service, &mut self.log, old, "removed");
+    }
+
+    self.log.info("config_reloaded", &[
+      ("plcs", json!(config.plcs.len())),
+      ("consumers", json!(config.consumers.len())),
+      ("sinks", json!(config.sinks.len())),
+    ]);
+    self.config = config;
+
+    Ok(())
+  }
+
+  /*
+  Start the service, which starts opening the connections
+  */
+  pub fn start(&mut self) -> Result<()> {
+    self.service.start()?;
+    for sink in &self.config.sinks {
+      if let SinkKind::Capture { path, format } = &sink.kind {
+        self.service.start_recording(create_capture(path, *format)?);
+      }
+    }
+    self.log.info("started", &[
+      ("io_addr", json!(self.service.io_addr()?.to_string())),
+      ("plcs", json!(self.config.plcs.len())),
+      ("consumers", json!(self.consumers.len())),
+    ]);
+
+    Ok(())
+  }
+
+  /*
+  Hand every value that arrived to the sinks, and log the connections that
+  opened or failed since the last poll
+  Returns how many values there were.
+  */
+  pub fn poll(&mut self) -> usize {
+    let mut values = 0;
+
+    for consumer in &mut self.consumers {
+      log_state(&mut self.log, consumer);
+
+      while let Some(data) = consumer.queue.pop() {
+        let row = [
+          ("time", json!(timestamp(SystemTime::now()))),
+          ("consumer", json!(consumer.config.name)),
+          ("plc", json!(consumer.config.plc)),
+          ("tag", json!(consumer.config.hint.tag)),
+          ("value", consumer.config.decode.value(&data)),
+        ];
+        for (name, sink) in self.sinks.iter_mut() {
+          if !consumer.config.sinks.is_empty() && !consumer.config.sinks.contains(name) {
+            continue;
+          }
+          if let Err(e) = sink.write(&row) {
+            self.log.error("sink_failed", &[("sink", json!(name)), ("error", json!(e.to_string()))]);
+          }
+        }
+        values += 1;
+      }
+    }
+
+    if values > 0 {
+      self.flush_sinks();
+    }
+
+    values
+  }
+
+  /*
+  Close every connection, finish the capture and stop the service
+  */
+  pub fn stop(&mut self) {
+    for consumer in std::mem::take(&mut self.consumers) {
+      stop(&mut self.service, &mut self.log, consumer, "shutdown");
+    }
+    self.flush_sinks();
+    if let Some(recorder) = self.service.stop_recording() {
+      if let Err(e) = recorder.flush() {
+        self.log.error("capture_failed", &[("error", json!(e.to_string()))]);
+      }
+    }
+    self.service.stop();
+    self.log.info("stopped", &[]);
+  }
+
+  /*
+  start, then poll (and reload the watched file) until running goes false,
+  then stop
+  */
+  pub fn run(&mut self, running: &AtomicBool) -> Result<()> {
+    self.start()?;
+    while running.load(Ordering::Acquire) {
+      self.check_reload();
+      if self.poll() == 0 {
+        thread::sleep(Duration::from_millis(1));
+      }
+    }
+    self.stop();
+
+    Ok(())
+  }
+
+  /*
+  Reload the watched file if it changed or a reload was asked for
+  A config that doesn't load or validate is logged and otherwise ignored.
+  */
+  fn check_reload(&mut self) {
+    const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+    let requested = self.reload_requested.swap(false, Ordering::AcqRel);
+    let (path, last_modified) = match &self.watched {
+      Some(watched) => watched.clone(),
+      None => return,
+    };
+    if !requested && self.last_checked.elapsed() < WATCH_INTERVAL {
+      return;
+    }
+    self.last_checked = Instant::now();
+
+    let modified = modified(&path);
+    if !requested && modified == last_modified {
+      return;
+    }
+    self.watched = Some((path.clone(), modified));
+
+    if let Err(e) = DaemonConfig::load(&path).and_then(|config| self.reload(config)) {
+      self.log.error("config_rejected", &[
+        ("path", json!(path.display().to_string())),
+        ("error", json!(e.to_string())),
+      ]);
+    }
+  }
+
+  fn flush_sinks(&mut self) {
+    for (name, sink) in self.sinks.iter_mut() {
+      if let Err(e) = sink.out.flush() {
+        self.log.error("sink_failed", &[("sink", json!(name)), ("error", json!(e.to_string()))]);
+      }
+    }
+  }
+}
+
+fn log_state(log: &mut EventLog, consumer: &mut RunningConsumer) {
+  let state = consumer.handle.state();
+  if state == consumer.state {
+    return;
+  }
+
+  let name = ("consumer", json!(consumer.config.name));
+  let plc = ("plc", json!(consumer.config.plc));
+  match &state {
+    ConsumerState::Connected { connection_id } => log.info("consumer_started", &[
+      name,
+      plc,
+      ("tag", json!(consumer.config.hint.tag)),
+      ("connection_id", json!(connection_id)),
+    ]),
+    ConsumerState::Retrying { attempts, error } => log.warn("consumer_failed", &[
+      name,
+      plc,
+      ("error", json!(error)),
+      ("attempts", json!(attempts)),
+    ]),
+    ConsumerState::Failed { error } => log.error("consumer_failed", &[
+      name,
+      plc,
+      ("error", json!(error)),
+    ]),
+    ConsumerState::Pending | ConsumerState::Stopped => (),
+  }
+  consumer.state = state;
+}
+
+fn stop(service: &mut Service, log: &mut EventLog, consumer: RunningConsumer, reason: &str) {
+  service.stop_consumer(&consumer.handle);
+  log.info("consumer_stopped", &[("consumer", json!(consumer.config.name)), ("reason", json!(reason))]);
+}
+
+fn create_capture(path: &Path, format: Option<CaptureFormat>) -> Result<Recorder> {
+  Recorder::create(path, format.unwrap_or_else(|| CaptureFormat::from_path(path)))
+}
+
+/*
+Where a config's capture sink records to, and in what format
+*/
+fn capture_key(config: &DaemonConfig) -> Option<(PathBuf, CaptureFormat)> {
+  config.sinks.iter().find_map(|sink| match &sink.kind {
+    SinkKind::Capture { path, format } => Some((path.clone(), format.unwrap_or_else(|| CaptureFormat::from_path(path)))),
+    _ => None,
+  })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/*
+A time as seconds since the epoch, to the microsecond
+*/
+pub fn timestamp(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  format!("{}.{:06}", since_epoch.as_secs(), since_epoch.subsec_micros())
+}
+
+/*
+A value as plain text: strings unquoted, null empty and arrays space-separated
+*/
+pub fn text(value: &Value) -> String {
+  match value {
+    Value::String(string) => string.clone(),
+    Value::Null => String::new(),
+    Value::Array(values) => values.iter().map(text).collect::<Vec<_>>().join(" "),
+    value => value.to_string(),
+  }
+}
+
+/*
+Quote a CSV field if it needs it
+*/
+pub fn csv_field(field: &str) -> String {
+  if field.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", field.replace('"', "\"\""))
+  } else {
+    String::from(field)
+  }
+}
+
+#[test]
+fn test_daemon_config() {
+  let toml = r#"
+    [service]
+    retry_min = 1000
+
+    [[plcs]]
+    name = "line1"
+    addr = "10.0.0.1"
+    slot = 2
+
+    [[consumers]]
+    name = "count"
+    plc = "line1"
+    tag = "Count"
+    data_size = 6
+    rpi = 100000
+    otrpi = 100000
+    decode = "DINT"
+    sinks = ["out"]
+
+    [[sinks]]
+    name = "out"
+    type = "File"
+    path = "/tmp/values.csv"
+    format = "Csv"
+  "#;
+  let config = DaemonConfig::from_toml(toml).unwrap();
+  assert_eq!(config.plcs[0].eip_addr(), EipAddr { addr: "10.0.0.1".parse().unwrap(), slot: 2 });
+  assert_eq!(config.plcs[0].profile, PlcProfile::ControlLogix);
+  assert_eq!(config.consumers[0].hint.tag, "Count");
+  assert_eq!(config.consumers[0].hint.data_size, 6);
+  assert_eq!(config.consumers[0].decode.value(&[1, 0, 0, 0]), json!(1));
+  assert_eq!(config.sinks[0].kind, SinkKind::File { path: PathBuf::from("/tmp/values.csv"), format: SinkFormat::Csv });
+  assert_eq!(config.service.retry_min, 1000);
+  assert_eq!(config.service.retry_max, ServiceConfig::default().retry_max);
+
+  let yaml = "
+plcs:
+  - { name: line1, addr: 10.0.0.1, profile: CompactLogix }
+consumers:
+  - { name: count, plc: line1, tag: Count, data_size: 6, rpi: 100000, otrpi: 100000 }
+sinks:
+  - { name: console, type: Stdout }
+";
+  let config = DaemonConfig::from_yaml(yaml).unwrap();
+  assert_eq!(config.service, ServiceConfig::default());
+  assert_eq!(config.consumers[0].decode, Decode::default());
+  assert_eq!(config.consumers[0].decode.value(&[1, 0xAB]), json!("01 ab"));
+
+  // References have to exist
+  assert!(DaemonConfig::from_yaml(&yaml.replace("plc: line1", "plc: line2")).is_err());
+  assert!(DaemonConfig::from_yaml(&yaml.replace(", otrpi: 100000", "")).is_err());
+  assert!(DaemonConfig::from_toml(&toml.replace("\"DINT\"", "\"UDT\"")).is_err());
+  assert!(DaemonConfig::from_toml(&toml.replace("[\"out\"]", "[\"missing\"]")).is_err());
+  assert!(DaemonConfig::from_toml(&toml.replace("slot = 2", "slot = 2\nprofile = \"CompactLogix\"")).is_err());
+}
+
+#[test]
+fn test_daemon_with_simulator() {
+  use std::sync::Mutex;
+  use crate::sim::Simulator;
+
+  #[derive(Clone, Default)]
+  struct Shared(Arc<Mutex<Vec<u8>>>);
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  let sim = Simulator::loopback(0).unwrap();
+  sim.add_tag("Count", 4);
+  sim.script("Count", Duration::from_millis(5), |tick, data| {
+    data.copy_from_slice(&(tick as u32).to_le_bytes());
+  }).unwrap();
+
+  let values = std::env::temp_dir().join(format!("rconpro-test-daemon-{}.jsonl", std::process::id()));
+  let _ = fs::remove_file(&values);
+  let mut config = DaemonConfig::from_toml(&format!(r#"
+    [[plcs]]
+    name = "sim"
+    addr = "127.0.0.1"
+    profile = "CompactLogix"
+
+    [[consumers]]
+    name = "count"
+    plc = "sim"
+    tag = "Count"
+    data_size = 6
+    rpi = 10000
+    otrpi = 10000
+    decode = "UDINT"
+
+    [[consumers]]
+    name = "missing"
+    plc = "sim"
+    tag = "Missing"
+    data_size = 6
+    rpi = 10000
+    otrpi = 10000
+
+    [[consumers]]
+    name = "oversized"
+    plc = "sim"
+    tag = "Count"
+    data_size = 600
+    rpi = 10000
+    otrpi = 10000
+
+    [[sinks]]
+    name = "values"
+    type = "File"
+    path = {:?}
+  "#, values)).unwrap();
+  config.service = sim.service_config().unwrap();
+
+  let log = Shared::default();
+  let mut daemon = Daemon::new(config, EventLog::new(log.clone())).unwrap();
+  let addr = daemon.consumer("count").unwrap().addr();
+  assert_eq!(daemon.service().profile(addr), PlcProfile::CompactLogix);
+  let running = Arc::new(AtomicBool::new(true));
+  let stopper = Arc::clone(&running);
+  thread::spawn(move || {
+    thread::sleep(Duration::from_millis(300));
+    stopper.store(false, Ordering::Release);
+  });
+  daemon.run(&running).unwrap();
+  assert!(sim.connected_tags().is_empty());
+
+  let rows: Vec<Value> = fs::read_to_string(&values).unwrap()
+    .lines()
+    .map(|line| serde_json::from_str(line).unwrap())
+    .collect();
+  assert!(rows.len() >= 10);
+  assert!(rows.iter().all(|row| row["consumer"] == "count" && row["value"].is_u64()));
+  assert!(rows.last().unwrap()["value"].as_u64().unwrap() > 0);
+
+  let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+  let lines: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+  let events: Vec<String> = lines.iter()
+    .map(|line| line["event"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(events.first().map(String::as_str), Some("started"));
+  assert!(events.contains(&String::from("consumer_started")));
+  assert!(events.contains(&String::from("consumer_failed")));
+  assert_eq!(events.last().map(String::as_str), Some("stopped"));
+
+  // Too big for a CompactLogix from the very first attempt
+  let oversized: Vec<&Value> = lines.iter()
+    .filter(|line| line["consumer"] == "oversized" && line["event"] != "consumer_stopped")
+    .collect();
+  assert_eq!(oversized.len(), 1);
+  assert_eq!((&oversized[0]["level"], &oversized[0]["event"]), (&json!("error"), &json!("consumer_failed")));
+  let _ = fs::remove_file(&values);
+}
+
+#[test]
+fn test_daemon_reload() {
+  use crate::sim::Simulator;
+
+  let sim = Simulator::loopback(0).unwrap();
+  sim.add_tag("A", 4);
+  sim.add_tag("B", 4);
+
+  let header = format!(r#"
+    [service]
+    {}
+    [[plcs]]
+    name = "sim"
+    addr = "127.0.0.1"
+  "#, toml::to_string(&sim.service_config().unwrap()).unwrap());
+  let consumer = |name: &str, tag: &str, rpi: usize, decode: &str| format!(r#"
+    [[consumers]]
+    name = "{}"
+    plc = "sim"
+    tag = "{}"
+    data_size = 6
+    rpi = {}
+    otrpi = {}
+    decode = "{}"
+  "#, name, tag, rpi, rpi, decode);
+  let connected = || {
+    let mut tags = sim.connected_tags();
+    tags.sort();
+    tags
+  };
+
+  let path = std::env::temp_dir().join(format!("rconpro-test-reload-{}.toml", std::process::id()));
+  fs::write(&path, format!("{}{}", header, consumer("a", "A", 10_000, "DINT"))).unwrap();
+  let mut daemon = Daemon::new(DaemonConfig::load(&path).unwrap(), EventLog::new(io::sink())).unwrap();
+  daemon.watch(&path);
+  daemon.start().unwrap();
+  let opened = |daemon: &Daemon, name: &str| match daemon.consumer(name).unwrap().wait(Duration::from_secs(2)) {
+    ConsumerState::Connected { connection_id } => connection_id,
+    state => panic!("{} is {:?}", name, state),
+  };
+  let a = opened(&daemon, "a");
+  assert_eq!(connected(), vec!["A"]);
+
+  // A new decode keeps a's connection; b is opened
+  let config = format!("{}{}{}", header, consumer("a", "A", 10_000, "UDINT"), consumer("b", "B", 10_000, "DINT"));
+  daemon.reload(DaemonConfig::from_toml(&config).unwrap()).unwrap();
+  assert_eq!(daemon.connection_id("a"), Some(a));
+  opened(&daemon, "b");
+  assert_eq!(connected(), vec!["A", "B"]);
+
+  // An invalid config changes nothing
+  let mut invalid = daemon.config().clone();
+  invalid.consumers[1].plc = String::from("nowhere");
+  assert!(daemon.reload(invalid).is_err());
+  assert_eq!(daemon.config().consumers[1].plc, "sim");
+  assert_eq!(connected(), vec!["A", "B"]);
+
+  // A new RPI re-opens b, and dropping a closes it; the file is picked up on request
+  let b = daemon.connection_id("b").unwrap();
+  fs::write(&path, format!("{}{}", header, consumer("b", "B", 20_000, "DINT"))).unwrap();
+  daemon.reload_trigger().store(true, Ordering::Release);
+  daemon.check_reload();
+  assert!(daemon.consumer("a").is_none());
+  assert_ne!(opened(&daemon, "b"), b);
+  assert_eq!(connected(), vec!["B"]);
+
+  // A new profile re-opens the PLC's consumers
+  let b = opened(&daemon, "b");
+  let profiled = header.replace("addr = \"127.0.0.1\"", "addr = \"127.0.0.1\"\n    profile = \"CompactLogix\"");
+  daemon.reload(DaemonConfig::from_toml(&format!("{}{}", profiled, consumer("b", "B", 20_000, "DINT"))).unwrap()).unwrap();
+  assert_ne!(opened(&daemon, "b"), b);
+  assert_eq!(daemon.service().profile(daemon.consumer("b").unwrap().addr()), PlcProfile::CompactLogix);
+  assert_eq!(connected(), vec!["B"]);
+
+  // A broken file is ignored
+  fs::write(&path, "plcs = 3").unwrap();
+  daemon.reload_trigger().store(true, Ordering::Release);
+  daemon.check_reload();
+  assert_eq!(daemon.config().consumers.len(), 1);
+  assert_eq!(connected(), vec!["B"]);
+
+  // Renaming a capture sink keeps the recording going instead of starting the file over
+  let capture = std::env::temp_dir().join(format!("rconpro-test-reload-{}.cap", std::process::id()));
+  let capture_sink = |name: &str| format!(r#"
+    [[sinks]]
+    name = "{}"
+    type = "Capture"
+    path = {:?}
+    format = "Native"
+  "#, name, capture);
+  let body = format!("{}{}", profiled, consumer("b", "B", 20_000, "DINT"));
+  daemon.reload(DaemonConfig::from_toml(&format!("{}{}", body, capture_sink("cap"))).unwrap()).unwrap();
+  thread::sleep(Duration::from_millis(100));
+  let renamed_at = SystemTime::now();
+  daemon.reload(DaemonConfig::from_toml(&format!("{}{}", body, capture_sink("capture"))).unwrap()).unwrap();
+
+  daemon.stop();
+  assert!(connected().is_empty());
+  let records: Vec<_> = crate::CaptureReader::open(&capture).unwrap().collect::<Result<_>>().unwrap();
+  assert!(records.iter().any(|record| record.timestamp < renamed_at));
+  let _ = fs::remove_file(&capture);
+  let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,742 @@
+use std::fmt;
+use std::io::{Result, Error, ErrorKind};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::eip::{self, ConnectionTriple, ForwardOpenReply, ForwardOpenRequest};
+use crate::identity::{self, Identity};
+use crate::tag::{self, TagDialect, TagValue};
+use crate::CipError;
+
+const HEADER_SIZE: usize = 24;
+
+// Encapsulation commands
+const LIST_IDENTITY: u16 = 0x63;
+const REGISTER_SESSION: u16 = 0x65;
+const SEND_RR_DATA: u16 = 0x6F;
+const SEND_UNIT_DATA: u16 = 0x70;
+
+// CPF items
+const CPF_NULL_ADDRESS: u16 = 0x0000;
+const CPF_IDENTITY: u16 = 0x000C;
+const CPF_CONNECTED_ADDRESS: u16 = 0x00A1;
+const CPF_CONNECTED_DATA: u16 = 0x00B1;
+const CPF_SEQUENCED_ADDRESS: u16 = 0x8002;
+
+// CIP services
+const REPLY: u8 = 0x80;
+const READ_TAG: u8 = 0x4C;
+const WRITE_TAG: u8 = 0x4D;
+const FORWARD_CLOSE: u8 = 0x4E;
+const UNCONNECTED_SEND: u8 = 0x52;
+const FORWARD_OPEN: u8 = 0x54;
+const LARGE_FORWARD_OPEN: u8 = 0x5B;
+const CONNECTION_MANAGER: u8 = 0x06;
+
+/*
+The 24 byte header every encapsulation packet starts with
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EncapsulationHeader {
+  pub command: u16,
+  pub length: u16,
+  pub session_handle: u32,
+  pub status: u32,
+  pub sender_context: u64,
+  pub options: u32,
+}
+
+pub fn parse_encapsulation_header(packet: &[u8]) -> Result<EncapsulationHeader> {
+  if packet.len() < HEADER_SIZE {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "encapsulation header is too short"));
+  }
+
+  return Ok(EncapsulationHeader {
+    command: LittleEndian::read_u16(&packet[0..2]),
+    length: LittleEndian::read_u16(&packet[2..4]),
+    session_handle: LittleEndian::read_u32(&packet[4..8]),
+    status: LittleEndian::read_u32(&packet[8..12]),
+    sender_context: LittleEndian::read_u64(&packet[12..20]),
+    options: LittleEndian::read_u32(&packet[20..24]),
+  });
+}
+
+/*
+One common packet format item
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CpfItem {
+  pub item_type: u16,
+  pub data: Vec<u8>,
+}
+
+/*
+Parse CPF items, starting at the item count
+*/
+pub fn parse_cpf(data: &[u8]) -> Result<Vec<CpfItem>> {
+  let truncated = || Error::new(ErrorKind::UnexpectedEof, "CPF items are truncated");
+
+  let item_count = LittleEndian::read_u16(data.get(0..2).ok_or_else(truncated)?);
+  let mut items = vec![];
+  let mut pos = 2;
+  for _ in 0..item_count {
+    let header = data.get(pos..pos + 4).ok_or_else(truncated)?;
+    let item_type = LittleEndian::read_u16(&header[0..2]);
+    let item_len = usize::from(LittleEndian::read_u16(&header[2..4]));
+    pos += 4;
+    items.push(CpfItem {
+      item_type,
+      data: data.get(pos..pos + item_len).ok_or_else(truncated)?.to_vec(),
+    });
+    pos += item_len;
+  }
+
+  return Ok(items);
+}
+
+/*
+An explicit CIP request: service, request path and request data
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CipRequest {
+  pub service: u8,
+  pub path: Vec<u8>,
+  pub data: Vec<u8>,
+}
+
+pub fn parse_cip_request(cip: &[u8]) -> Result<CipRequest> {
+  let too_short = || Error::new(ErrorKind::UnexpectedEof, "CIP request is too short");
+
+  let path_len = 2 * usize::from(*cip.get(1).ok_or_else(too_short)?);
+  return Ok(CipRequest {
+    service: cip[0],
+    path: cip.get(2..2 + path_len).ok_or_else(too_short)?.to_vec(),
+    data: cip[2 + path_len..].to_vec(),
+  });
+}
+
+/*
+An explicit CIP reply; service has the reply bit set
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CipResponse {
+  pub service: u8,
+  pub general_status: u8,
+  pub extended_status: Vec<u16>,
+  pub data: Vec<u8>,
+}
+
+pub fn parse_cip_response(cip: &[u8]) -> Result<CipResponse> {
+  if cip.len() < 4 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "CIP reply is too short"));
+  }
+  let data_start = 4 + 2 * usize::from(cip[3]);
+  if cip.len() < data_start {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "CIP reply extended status is truncated"));
+  }
+
+  return Ok(CipResponse {
+    service: cip[0],
+    general_status: cip[2],
+    extended_status: cip[4..data_start].chunks_exact(2).map(LittleEndian::read_u16).collect(),
+    data: cip[data_start..].to_vec(),
+  });
+}
+
+/*
+A class 1 packet: the sequenced address item, then the connected data item
+data includes the 16-bit CIP sequence count, and the run/idle header if the
+connection has one.
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IoPacket {
+  pub connection_id: u32,
+  pub sequence_count: u32,
+  pub data: Vec<u8>,
+}
+
+pub fn parse_io_packet(packet: &[u8]) -> Result<IoPacket> {
+  let items = parse_cpf(packet)?;
+  let address = items.iter()
+    .find(|item| item.item_type == CPF_SEQUENCED_ADDRESS && item.data.len() >= 8)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "class 1 packet has no sequenced address item"))?;
+  let data = items.iter()
+    .find(|item| item.item_type == CPF_CONNECTED_DATA)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "class 1 packet has no connected data item"))?;
+
+  return Ok(IoPacket {
+    connection_id: LittleEndian::read_u32(&address.data[0..4]),
+    sequence_count: LittleEndian::read_u32(&address.data[4..8]),
+    data: data.data.clone(),
+  });
+}
+
+/*
+A whole packet, decoded as far as we understand it
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Dissection {
+  Encapsulation(EncapsulationHeader, EncapsulationData),
+  Io(IoPacket),
+}
+
+/*
+What follows an encapsulation header
+SendRRData and SendUnitData carry an interface handle and timeout before their
+items; ListIdentity replies go straight to the items.
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EncapsulationData {
+  Empty,
+  RegisterSession { protocol_version: u16, options: u16 },
+  Items { interface_handle: Option<(u32, u16)>, items: Vec<CpfItem> },
+  Raw(Vec<u8>),
+}
+
+/*
+Decode an encapsulation packet or a class 1 packet
+Which one it is is told from the encapsulation length field: it has to match
+the rest of the packet.
+*/
+pub fn dissect(packet: &[u8]) -> Result<Dissection> {
+  let is_encapsulation = packet.len() >= HEADER_SIZE
+    && usize::from(LittleEndian::read_u16(&packet[2..4])) == packet.len() - HEADER_SIZE;
+  if !is_encapsulation {
+    return parse_io_packet(packet).map(Dissection::Io);
+  }
+
+  let header = parse_encapsulation_header(packet)?;
+  let data = &packet[HEADER_SIZE..];
+  let data = match header.command {
+    _ if data.is_empty() => EncapsulationData::Empty,
+    REGISTER_SESSION if data.len() >= 4 => EncapsulationData::RegisterSession {
+      protocol_version: LittleEndian::read_u16(&data[0..2]),
+      options: LittleEndian::read_u16(&data[2..4]),
+    },
+    SEND_RR_DATA | SEND_UNIT_DATA if data.len() >= 6 => EncapsulationData::Items {
+      interface_handle: Some((LittleEndian::read_u32(&data[0..4]), LittleEndian::read_u16(&data[4..6]))),
+      items: parse_cpf(&data[6..])?,
+    },
+    LIST_IDENTITY => EncapsulationData::Items {
+      interface_handle: None,
+      items: parse_cpf(data)?,
+    },
+    _ => EncapsulationData::Raw(data.to_vec()),
+  };
+
+  return Ok(Dissection::Encapsulation(header, data));
+}
+
+/*
+Read bytes written as hex, the way they're pasted from tests or Wireshark
+Whitespace, commas, colons and brackets between bytes are ignored, as are 0x
+prefixes.
+*/
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+  let digits: String = text.split(|c: char| c.is_whitespace() || ",:[]".contains(c))
+    .map(|chunk| chunk.trim_start_matches("0x").trim_start_matches("0X"))
+    .map(|chunk| if chunk.len() == 1 { format!("0{}", chunk) } else { String::from(chunk) })
+    .collect();
+  if digits.len() % 2 == 1 {
+    return Err(Error::new(ErrorKind::InvalidInput, "hex dump has an odd number of digits"));
+  }
+
+  (0..digits.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16)
+      .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{:?} isn't hex", &digits[i..i + 2]))))
+    .collect()
+}
+
+/*
+Describe a request path or connection path, one segment at a time
+*/
+pub fn format_path(path: &[u8]) -> String {
+  let segments = match eip::split_path_segments(path) {
+    Some(segments) => segments,
+    None => return format!("unparsed {}", hex(path)),
+  };
+
+  let described: Vec<String> = segments.into_iter().map(|segment| {
+    let u16_value = || LittleEndian::read_u16(&segment[2..4]);
+    let u32_value = || LittleEndian::read_u32(&segment[2..6]);
+    match segment[0] {
+      0x00..=0x0F => format!("port {} link {}", segment[0] & 0x0F, segment[1]),
+      0x10..=0x1F => {
+        let link = &segment[2..2 + usize::from(segment[1])];
+        format!("port {} link {}", segment[0] & 0x0F, String::from_utf8_lossy(link))
+      },
+      0x20 => format!("class {:#04x}", segment[1]),
+      0x21 => format!("class {:#06x}", u16_value()),
+      0x22 => format!("class {:#010x}", u32_value()),
+      0x24 => format!("instance {}", segment[1]),
+      0x25 => format!("instance {}", u16_value()),
+      0x26 => format!("instance {}", u32_value()),
+      0x28 => format!("element {}", segment[1]),
+      0x29 => format!("element {}", u16_value()),
+      0x2A => format!("element {}", u32_value()),
+      0x2C => format!("connection point {}", segment[1]),
+      0x2D => format!("connection point {}", u16_value()),
+      0x2E => format!("connection point {}", u32_value()),
+      0x30 => format!("attribute {}", segment[1]),
+      0x31 => format!("attribute {}", u16_value()),
+      0x34 => format!(
+        "key vendor {} device type {} product code {} revision {}.{}{}",
+        LittleEndian::read_u16(&segment[2..4]),
+        LittleEndian::read_u16(&segment[4..6]),
+        LittleEndian::read_u16(&segment[6..8]),
+        segment[8] & 0x7F,
+        segment[9],
+        if segment[8] & 0x80 != 0 { " (compatible)" } else { "" }
+      ),
+      0x43 => format!("production inhibit {} ms", segment[1]),
+      0x80 => format!("data {}", hex(&segment[2..])),
+      0x91 => format!("symbol {:?}", String::from_utf8_lossy(&segment[2..2 + usize::from(segment[1])])),
+      _ => hex(segment),
+    }
+  }).collect();
+
+  described.join(", ")
+}
+
+pub(crate) fn hex(data: &[u8]) -> String {
+  data.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+fn command_name(command: u16) -> &'static str {
+  match command {
+    0x00 => "NOP",
+    0x04 => "ListServices",
+    LIST_IDENTITY => "ListIdentity",
+    0x64 => "ListInterfaces",
+    REGISTER_SESSION => "RegisterSession",
+    0x66 => "UnRegisterSession",
+    SEND_RR_DATA => "SendRRData",
+    SEND_UNIT_DATA => "SendUnitData",
+    _ => "unknown command",
+  }
+}
+
+fn encapsulation_status_name(status: u32) -> &'static str {
+  match status {
+    0x00 => "success",
+    0x01 => "invalid or unsupported command",
+    0x02 => "insufficient memory",
+    0x03 => "incorrectly formed data",
+    0x64 => "invalid session handle",
+    0x65 => "invalid length",
+    0x69 => "unsupported protocol revision",
+    _ => "unknown status",
+  }
+}
+
+fn item_name(item_type: u16) -> &'static str {
+  match item_type {
+    CPF_NULL_ADDRESS => "null address",
+    CPF_IDENTITY => "identity",
+    CPF_CONNECTED_ADDRESS => "connected address",
+    CPF_CONNECTED_DATA => "connected data",
+    eip::CPF_UNCONNECTED_DATA => "unconnected data",
+    eip::CPF_SOCKADDR_OT => "sockaddr info O->T",
+    eip::CPF_SOCKADDR_TO => "sockaddr info T->O",
+    CPF_SEQUENCED_ADDRESS => "sequenced address",
+    _ => "unknown item",
+  }
+}
+
+/*
+Services that mean different things on different classes are named for the
+Connection Manager when the path goes there
+*/
+fn service_name(service: u8, connection_manager: bool) -> &'static str {
+  match service & !REPLY {
+    0x01 => "Get Attributes All",
+    0x03 => "Get Attribute List",
+    0x04 => "Set Attribute List",
+    0x05 => "Reset",
+    0x0E => "Get Attribute Single",
+    0x10 => "Set Attribute Single",
+    0x4B => "Execute PCCC",
+    READ_TAG => "Read Tag",
+    WRITE_TAG => "Write Tag",
+    FORWARD_CLOSE if connection_manager => "Forward Close",
+    FORWARD_CLOSE => "Read Modify Write Tag",
+    UNCONNECTED_SEND if connection_manager => "Unconnected Send",
+    UNCONNECTED_SEND => "Read Tag Fragmented",
+    0x53 => "Write Tag Fragmented",
+    FORWARD_OPEN => "Forward Open",
+    0x55 => "Get Instance Attribute List",
+    LARGE_FORWARD_OPEN => "Large Forward Open",
+    _ => "unknown service",
+  }
+}
+
+fn is_connection_manager(path: &[u8]) -> bool {
+  path.len() >= 2 && path[0] == 0x20 && path[1] == CONNECTION_MANAGER
+}
+
+/*
+Write a name and value as one aligned line
+*/
+fn field(f: &mut fmt::Formatter<'_>, name: &str, value: impl fmt::Display) -> fmt::Result {
+  writeln!(f, "  {:<22}{}", name, value)
+}
+
+/*
+Write something's own lines, indented under a heading
+*/
+fn nested(f: &mut fmt::Formatter<'_>, value: &impl fmt::Display) -> fmt::Result {
+  for line in value.to_string().lines() {
+    writeln!(f, "  {}", line)?;
+  }
+
+  Ok(())
+}
+
+impl fmt::Display for EncapsulationHeader {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Encapsulation header")?;
+    field(f, "command", format!("{:#06x} {}", self.command, command_name(self.command)))?;
+    field(f, "length", self.length)?;
+    field(f, "session handle", format!("{:#010x}", self.session_handle))?;
+    field(f, "status", format!("{:#010x} {}", self.status, encapsulation_status_name(self.status)))?;
+    field(f, "sender context", format!("{:#018x}", self.sender_context))?;
+    field(f, "options", format!("{:#010x}", self.options))
+  }
+}
+
+impl fmt::Display for CpfItem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Item {:#06x} {} ({} bytes)", self.item_type, item_name(self.item_type), self.data.len())?;
+
+    match self.item_type {
+      CPF_CONNECTED_ADDRESS if self.data.len() >= 4 => {
+        field(f, "connection ID", format!("{:#010x}", LittleEndian::read_u32(&self.data)))
+      },
+      CPF_SEQUENCED_ADDRESS if self.data.len() >= 8 => {
+        field(f, "connection ID", format!("{:#010x}", LittleEndian::read_u32(&self.data[0..4])))?;
+        field(f, "sequence count", LittleEndian::read_u32(&self.data[4..8]))
+      },
+      eip::CPF_SOCKADDR_OT | eip::CPF_SOCKADDR_TO => match eip::parse_sockaddr_info(&self.data) {
+        Ok(sockaddr) => field(f, "address", sockaddr),
+        Err(e) => field(f, "unparsed", e),
+      },
+      CPF_IDENTITY => match identity::parse_identity_item(&self.data) {
+        Ok(identity) => nested(f, &identity),
+        Err(e) => field(f, "unparsed", e),
+      },
+      eip::CPF_UNCONNECTED_DATA => nested(f, &CipMessage(&self.data)),
+      CPF_CONNECTED_DATA if self.data.len() >= 2 => {
+        // SendUnitData starts with the sequence count of the connection
+        field(f, "CIP sequence count", LittleEndian::read_u16(&self.data[0..2]))?;
+        nested(f, &CipMessage(&self.data[2..]))
+      },
+      _ if self.data.is_empty() => Ok(()),
+      _ => field(f, "data", hex(&self.data)),
+    }
+  }
+}
+
+/*
+A CIP request or reply, told apart by the reply bit of the service
+*/
+struct CipMessage<'a>(&'a [u8]);
+impl fmt::Display for CipMessage<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let parsed = match self.0.first() {
+      Some(service) if service & REPLY == 0 => parse_cip_request(self.0).map(|request| request.to_string()),
+      Some(_) => parse_cip_response(self.0).map(|response| response.to_string()),
+      None => return Ok(()),
+    };
+
+    match parsed {
+      Ok(message) => write!(f, "{}", message),
+      Err(e) => writeln!(f, "Unparsed CIP message ({}): {}", e, hex(self.0)),
+    }
+  }
+}
+
+impl fmt::Display for CipRequest {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let connection_manager = is_connection_manager(&self.path);
+    writeln!(f, "CIP request")?;
+    field(f, "service", format!("{:#04x} {}", self.service, service_name(self.service, connection_manager)))?;
+    field(f, "path", format_path(&self.path))?;
+
+    let mut cip = vec![self.service, (self.path.len() / 2) as u8];
+    cip.extend_from_slice(&self.path);
+    cip.extend_from_slice(&self.data);
+
+    match self.service {
+      FORWARD_OPEN | LARGE_FORWARD_OPEN if connection_manager => match eip::parse_forward_open_request(&cip) {
+        Ok(request) => nested(f, &request),
+        Err(e) => field(f, "unparsed", e),
+      },
+      FORWARD_CLOSE if connection_manager => match eip::parse_forward_close_request(&cip) {
+        Ok(triple) => nested(f, &triple),
+        Err(e) => field(f, "unparsed", e),
+      },
+      UNCONNECTED_SEND if connection_manager => write_unconnected_send(f, &self.data),
+      READ_TAG if self.data.len() >= 2 => field(f, "elements", LittleEndian::read_u16(&self.data)),
+      WRITE_TAG if self.data.len() >= 4 => {
+        let data_type = LittleEndian::read_u16(&self.data[0..2]);
+        let (handle_len, data_type) = if data_type == tag::TYPE_STRUCT && self.data.len() >= 6 {
+          (2, format!("{:#06x} struct {:#06x}", data_type, LittleEndian::read_u16(&self.data[2..4])))
+        } else {
+          (0, format!("{:#06x}", data_type))
+        };
+        field(f, "type", data_type)?;
+        field(f, "elements", LittleEndian::read_u16(&self.data[2 + handle_len..4 + handle_len]))?;
+        field(f, "data", hex(&self.data[4 + handle_len..]))
+      },
+      _ if self.data.is_empty() => Ok(()),
+      _ => field(f, "data", hex(&self.data)),
+    }
+  }
+}
+
+/*
+Priority, timeout, the embedded request and the route it takes
+*/
+fn write_unconnected_send(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+  if data.len() < 4 {
+    return field(f, "unparsed", hex(data));
+  }
+  field(f, "priority/time tick", data[0])?;
+  field(f, "timeout ticks", data[1])?;
+
+  let embedded_len = usize::from(LittleEndian::read_u16(&data[2..4]));
+  let embedded = match data.get(4..4 + embedded_len) {
+    Some(embedded) => embedded,
+    None => return field(f, "unparsed", hex(&data[2..])),
+  };
+  let route_start = 4 + embedded_len + embedded_len % 2;
+  if let Some(route_words) = data.get(route_start) {
+    let route = data.get(route_start + 2..).unwrap_or_default();
+    field(f, "route", format!("{} ({} words)", format_path(route), route_words))?;
+  }
+  nested(f, &CipMessage(embedded))
+}
+
+impl fmt::Display for CipResponse {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // A Forward Close reply carries the triple; a Read Modify Write reply nothing
+    let connection_manager = match self.service & !REPLY {
+      FORWARD_CLOSE => !self.data.is_empty(),
+      UNCONNECTED_SEND => self.general_status != 0,
+      _ => true,
+    };
+    writeln!(f, "CIP reply")?;
+    field(f, "service", format!("{:#04x} {} reply", self.service, service_name(self.service, connection_manager)))?;
+
+    if self.general_status != 0 {
+      field(f, "status", CipError::new(self.general_status, self.extended_status.clone()))?;
+      // Routing errors are followed by the remaining path size
+      return match self.data.first() {
+        Some(remaining) if self.service & !REPLY == UNCONNECTED_SEND => field(f, "remaining path size", remaining),
+        _ if self.data.is_empty() => Ok(()),
+        _ => field(f, "data", hex(&self.data)),
+      };
+    }
+    field(f, "status", "success")?;
+
+    let mut cip = vec![self.service, 0, 0, 0];
+    cip.extend_from_slice(&self.data);
+
+    match self.service & !REPLY {
+      FORWARD_OPEN | LARGE_FORWARD_OPEN => match eip::parse_cip_forward_open_reply(&cip) {
+        Ok(reply) => nested(f, &reply),
+        Err(e) => field(f, "unparsed", e),
+      },
+      FORWARD_CLOSE if connection_manager && self.data.len() >= 8 => {
+        nested(f, &ConnectionTriple {
+          connection_serial: LittleEndian::read_u16(&self.data[0..2]),
+          vendor_id: LittleEndian::read_u16(&self.data[2..4]),
+          originator_serial: LittleEndian::read_u32(&self.data[4..8]),
+        })
+      },
+      READ_TAG => match tag::parse_read_tag_reply(&cip, TagDialect::Logix) {
+        Ok(value) => field(f, "value", describe_value(&value)),
+        Err(_) => field(f, "data", hex(&self.data)),
+      },
+      _ if self.data.is_empty() => Ok(()),
+      _ => field(f, "data", hex(&self.data)),
+    }
+  }
+}
+
+fn describe_value(value: &TagValue) -> String {
+  match value {
+    TagValue::Struct { handle, data } => format!("struct {:#06x}: {}", handle, hex(data)),
+    _ => format!("{:?}", value),
+  }
+}
+
+impl fmt::Display for ForwardOpenRequest {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "{}", if self.large { "Large Forward Open" } else { "Forward Open" })?;
+    field(f, "priority/time tick", self.priority_time_tick)?;
+    field(f, "timeout ticks", self.timeout_ticks)?;
+    field(f, "O->T connection ID", format!("{:#010x}", self.ot_connection_id))?;
+    field(f, "T->O connection ID", format!("{:#010x}", self.to_connection_id))?;
+    nested(f, &self.triple)?;
+    field(f, "timeout multiplier", self.timeout_multiplier)?;
+    field(f, "O->T RPI", format!("{} us", self.ot_rpi))?;
+    field(f, "O->T parameters", format!("{:#x}: {:?}, {} bytes", self.ot_parameters, self.ot_connection_type(), self.ot_size()))?;
+    field(f, "T->O RPI", format!("{} us", self.to_rpi))?;
+    field(f, "T->O parameters", format!(
+      "{:#x}: {:?}, {} bytes{}",
+      self.to_parameters,
+      self.to_connection_type(),
+      self.to_size(),
+      if self.to_variable_size() { " variable" } else { "" }
+    ))?;
+    let trigger = match self.trigger() {
+      Some(trigger) => format!("{:?}", trigger),
+      None => String::from("unknown"),
+    };
+    field(f, "transport trigger", format!("{:#04x} {}", self.transport_trigger, trigger))?;
+    field(f, "connection path", format_path(&self.path))
+  }
+}
+
+impl fmt::Display for ForwardOpenReply {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Forward Open reply")?;
+    field(f, "O->T connection ID", format!("{:#010x}", self.ot_connection_id))?;
+    field(f, "T->O connection ID", format!("{:#010x}", self.to_connection_id))?;
+    nested(f, &self.triple())?;
+    field(f, "O->T API", format!("{} us", self.ot_api))?;
+    field(f, "T->O API", format!("{} us", self.to_api))?;
+    if let Some(sockaddr) = self.to_sockaddr {
+      field(f, "T->O sockaddr", sockaddr)?;
+    }
+
+    Ok(())
+  }
+}
+
+impl fmt::Display for ConnectionTriple {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Connection triple")?;
+    field(f, "connection serial", format!("{:#06x}", self.connection_serial))?;
+    field(f, "vendor ID", format!("{:#06x}", self.vendor_id))?;
+    field(f, "originator serial", format!("{:#010x}", self.originator_serial))
+  }
+}
+
+impl fmt::Display for Identity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Identity")?;
+    field(f, "product name", &self.product_name)?;
+    field(f, "address", self.socket_addr)?;
+    field(f, "vendor ID", self.vendor_id)?;
+    field(f, "device type", self.device_type)?;
+    field(f, "product code", self.product_code)?;
+    field(f, "revision", format!("{}.{}", self.major_revision, self.minor_revision))?;
+    field(f, "status", format!("{:#06x}", self.status))?;
+    field(f, "serial number", format!("{:#010x}", self.serial_number))?;
+    field(f, "state", self.state)
+  }
+}
+
+impl fmt::Display for IoPacket {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Class 1 packet")?;
+    field(f, "connection ID", format!("{:#010x}", self.connection_id))?;
+    field(f, "sequence count", self.sequence_count)?;
+    if self.data.len() >= 2 {
+      field(f, "CIP sequence count", LittleEndian::read_u16(&self.data[0..2]))?;
+      field(f, "data", format!("{} ({} bytes)", hex(&self.data[2..]), self.data.len() - 2))?;
+    } else {
+      field(f, "data", hex(&self.data))?;
+    }
+
+    Ok(())
+  }
+}
+
+impl fmt::Display for Dissection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let (header, data) = match self {
+      Dissection::Io(packet) => return write!(f, "{}", packet),
+      Dissection::Encapsulation(header, data) => (header, data),
+    };
+    write!(f, "{}", header)?;
+
+    match data {
+      EncapsulationData::Empty => Ok(()),
+      EncapsulationData::RegisterSession { protocol_version, options } => {
+        writeln!(f, "RegisterSession")?;
+        field(f, "protocol version", protocol_version)?;
+        field(f, "options", format!("{:#06x}", options))
+      },
+      EncapsulationData::Items { interface_handle, items } => {
+        if let Some((handle, timeout)) = interface_handle {
+          field(f, "interface handle", handle)?;
+          field(f, "timeout", timeout)?;
+        }
+        for item in items {
+          write!(f, "{}", item)?;
+        }
+
+        Ok(())
+      },
+      EncapsulationData::Raw(data) => {
+        writeln!(f, "Command data")?;
+        field(f, "data", hex(data))
+      },
+    }
+  }
+}
+
+#[test]
+fn test_dissect_forward_open() {
+  use crate::{CipRoute, ConsumerHint};
+
+  let hint = ConsumerHint {
+    tag: String::from("Produced"),
+    data_size: 10,
+    rpi: 20_000,
+    otrpi: 20_000,
+    ..ConsumerHint::default()
+  };
+  let packet = eip::build_forward_open_packet(&CipRoute::backplane(2), 0x1234, 9, &hint.forward_open_spec()).unwrap();
+  let dissection = dissect(&packet).unwrap();
+  match &dissection {
+    Dissection::Encapsulation(header, EncapsulationData::Items { items, .. }) => {
+      assert_eq!((header.command, header.session_handle, header.sender_context), (SEND_RR_DATA, 0x1234, 9));
+      assert_eq!(items.len(), 2);
+    },
+    _ => panic!("not a SendRRData: {:?}", dissection),
+  }
+
+  let text = dissection.to_string();
+  assert!(text.contains("SendRRData"));
+  assert!(text.contains("0x54 Forward Open"));
+  assert!(text.contains("port 1 link 2"));
+  assert!(text.contains("symbol \"Produced\""));
+  assert!(text.contains("T->O RPI              20000 us"));
+}
+
+#[test]
+fn test_dissect_io_and_replies() {
+  let packet = eip::build_output_packet(0x0102_0304, 9, 3, None, &[0xAA, 0xBB]);
+  assert_eq!(dissect(&packet).unwrap(), Dissection::Io(IoPacket {
+    connection_id: 0x0102_0304,
+    sequence_count: 9,
+    data: vec![3, 0, 0xAA, 0xBB],
+  }));
+
+  let error = parse_cip_response(&[0xD4, 0, 0x01, 1, 0x00, 0x01]).unwrap();
+  assert_eq!(error.extended_status, vec![0x0100]);
+  assert!(error.to_string().contains("Connection in use"));
+
+  let reply = eip::build_send_rr_data_packet(1, 2, &[0xCC, 0, 0, 0, 0xC4, 0, 0x2A, 0, 0, 0]);
+  assert!(dissect(&reply).unwrap().to_string().contains("value                 Dint(42)"));
+
+  let query = crate::tag::build_get_tag_list_query(0x12345);
+  assert_eq!(format_path(&query[2..10]), "class 0x6b, instance 74565");
+  assert_eq!(format_path(&[0x22, 0, 4, 3, 2, 1, 0x2E, 0, 0, 0, 1, 0]), "class 0x01020304, connection point 65536");
+
+  assert_eq!(parse_hex("0x6f, 00 [0a]\n1:2").unwrap(), vec![0x6F, 0x00, 0x0A, 0x01, 0x02]);
+  assert!(parse_hex("6f0").is_err());
+}
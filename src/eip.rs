@@ -1,9 +1,14 @@
 use std::convert::TryInto;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{Result, Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian, LittleEndian, WriteBytesExt};
 use rand::Rng;
 use encoding::{Encoding, EncoderTrap};
 use encoding::all::UTF_8;
 
+use crate::{AssemblyPath, CipError, CipRoute, ConnectionKind, ConnectionType, ForwardOpenParams, Keying, RoutingError, TransportTrigger};
+#[cfg(test)]
 use crate::ConsumerHint;
 
 
@@ -54,26 +59,869 @@ fn test_build_register_session() {
 
 
 /* Create Forward Open */
-pub fn build_forward_open_packet(slot: u8, session_handle: u32, hint: &ConsumerHint) -> Vec<u8>{
-  // Get bytes
-  let mut forward_open = build_cip_forward_open(slot, hint);
-  let mut header = build_eip_send_rr_data_header(
-    forward_open.len().try_into().unwrap(),
-    session_handle
+pub fn build_forward_open_packet(route: &CipRoute, session_handle: u32, sender_context: u64, spec: &ForwardOpenSpec) -> Result<Vec<u8>> {
+  return Ok(build_send_rr_data_packet(session_handle, sender_context, &build_cip_forward_open(route, spec)?));
+}
+
+
+/*
+Everything needed to build a Forward Open
+Consumers and producers both describe their connection with one of these. The
+application path is the assembly if there is one, and the tag otherwise. Without
+a route, the connection goes along the default route the builder is given,
+which is what the PLC's profile makes of its EipAddr. The sizes are the full
+connection sizes, including the CIP sequence count and any run/idle header.
+*/
+#[derive(Debug, Clone)]
+pub struct ForwardOpenSpec<'a> {
+  pub tag: &'a str,
+  pub assembly: Option<&'a AssemblyPath>,
+  pub route: Option<&'a CipRoute>,
+  pub keying: Keying,
+  pub kind: ConnectionKind,
+  pub params: &'a ForwardOpenParams,
+  pub ot_rpi: usize,
+  pub ot_size: usize,
+  pub to_rpi: usize,
+  pub to_size: usize,
+}
+
+
+/* Wrap an explicit (unconnected) CIP request in SendRRData */
+pub fn build_send_rr_data_packet(session_handle: u32, sender_context: u64, cip_request: &[u8]) -> Vec<u8> {
+  let mut packet = build_eip_send_rr_data_header(
+    cip_request.len().try_into().unwrap(),
+    session_handle,
+    sender_context
   );
+  packet.extend_from_slice(cip_request);
 
-  // Concatenate
-  header.append(&mut forward_open);
+  return packet;
+}
 
-  return header;
+#[test]
+fn test_build_send_rr_data_packet() {
+  assert_eq!(
+    build_send_rr_data_packet(0x11223344, 7, &[0x01, 0x02]),
+    vec![111, 0, 18, 0, 0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0,
+         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 178, 0, 2, 0, 1, 2]
+  );
+}
+
+
+/*
+Common packet format item types
+*/
+pub const CPF_UNCONNECTED_DATA: u16 = 0xB2;
+pub const CPF_SOCKADDR_OT: u16 = 0x8000;
+pub const CPF_SOCKADDR_TO: u16 = 0x8001;
+
+
+/*
+Split the CPF items out of a SendRRData reply
+Checks the encapsulation status and returns (type, data) for each item.
+*/
+pub fn parse_cpf_items(reply: &[u8]) -> Result<Vec<(u16, &[u8])>> {
+  const HEADER_SIZE: usize = 24;
+
+  if reply.len() < HEADER_SIZE + 8 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "SendRRData reply is too short"));
+  }
+
+  let status = LittleEndian::read_u32(&reply[8..12]);
+  if status != 0 {
+    return Err(Error::other(format!("encapsulation status {:#x}", status)));
+  }
+
+  // Walk the CPF items (after the interface handle and timeout)
+  let item_count = LittleEndian::read_u16(&reply[30..32]);
+  let mut items = Vec::with_capacity(item_count.into());
+  let mut pos = 32;
+  for _ in 0..item_count {
+    if reply.len() < pos + 4 {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "CPF item header is truncated"));
+    }
+    let item_type = LittleEndian::read_u16(&reply[pos..pos + 2]);
+    let item_len: usize = LittleEndian::read_u16(&reply[pos + 2..pos + 4]).into();
+    pos += 4;
+    if reply.len() < pos + item_len {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "CPF item data is truncated"));
+    }
+    items.push((item_type, &reply[pos..pos + item_len]));
+    pos += item_len;
+  }
+
+  return Ok(items);
+}
+
+
+/*
+Pull the CIP reply out of a SendRRData reply
+Returns the data of the unconnected data item (0xB2).
+*/
+pub fn parse_send_rr_data_reply(reply: &[u8]) -> Result<&[u8]> {
+  return parse_cpf_items(reply)?
+    .into_iter()
+    .find(|(item_type, _)| *item_type == CPF_UNCONNECTED_DATA)
+    .map(|(_, data)| data)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "SendRRData reply has no unconnected data item"));
+}
+
+#[test]
+fn test_parse_send_rr_data_reply() {
+  let packet = build_send_rr_data_packet(1, 2, &[0xCC, 0x00, 0x00, 0x00]);
+  assert_eq!(parse_send_rr_data_reply(&packet).unwrap(), &[0xCC, 0x00, 0x00, 0x00]);
+
+  let mut failed = packet.clone();
+  failed[8] = 0x64;
+  assert!(parse_send_rr_data_reply(&failed).is_err());
+}
+
+
+/*
+Build a sockaddr info item (0x8000 or 0x8001), big-endian like the parser expects
+*/
+pub fn build_sockaddr_info(addr: SocketAddrV4) -> Vec<u8> {
+  const AF_INET: u16 = 2;
+
+  let mut item = Vec::<u8>::with_capacity(16);
+  item.write_u16::<BigEndian>(AF_INET).unwrap();
+  item.write_u16::<BigEndian>(addr.port()).unwrap();
+  item.write_u32::<BigEndian>(u32::from(*addr.ip())).unwrap();
+  item.extend_from_slice(&[0; 8]);
+
+  return item;
+}
+
+/*
+Append a CPF item to a SendRRData packet
+The item count and encapsulation length are bumped to match.
+*/
+pub fn append_cpf_item(packet: &mut Vec<u8>, item_type: u16, data: &[u8]) {
+  packet.write_u16::<LittleEndian>(item_type).unwrap();
+  packet.write_u16::<LittleEndian>(data.len().try_into().unwrap()).unwrap();
+  packet.extend_from_slice(data);
+
+  let items = LittleEndian::read_u16(&packet[30..32]) + 1;
+  LittleEndian::write_u16(&mut packet[30..32], items);
+  let length = (packet.len() - 24).try_into().unwrap();
+  LittleEndian::write_u16(&mut packet[2..4], length);
+}
+
+/*
+Parse a sockaddr info item (0x8000 or 0x8001)
+Unlike everything else in EIP, these fields are big-endian.
+*/
+pub fn parse_sockaddr_info(item: &[u8]) -> Result<SocketAddrV4> {
+  const AF_INET: u16 = 2;
+
+  if item.len() < 16 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "sockaddr info item is too short"));
+  }
+  if BigEndian::read_u16(&item[0..2]) != AF_INET {
+    return Err(Error::new(ErrorKind::InvalidData, "sockaddr info item is not AF_INET"));
+  }
+
+  return Ok(SocketAddrV4::new(
+    Ipv4Addr::from(BigEndian::read_u32(&item[4..8])),
+    BigEndian::read_u16(&item[2..4])
+  ));
+}
+
+
+/*
+The useful parts of a successful Forward Open reply
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ForwardOpenReply {
+  pub ot_connection_id: u32,
+  pub to_connection_id: u32,
+  pub connection_serial: u16,
+  pub vendor_id: u16,
+  pub originator_serial: u32,
+  pub ot_api: u32,
+  pub to_api: u32,
+  pub to_sockaddr: Option<SocketAddrV4>,
+}
+
+/*
+Parse the SendRRData reply to a (Large) Forward Open
+*/
+pub fn parse_forward_open_reply(reply: &[u8]) -> Result<ForwardOpenReply> {
+  let items = parse_cpf_items(reply)?;
+  let cip = items.iter()
+    .find(|(item_type, _)| *item_type == CPF_UNCONNECTED_DATA)
+    .map(|(_, data)| *data)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Forward Open reply has no unconnected data item"))?;
+
+  let mut reply = parse_cip_forward_open_reply(cip)?;
+  if let Some((_, data)) = items.iter().find(|(item_type, _)| *item_type == CPF_SOCKADDR_TO) {
+    reply.to_sockaddr = Some(parse_sockaddr_info(data)?);
+  }
+
+  return Ok(reply);
+}
+
+/*
+Parse the CIP reply to a (Large) Forward Open
+The T->O sockaddr comes in a CPF item of its own, so it's left empty here.
+*/
+pub fn parse_cip_forward_open_reply(cip: &[u8]) -> Result<ForwardOpenReply> {
+  if cip.len() < 4 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Forward Open reply is too short"));
+  }
+  if let Some(error) = CipError::from_reply(cip) {
+    return Err(error.into());
+  }
+  if cip.len() < 30 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Forward Open reply is too short"));
+  }
+
+  return Ok(ForwardOpenReply {
+    ot_connection_id: LittleEndian::read_u32(&cip[4..8]),
+    to_connection_id: LittleEndian::read_u32(&cip[8..12]),
+    connection_serial: LittleEndian::read_u16(&cip[12..14]),
+    vendor_id: LittleEndian::read_u16(&cip[14..16]),
+    originator_serial: LittleEndian::read_u32(&cip[16..20]),
+    ot_api: LittleEndian::read_u32(&cip[20..24]),
+    to_api: LittleEndian::read_u32(&cip[24..28]),
+    to_sockaddr: None,
+  });
+}
+
+#[cfg(test)]
+fn forward_open_reply_for_test(general_status: u8, to_sockaddr: Option<[u8; 16]>) -> Vec<u8> {
+  let mut cip = vec![0xD4, 0, general_status, 0];
+  cip.write_u32::<LittleEndian>(0x1111).unwrap();
+  cip.write_u32::<LittleEndian>(0x2222).unwrap();
+  cip.extend_from_slice(&[0; 8]);
+  cip.write_u32::<LittleEndian>(1_000).unwrap();
+  cip.write_u32::<LittleEndian>(2_000).unwrap();
+  cip.extend_from_slice(&[0, 0]);
+
+  let mut reply = build_send_rr_data_packet(1, 2, &cip);
+  if let Some(sockaddr) = to_sockaddr {
+    append_cpf_item(&mut reply, CPF_SOCKADDR_TO, &sockaddr);
+  }
+
+  return reply;
+}
+
+#[test]
+fn test_parse_forward_open_reply() {
+  let sockaddr = [0, 2, 0x08, 0xAE, 239, 192, 1, 5, 0, 0, 0, 0, 0, 0, 0, 0];
+  assert_eq!(build_sockaddr_info(SocketAddrV4::new(Ipv4Addr::new(239, 192, 1, 5), 2222)), sockaddr);
+  let reply = parse_forward_open_reply(&forward_open_reply_for_test(0, Some(sockaddr))).unwrap();
+
+  assert_eq!(reply, ForwardOpenReply {
+    ot_connection_id: 0x1111,
+    to_connection_id: 0x2222,
+    connection_serial: 0,
+    vendor_id: 0,
+    originator_serial: 0,
+    ot_api: 1_000,
+    to_api: 2_000,
+    to_sockaddr: Some(SocketAddrV4::new(Ipv4Addr::new(239, 192, 1, 5), 2222)),
+  });
+
+  // Listen-only with no owner: general status 0x01, one extended status word 0x0119
+  let mut reply = forward_open_reply_for_test(0x01, None);
+  reply[43] = 1;
+  reply[44..46].copy_from_slice(&[0x19, 0x01]);
+  let error = parse_forward_open_reply(&reply).unwrap_err();
+  assert!(CipError::from_io(&error).unwrap().is_owner_not_found());
+}
+
+
+/*
+The connection triple that identifies a connection to Forward Close
+*/
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct ConnectionTriple {
+  pub connection_serial: u16,
+  pub vendor_id: u16,
+  pub originator_serial: u32,
+}
+impl ForwardOpenReply {
+  pub fn triple(&self) -> ConnectionTriple {
+    ConnectionTriple {
+      connection_serial: self.connection_serial,
+      vendor_id: self.vendor_id,
+      originator_serial: self.originator_serial,
+    }
+  }
+}
+
+
+/*
+A Forward Open request, as a target sees it
+The network connection parameters are kept as sent; use the accessors to read
+them, since their layout depends on whether this was a Large Forward Open.
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ForwardOpenRequest {
+  pub large: bool,
+  pub priority_time_tick: u8,
+  pub timeout_ticks: u8,
+  pub ot_connection_id: u32,
+  pub to_connection_id: u32,
+  pub triple: ConnectionTriple,
+  pub timeout_multiplier: u8,
+  pub ot_rpi: u32,
+  pub ot_parameters: u32,
+  pub to_rpi: u32,
+  pub to_parameters: u32,
+  pub transport_trigger: u8,
+  pub path: Vec<u8>,
+}
+impl ForwardOpenRequest {
+  pub fn ot_size(&self) -> usize {
+    network_parameters_size(self.ot_parameters, self.large)
+  }
+
+  pub fn to_size(&self) -> usize {
+    network_parameters_size(self.to_parameters, self.large)
+  }
+
+  pub fn to_connection_type(&self) -> ConnectionType {
+    network_parameters_type(self.to_parameters, self.large)
+  }
+
+  pub fn ot_connection_type(&self) -> ConnectionType {
+    network_parameters_type(self.ot_parameters, self.large)
+  }
+
+  pub fn trigger(&self) -> Option<TransportTrigger> {
+    TransportTrigger::decode(self.transport_trigger)
+  }
+
+  /*
+  The production inhibit time from the connection path, if there is one
+  */
+  pub fn production_inhibit(&self) -> Option<Duration> {
+    parse_production_inhibit(&self.path).map(|ms| Duration::from_millis(ms.into()))
+  }
+
+  pub fn to_variable_size(&self) -> bool {
+    let bit = if self.large { 25 } else { 9 };
+    self.to_parameters & (1 << bit) != 0
+  }
+}
+
+fn network_parameters_size(parameters: u32, large: bool) -> usize {
+  if large {
+    (parameters & 0xFFFF) as usize
+  } else {
+    (parameters & 0x1FF) as usize
+  }
+}
+
+fn network_parameters_type(parameters: u32, large: bool) -> ConnectionType {
+  let shift = if large { 29 } else { 13 };
+  match (parameters >> shift) & 0x3 {
+    0 => ConnectionType::Null,
+    1 => ConnectionType::Multicast,
+    _ => ConnectionType::PointToPoint,
+  }
+}
+
+/*
+Parse a (Large) Forward Open request out of the CIP data of a SendRRData
+*/
+pub fn parse_forward_open_request(cip: &[u8]) -> Result<ForwardOpenRequest> {
+  const CIP_FORWARD_OPEN: u8 = 0x54;
+  const CIP_LARGE_FORWARD_OPEN: u8 = 0x5B;
+
+  let too_short = || Error::new(ErrorKind::UnexpectedEof, "Forward Open request is too short");
+
+  if cip.len() < 2 {
+    return Err(too_short());
+  }
+  let large = match cip[0] {
+    CIP_FORWARD_OPEN => false,
+    CIP_LARGE_FORWARD_OPEN => true,
+    _ => return Err(Error::new(ErrorKind::InvalidData, "not a Forward Open request")),
+  };
+
+  // Skip the request path (the Connection Manager)
+  let mut pos = 2 + 2 * usize::from(cip[1]);
+  let params_len = if large { 4 } else { 2 };
+  if cip.len() < pos + 32 + 2 * params_len {
+    return Err(too_short());
+  }
+
+  let priority_time_tick = cip[pos];
+  let timeout_ticks = cip[pos + 1];
+  let ot_connection_id = LittleEndian::read_u32(&cip[pos + 2..pos + 6]);
+  let to_connection_id = LittleEndian::read_u32(&cip[pos + 6..pos + 10]);
+  let triple = ConnectionTriple {
+    connection_serial: LittleEndian::read_u16(&cip[pos + 10..pos + 12]),
+    vendor_id: LittleEndian::read_u16(&cip[pos + 12..pos + 14]),
+    originator_serial: LittleEndian::read_u32(&cip[pos + 14..pos + 18]),
+  };
+  let timeout_multiplier = cip[pos + 18];
+  pos += 22;
+
+  let read_parameters = |pos: usize| if large {
+    LittleEndian::read_u32(&cip[pos..pos + 4])
+  } else {
+    u32::from(LittleEndian::read_u16(&cip[pos..pos + 2]))
+  };
+
+  let ot_rpi = LittleEndian::read_u32(&cip[pos..pos + 4]);
+  let ot_parameters = read_parameters(pos + 4);
+  pos += 4 + params_len;
+  let to_rpi = LittleEndian::read_u32(&cip[pos..pos + 4]);
+  let to_parameters = read_parameters(pos + 4);
+  pos += 4 + params_len;
+
+  let transport_trigger = cip[pos];
+  let path_len = 2 * usize::from(cip[pos + 1]);
+  pos += 2;
+  if cip.len() < pos + path_len {
+    return Err(too_short());
+  }
+
+  return Ok(ForwardOpenRequest {
+    large,
+    priority_time_tick,
+    timeout_ticks,
+    ot_connection_id,
+    to_connection_id,
+    triple,
+    timeout_multiplier,
+    ot_rpi,
+    ot_parameters,
+    to_rpi,
+    to_parameters,
+    transport_trigger,
+    path: cip[pos..pos + path_len].to_vec(),
+  });
+}
+
+#[test]
+fn test_parse_forward_open_request() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    data_size: 1200,
+    rpi: 1000,
+    otrpi: 1100,
+    ..ConsumerHint::default()
+  };
+
+  let request = parse_forward_open_request(&build_cip_forward_open(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap()).unwrap();
+  assert!(request.large);
+  assert_eq!(request.ot_rpi, 1100);
+  assert_eq!(request.to_rpi, 1000);
+  assert_eq!(request.ot_size(), 2);
+  assert_eq!(request.to_size(), 1200);
+  assert_eq!(request.to_connection_type(), ConnectionType::PointToPoint);
+  assert_eq!(request.triple.originator_serial, 42);
+  assert_eq!(parse_symbolic_path(&request.path), Some(String::from("Test")));
+}
+
+
+/*
+Pull the tag name out of a connection path
+Port, key, connection point and data segments are skipped over; the symbolic
+segments are joined with dots. Returns None if the path doesn't parse or has no
+symbolic segment.
+*/
+pub fn parse_symbolic_path(path: &[u8]) -> Option<String> {
+  let names: Vec<String> = split_path_segments(path)?
+    .into_iter()
+    .filter(|segment| segment[0] == 0x91)
+    .map(|segment| String::from_utf8(segment[2..2 + usize::from(segment[1])].to_vec()).ok())
+    .collect::<Option<_>>()?;
+
+  if names.is_empty() {
+    None
+  } else {
+    Some(names.join("."))
+  }
+}
+
+/*
+Pull the Assembly object application path out of a connection path
+The first connection point is the O->T one and the second the T->O one, the
+way build_assembly_path writes them. Configuration data comes back padded to a
+whole number of words. Returns None if the path doesn't parse or doesn't go to
+the Assembly class.
+*/
+pub fn parse_assembly_path(path: &[u8]) -> Option<AssemblyPath> {
+  let segments = split_path_segments(path)?;
+  let class = segments.iter().position(|segment| segment[..] == [0x20, 0x04])?;
+
+  let mut assembly = AssemblyPath::default();
+  let mut points = vec![];
+  for segment in &segments[class + 1..] {
+    match segment[0] {
+      0x24 => assembly.config = u16::from(segment[1]),
+      0x25 => assembly.config = LittleEndian::read_u16(&segment[2..4]),
+      0x2C => points.push(u16::from(segment[1])),
+      0x2D => points.push(LittleEndian::read_u16(&segment[2..4])),
+      0x80 => assembly.config_data = segment[2..].to_vec(),
+      _ => (),
+    }
+  }
+
+  match points[..] {
+    [output, input] => {
+      assembly.output = output;
+      assembly.input = input;
+      Some(assembly)
+    },
+    _ => None,
+  }
+}
+
+/*
+Split a connection path into its segments
+Returns None if there is a segment we don't know or the path is truncated.
+*/
+pub(crate) fn split_path_segments(path: &[u8]) -> Option<Vec<&[u8]>> {
+  let mut segments = vec![];
+  let mut pos = 0;
+
+  while pos < path.len() {
+    let segment = path[pos];
+    let len = match segment {
+      // Port segment, possibly with an extended link address
+      0x00..=0x0F => 2,
+      0x10..=0x1F => {
+        let len = usize::from(*path.get(pos + 1)?);
+        2 + len + len % 2
+      },
+      // Logical segments with 8-bit values
+      0x20 | 0x24 | 0x28 | 0x2C | 0x30 => 2,
+      // Logical segments with 16-bit values
+      0x21 | 0x25 | 0x29 | 0x2D | 0x31 => 4,
+      // Logical segments with 32-bit values
+      0x22 | 0x26 | 0x2A | 0x2E => 6,
+      // Electronic key
+      0x34 => 10,
+      // Network segments (production inhibit time and friends)
+      0x43 => 2,
+      // Simple data segment
+      0x80 => 2 + 2 * usize::from(*path.get(pos + 1)?),
+      // ANSI extended symbolic segment
+      0x91 => {
+        let len = usize::from(*path.get(pos + 1)?);
+        2 + len + len % 2
+      },
+      _ => return None,
+    };
+
+    segments.push(path.get(pos..pos + len)?);
+    pos += len;
+  }
+
+  Some(segments)
+}
+
+/*
+The production inhibit time network segment of a connection path, in milliseconds
+*/
+pub fn parse_production_inhibit(path: &[u8]) -> Option<u8> {
+  split_path_segments(path)?
+    .into_iter()
+    .find(|segment| segment[0] == 0x43)
+    .map(|segment| segment[1])
+}
+
+
+/*
+Build the CIP reply to a Forward Open
+*/
+pub fn build_cip_forward_open_reply(request: &ForwardOpenRequest, ot_connection_id: u32, to_connection_id: u32) -> Vec<u8> {
+  let service = if request.large { 0x5B } else { 0x54 } | 0x80;
+
+  let mut reply = Vec::<u8>::with_capacity(30);
+  reply.write_u8(service).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u32::<LittleEndian>(ot_connection_id).unwrap();
+  reply.write_u32::<LittleEndian>(to_connection_id).unwrap();
+  reply.write_u16::<LittleEndian>(request.triple.connection_serial).unwrap();
+  reply.write_u16::<LittleEndian>(request.triple.vendor_id).unwrap();
+  reply.write_u32::<LittleEndian>(request.triple.originator_serial).unwrap();
+  reply.write_u32::<LittleEndian>(request.ot_rpi).unwrap();
+  reply.write_u32::<LittleEndian>(request.to_rpi).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+
+  return reply;
+}
+
+/*
+Build a CIP error reply
+Forward Open and Forward Close failures also carry the connection triple; pass
+it in so the originator can match the reply up.
+*/
+pub fn build_cip_error_reply(service: u8, general_status: u8, extended_status: &[u16], triple: Option<&ConnectionTriple>) -> Vec<u8> {
+  let mut reply = Vec::<u8>::with_capacity(20);
+  reply.write_u8(service | 0x80).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(general_status).unwrap();
+  reply.write_u8(extended_status.len().try_into().unwrap()).unwrap();
+  for status in extended_status {
+    reply.write_u16::<LittleEndian>(*status).unwrap();
+  }
+
+  if let Some(triple) = triple {
+    reply.write_u16::<LittleEndian>(triple.connection_serial).unwrap();
+    reply.write_u16::<LittleEndian>(triple.vendor_id).unwrap();
+    reply.write_u32::<LittleEndian>(triple.originator_serial).unwrap();
+    // Remaining path size and reserved
+    reply.write_u8(0).unwrap();
+    reply.write_u8(0).unwrap();
+  }
+
+  return reply;
+}
+
+#[test]
+fn test_forward_open_reply_round_trip() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    data_size: 6,
+    rpi: 1000,
+    otrpi: 1100,
+    ..ConsumerHint::default()
+  };
+  let request = parse_forward_open_request(&build_cip_forward_open(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap()).unwrap();
+
+  let cip = build_cip_forward_open_reply(&request, 7, 8);
+  let reply = parse_forward_open_reply(&build_send_rr_data_packet(1, 2, &cip)).unwrap();
+  assert_eq!(reply.ot_connection_id, 7);
+  assert_eq!(reply.to_connection_id, 8);
+  assert_eq!(reply.triple(), request.triple);
+  assert_eq!(reply.to_api, 1000);
+
+  let cip = build_cip_error_reply(0x54, 0x01, &[0x0111], Some(&request.triple));
+  let error = parse_forward_open_reply(&build_send_rr_data_packet(1, 2, &cip)).unwrap_err();
+  assert_eq!(CipError::from_io(&error).unwrap().extended(), Some(0x0111));
 }
 
 
-fn build_eip_send_rr_data_header(frame_len: u16, session_handle: u32) -> Vec<u8> {
+/* Forward Close */
+pub fn build_forward_close_packet(route: &CipRoute, session_handle: u32, sender_context: u64, triple: &ConnectionTriple) -> Vec<u8> {
+  return build_send_rr_data_packet(session_handle, sender_context, &build_cip_forward_close(route, triple));
+}
+
+fn build_cip_forward_close(route: &CipRoute, triple: &ConnectionTriple) -> Vec<u8> {
+  const CIP_SERVICE: u8 = 0x4E;
+  const CIP_PATH_SIZE: u8 = 0x02;
+  const CIP_CLASS_TYPE: u8 = 0x20;
+  const CIP_CLASS: u8 = 0x06;
+  const CIP_INSTANCE_TYPE: u8 = 0x24;
+  const CIP_INSTANCE: u8 = 0x01;
+  const CIP_PRIORITY: u8 = 0x0A;
+  const CIP_TIMEOUT_TICKS: u8 = 0x0e;
+
+  let mut forward_close = Vec::<u8>::with_capacity(24);
+
+  forward_close.write_u8(CIP_SERVICE).unwrap();
+  forward_close.write_u8(CIP_PATH_SIZE).unwrap();
+  forward_close.write_u8(CIP_CLASS_TYPE).unwrap();
+  forward_close.write_u8(CIP_CLASS).unwrap();
+  forward_close.write_u8(CIP_INSTANCE_TYPE).unwrap();
+  forward_close.write_u8(CIP_INSTANCE).unwrap();
+  forward_close.write_u8(CIP_PRIORITY).unwrap();
+  forward_close.write_u8(CIP_TIMEOUT_TICKS).unwrap();
+  forward_close.write_u16::<LittleEndian>(triple.connection_serial).unwrap();
+  forward_close.write_u16::<LittleEndian>(triple.vendor_id).unwrap();
+  forward_close.write_u32::<LittleEndian>(triple.originator_serial).unwrap();
+
+  // Connection path (size in words, then a reserved byte)
+  let mut path = route.encode();
+  forward_close.write_u8( (path.len()/2).try_into().unwrap() ).unwrap();
+  forward_close.write_u8(0).unwrap();
+  forward_close.append(&mut path);
+
+  return forward_close;
+}
+
+/*
+Parse the connection triple out of a Forward Close request
+*/
+pub fn parse_forward_close_request(cip: &[u8]) -> Result<ConnectionTriple> {
+  const CIP_FORWARD_CLOSE: u8 = 0x4E;
+
+  if cip.len() < 2 || cip[0] != CIP_FORWARD_CLOSE {
+    return Err(Error::new(ErrorKind::InvalidData, "not a Forward Close request"));
+  }
+  let pos = 2 + 2 * usize::from(cip[1]);
+  if cip.len() < pos + 10 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Forward Close request is too short"));
+  }
+
+  return Ok(ConnectionTriple {
+    connection_serial: LittleEndian::read_u16(&cip[pos + 2..pos + 4]),
+    vendor_id: LittleEndian::read_u16(&cip[pos + 4..pos + 6]),
+    originator_serial: LittleEndian::read_u32(&cip[pos + 6..pos + 10]),
+  });
+}
+
+/*
+Build the CIP reply to a Forward Close
+*/
+pub fn build_cip_forward_close_reply(triple: &ConnectionTriple) -> Vec<u8> {
+  const CIP_REPLY_SERVICE: u8 = 0xCE;
+
+  let mut reply = Vec::<u8>::with_capacity(14);
+  reply.write_u8(CIP_REPLY_SERVICE).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u16::<LittleEndian>(triple.connection_serial).unwrap();
+  reply.write_u16::<LittleEndian>(triple.vendor_id).unwrap();
+  reply.write_u32::<LittleEndian>(triple.originator_serial).unwrap();
+  reply.write_u8(0).unwrap();
+  reply.write_u8(0).unwrap();
+
+  return reply;
+}
+
+#[test]
+fn test_forward_close_round_trip() {
+  let triple = ConnectionTriple {
+    connection_serial: 0x1234,
+    vendor_id: 1,
+    originator_serial: 42,
+  };
+
+  assert_eq!(parse_forward_close_request(&build_cip_forward_close(&CipRoute::backplane(0), &triple)).unwrap(), triple);
+  assert_eq!(
+    build_cip_forward_close_reply(&triple),
+    vec![0xCE, 0, 0, 0, 0x34, 0x12, 1, 0, 42, 0, 0, 0, 0, 0]
+  );
+}
+
+
+/*
+Wrap an explicit request in an Unconnected Send to the Connection Manager
+The Connection Manager forwards the embedded request along the route and hands
+back the reply of the device at the end of it.
+*/
+pub fn build_unconnected_send(route: &CipRoute, cip_request: &[u8]) -> Vec<u8> {
+  const CIP_SERVICE: u8 = 0x52;
+  const CIP_PATH_SIZE: u8 = 0x02;
+  const CIP_CLASS_TYPE: u8 = 0x20;
+  const CIP_CLASS: u8 = 0x06;
+  const CIP_INSTANCE_TYPE: u8 = 0x24;
+  const CIP_INSTANCE: u8 = 0x01;
+  const CIP_PRIORITY: u8 = 0x0A;
+  const CIP_TIMEOUT_TICKS: u8 = 0x0e;
+
+  let mut path = route.encode();
+  let mut unconnected_send = Vec::<u8>::with_capacity(12 + cip_request.len() + path.len());
+
+  unconnected_send.write_u8(CIP_SERVICE).unwrap();
+  unconnected_send.write_u8(CIP_PATH_SIZE).unwrap();
+  unconnected_send.write_u8(CIP_CLASS_TYPE).unwrap();
+  unconnected_send.write_u8(CIP_CLASS).unwrap();
+  unconnected_send.write_u8(CIP_INSTANCE_TYPE).unwrap();
+  unconnected_send.write_u8(CIP_INSTANCE).unwrap();
+  unconnected_send.write_u8(CIP_PRIORITY).unwrap();
+  unconnected_send.write_u8(CIP_TIMEOUT_TICKS).unwrap();
+
+  // Embedded request, padded to a word boundary
+  unconnected_send.write_u16::<LittleEndian>(cip_request.len().try_into().unwrap()).unwrap();
+  unconnected_send.extend_from_slice(cip_request);
+  if cip_request.len() % 2 == 1 {
+    unconnected_send.write_u8(0).unwrap();
+  }
+
+  // Route path (size in words, then a reserved byte)
+  unconnected_send.write_u8( (path.len()/2).try_into().unwrap() ).unwrap();
+  unconnected_send.write_u8(0).unwrap();
+  unconnected_send.append(&mut path);
+
+  return unconnected_send;
+}
+
+/*
+Unwrap the reply to an Unconnected Send
+A successful send answers with the embedded reply itself. An Unconnected Send
+reply with an error status means the request never got to the device, and
+becomes a RoutingError.
+*/
+pub fn parse_unconnected_send_reply(cip: &[u8]) -> Result<&[u8]> {
+  const CIP_REPLY_SERVICE: u8 = 0xD2;
+
+  if cip.len() < 4 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Unconnected Send reply is too short"));
+  }
+
+  if cip[0] == CIP_REPLY_SERVICE {
+    if let Some(error) = CipError::from_reply(cip) {
+      // The remaining path size follows the extended status
+      let remaining_path_size = cip.get(4 + 2 * usize::from(cip[3])).copied();
+      return Err(RoutingError::new(error, remaining_path_size).into());
+    }
+  }
+
+  Ok(cip)
+}
+
+#[test]
+fn test_build_unconnected_send() {
+  let route: CipRoute = "1,3".parse().unwrap();
+  assert_eq!(
+    build_unconnected_send(&route, &[0x4C, 0x01, 0x91]),
+    vec![0x52, 0x02, 0x20, 0x06, 0x24, 0x01, 0x0A, 0x0E,
+         3, 0, 0x4C, 0x01, 0x91, 0,
+         1, 0, 0x01, 0x03]
+  );
+}
+
+#[test]
+fn test_parse_unconnected_send_reply() {
+  let embedded = [0xCC, 0, 0, 0, 0xC4, 0, 1, 0, 0, 0];
+  assert_eq!(parse_unconnected_send_reply(&embedded).unwrap(), &embedded);
+
+  // No module in the slot
+  let error = parse_unconnected_send_reply(&[0xD2, 0, 0x01, 1, 0x12, 0x03, 1]).unwrap_err();
+  let routing = RoutingError::from_io(&error).unwrap();
+  assert_eq!(routing.kind, crate::RoutingErrorKind::LinkAddressNotValid);
+  assert_eq!(routing.remaining_path_size, Some(1));
+}
+
+/*
+Build an encapsulation packet from its parts
+Targets use this to answer requests; the sender context is echoed back.
+*/
+pub fn build_encapsulation(command: u16, session_handle: u32, status: u32, sender_context: u64, data: &[u8]) -> Vec<u8> {
+  const EIP_OPTIONS: u32 = 0x00;
+
+  let mut packet = Vec::<u8>::with_capacity(24 + data.len());
+
+  packet.write_u16::<LittleEndian>(command).unwrap();
+  packet.write_u16::<LittleEndian>(data.len().try_into().unwrap()).unwrap();
+  packet.write_u32::<LittleEndian>(session_handle).unwrap();
+  packet.write_u32::<LittleEndian>(status).unwrap();
+  packet.write_u64::<LittleEndian>(sender_context).unwrap();
+  packet.write_u32::<LittleEndian>(EIP_OPTIONS).unwrap();
+  packet.extend_from_slice(data);
+
+  return packet;
+}
+
+#[test]
+fn test_build_encapsulation() {
+  assert_eq!(
+    build_encapsulation(0x65, 0, 0, 0, &[1, 0, 0, 0]),
+    build_register_session()
+  );
+}
+
+
+fn build_eip_send_rr_data_header(frame_len: u16, session_handle: u32, sender_context: u64) -> Vec<u8> {
   const EIP_COMMAND: u16 = 0x6F;
   let eip_length: u16 = 16+frame_len;
   const EIP_STATUS: u32 = 0x00;
-  const EIP_CONTEXT: u64 = 0x8000004a00000000;
   const EIP_OPTIONS: u32 = 0x00;
 
   const EIP_INTERFACE_HANDLE: u32 = 0x00;
@@ -90,7 +938,7 @@ fn build_eip_send_rr_data_header(frame_len: u16, session_handle: u32) -> Vec<u8>
   header.write_u16::<LittleEndian>(eip_length).unwrap();
   header.write_u32::<LittleEndian>(session_handle).unwrap();
   header.write_u32::<LittleEndian>(EIP_STATUS).unwrap();
-  header.write_u64::<LittleEndian>(EIP_CONTEXT).unwrap();
+  header.write_u64::<LittleEndian>(sender_context).unwrap();
   header.write_u32::<LittleEndian>(EIP_OPTIONS).unwrap();
   header.write_u32::<LittleEndian>(EIP_INTERFACE_HANDLE).unwrap();
   header.write_u16::<LittleEndian>(EIP_TIMEOUT).unwrap();
@@ -106,22 +954,30 @@ fn build_eip_send_rr_data_header(frame_len: u16, session_handle: u32) -> Vec<u8>
 #[test]
 fn test_build_eip_send_rr_data_header() {
   assert_eq!(
-    build_eip_send_rr_data_header(0, 0),
+    build_eip_send_rr_data_header(0, 0, 0x8000004a00000000),
     vec![111, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 74, 0, 0, 128, 0, 0,
          0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 178, 0, 0, 0]
   )
 }
 
 
-fn build_cip_forward_open(slot: u8, hint: &ConsumerHint) -> Vec<u8> {
-  const CIP_SERVICE: u8 = 0x54;
+/*
+Largest connection size that fits the 9-bit field of the 16-bit network
+connection parameters; anything bigger needs a Large Forward Open
+*/
+pub const MAX_FORWARD_OPEN_SIZE: usize = 511;
+pub const MAX_LARGE_FORWARD_OPEN_SIZE: usize = 0xFFFF;
+
+fn build_cip_forward_open(route: &CipRoute, spec: &ForwardOpenSpec) -> Result<Vec<u8>> {
+  const CIP_FORWARD_OPEN: u8 = 0x54;
+  const CIP_LARGE_FORWARD_OPEN: u8 = 0x5B;
   const CIP_PATH_SIZE: u8 = 0x02;
   const CIP_CLASS_TYPE: u8 = 0x20;
   const CIP_CLASS: u8 = 0x06;
   const CIP_INSTANCE_TYPE: u8 = 0x24;
   const CIP_INSTANCE: u8 = 0x01;
-  const CIP_PRIORITY: u8 = 0x0A;
-  const CIP_TIMEOUT_TICKS: u8 = 0x0e;
+
+  let params = spec.params;
 
   // Random number generator
   let mut rng = rand::thread_rng();
@@ -129,76 +985,150 @@ fn build_cip_forward_open(slot: u8, hint: &ConsumerHint) -> Vec<u8> {
   const CIP_OT_CONNECTION_ID: u32 = 0x00;
   let cip_to_connection_id: u32 = rng.gen_range(0..65000);
   let cip_connection_serial_number: u16 = rng.gen_range(0..65000);
-  const CIP_VENDOR_ID: u16 = 0x01;
-  const CIP_ORIGINATOR_SERIAL_NUMBER: u32 = 42;
-  const CIP_MULTIPLIER: u32 = 0x00;
-  let cip_ot_rpi: u32 = hint.otrpi.try_into().unwrap();
-  const CIP_OT_NETWORK_CONNECTION_PARAMETERS: u16 = 0x4802;
-  let cip_to_rpi: u32 = hint.rpi.try_into().unwrap();
-  let cip_to_network_connection_parameters: u16 = (0x4800 + hint.data_size).try_into().unwrap();
-  
-  const CIP_TRANSPORT_TRIGGER: u8 = 0x81;
+  let cip_multiplier: u32 = params.timeout_multiplier as u32;
+  let cip_ot_rpi: u32 = spec.ot_rpi.try_into().unwrap();
+  let cip_ot_connection_size: u16 = spec.ot_size.try_into()
+    .expect("connection size is too big for a Large Forward Open");
+  let cip_to_rpi: u32 = spec.to_rpi.try_into().unwrap();
+  let cip_to_connection_size: u16 = spec.to_size.try_into()
+    .expect("connection size is too big for a Large Forward Open");
+
+  let large = spec.ot_size > MAX_FORWARD_OPEN_SIZE || spec.to_size > MAX_FORWARD_OPEN_SIZE;
 
   // Build bytes
   let mut forward_open = Vec::<u8>::with_capacity(328);
 
-  forward_open.write_u8(CIP_SERVICE).unwrap();
+  forward_open.write_u8(if large { CIP_LARGE_FORWARD_OPEN } else { CIP_FORWARD_OPEN }).unwrap();
   forward_open.write_u8(CIP_PATH_SIZE).unwrap();
   forward_open.write_u8(CIP_CLASS_TYPE).unwrap();
   forward_open.write_u8(CIP_CLASS).unwrap();
   forward_open.write_u8(CIP_INSTANCE_TYPE).unwrap();
   forward_open.write_u8(CIP_INSTANCE).unwrap();
-  forward_open.write_u8(CIP_PRIORITY).unwrap();
-  forward_open.write_u8(CIP_TIMEOUT_TICKS).unwrap();
+  forward_open.write_u8(params.priority_time_tick).unwrap();
+  forward_open.write_u8(params.timeout_ticks).unwrap();
   forward_open.write_u32::<LittleEndian>(CIP_OT_CONNECTION_ID).unwrap();
   forward_open.write_u32::<LittleEndian>(cip_to_connection_id).unwrap();
   forward_open.write_u16::<LittleEndian>(cip_connection_serial_number).unwrap();
-  forward_open.write_u16::<LittleEndian>(CIP_VENDOR_ID).unwrap();
-  forward_open.write_u32::<LittleEndian>(CIP_ORIGINATOR_SERIAL_NUMBER).unwrap();
-  forward_open.write_u32::<LittleEndian>(CIP_MULTIPLIER).unwrap();
+  forward_open.write_u16::<LittleEndian>(params.vendor_id).unwrap();
+  forward_open.write_u32::<LittleEndian>(params.originator_serial).unwrap();
+  forward_open.write_u32::<LittleEndian>(cip_multiplier).unwrap();
   forward_open.write_u32::<LittleEndian>(cip_ot_rpi).unwrap();
-  forward_open.write_u16::<LittleEndian>(CIP_OT_NETWORK_CONNECTION_PARAMETERS).unwrap();
+  if large {
+    forward_open.write_u32::<LittleEndian>(params.ot.encode_large(params.redundant_owner, cip_ot_connection_size)).unwrap();
+  } else {
+    forward_open.write_u16::<LittleEndian>(params.ot.encode(params.redundant_owner, cip_ot_connection_size)).unwrap();
+  }
   forward_open.write_u32::<LittleEndian>(cip_to_rpi).unwrap();
-  forward_open.write_u16::<LittleEndian>(cip_to_network_connection_parameters).unwrap();
-  forward_open.write_u8(CIP_TRANSPORT_TRIGGER).unwrap();
+  if large {
+    forward_open.write_u32::<LittleEndian>(params.to.encode_large(false, cip_to_connection_size)).unwrap();
+  } else {
+    forward_open.write_u16::<LittleEndian>(params.to.encode(false, cip_to_connection_size)).unwrap();
+  }
+  forward_open.write_u8(params.trigger.encode()).unwrap();
 
   // Add the connection path
-  let mut path = build_connection_path(slot, hint);
+  let mut path = build_connection_path(route, spec)?;
   forward_open.write_u8( (path.len()/2).try_into().unwrap() ).unwrap();
   forward_open.append(&mut path);
 
-  return forward_open;
+  return Ok(forward_open);
+}
+
+#[test]
+fn test_build_cip_forward_open() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    data_size: 6,
+    rpi: 1000,
+    otrpi: 1100,
+    ..ConsumerHint::default()
+  };
+
+  let forward_open = build_cip_forward_open(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap();
+  assert_eq!(forward_open[0], 0x54);
+  assert_eq!(forward_open[28..40], [76, 4, 0, 0, 0x02, 0x48, 232, 3, 0, 0, 0x06, 0x48]);
+  assert_eq!(forward_open[40], 0x81);
+  assert_eq!(forward_open[41], 9);
+  assert_eq!(forward_open.len(), 42 + 18);
 }
 
+#[test]
+fn test_build_cip_forward_open_params() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    data_size: 6,
+    rpi: 1000,
+    otrpi: 1100,
+    params: crate::ForwardOpenParams::builder()
+      .to_connection_type(crate::ConnectionType::Multicast)
+      .timeout_multiplier(crate::TimeoutMultiplier::X32)
+      .vendor_id(0x1234)
+      .build(),
+    ..ConsumerHint::default()
+  };
 
-fn build_connection_path(slot: u8, hint: &ConsumerHint) -> Vec<u8> {
-  const PORT_SEGMENT: u8 = 0x01;
-  let LINK_ADDRESS = slot;
+  let forward_open = build_cip_forward_open(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap();
+  assert_eq!(forward_open[18..20], [0x34, 0x12]);
+  assert_eq!(forward_open[24], 3);
+  assert_eq!(forward_open[38..40], [0x06, 0x28]);
+}
+
+#[test]
+fn test_build_cip_large_forward_open() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    data_size: 1200,
+    rpi: 1000,
+    otrpi: 1100,
+    ..ConsumerHint::default()
+  };
+
+  let forward_open = build_cip_forward_open(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap();
+  assert_eq!(forward_open[0], 0x5B);
+  assert_eq!(
+    forward_open[28..44],
+    [76, 4, 0, 0, 0x02, 0x00, 0x00, 0x48, 232, 3, 0, 0, 0xB0, 0x04, 0x00, 0x48]
+  );
+  assert_eq!(forward_open[44], 0x81);
+  assert_eq!(forward_open.len(), 46 + 18);
+}
+
+
+fn build_connection_path(route: &CipRoute, spec: &ForwardOpenSpec) -> Result<Vec<u8>> {
   const KEY_SEGMENT: u8 = 0x34;
   const KEY_FORMAT: u8 = 0x04;
-  const VENDOR_ID: u16 = 0x00;
-  const DEVICE_TYPE: u16 = 0x00;
-  const PRODUCT_CODE: u16 = 0x00;
-  const MAJOR_REVISION: u8 = 0x00;
-  const MINOR_REVISION: u8 = 0x00;
+  const PRODUCTION_INHIBIT_SEGMENT: u8 = 0x43;
 
   // Build bytes
   let mut path = Vec::<u8>::with_capacity(96);
 
-  path.write_u8(PORT_SEGMENT).unwrap();
-  path.write_u8(LINK_ADDRESS).unwrap();
+  // Route to the target
+  path.append( &mut spec.route.unwrap_or(route).encode() );
+
   path.write_u8(KEY_SEGMENT).unwrap();
   path.write_u8(KEY_FORMAT).unwrap();
-  path.write_u16::<LittleEndian>(VENDOR_ID).unwrap();
-  path.write_u16::<LittleEndian>(DEVICE_TYPE).unwrap();
-  path.write_u16::<LittleEndian>(PRODUCT_CODE).unwrap();
-  path.write_u8(MAJOR_REVISION).unwrap();
-  path.write_u8(MINOR_REVISION).unwrap();
+  path.extend_from_slice(&spec.keying.encode());
 
-  // Add tag
-  path.append( &mut build_tag_ioi(&hint.tag) );
+  // Change-of-state connections can limit how often the target produces
+  if let Some(inhibit) = spec.params.production_inhibit {
+    path.write_u8(PRODUCTION_INHIBIT_SEGMENT).unwrap();
+    path.write_u8(inhibit).unwrap();
+  }
 
-  return path;
+  match spec.assembly {
+    Some(assembly) => path.append( &mut build_assembly_path(assembly, spec.kind) ),
+    None => {
+      // Heartbeat connection points only exist on assemblies
+      if spec.kind.heartbeat_point().is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{:?} connections need an assembly path", spec.kind)));
+      }
+
+      // Add tag
+      path.append( &mut build_tag_ioi(spec.tag)? );
+    }
+  }
+
+  return Ok(path);
 }
 
 #[test]
@@ -207,17 +1137,141 @@ fn test_build_connection_path() {
     tag: String::from("Test"),
     data_size: 6,
     rpi: 1000,
-    otrpi: 1100
+    otrpi: 1100,
+    ..ConsumerHint::default()
   };
 
   assert_eq!(
-    build_connection_path(0, &hint),
+    build_connection_path(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap(),
     vec![1, 0, 52, 4, 0, 0, 0, 0, 0, 0, 0, 0, 145, 4, 84, 101, 115, 116]
   );
 }
 
+#[test]
+fn test_build_routed_connection_path() {
+  let route: CipRoute = "1,1,2,10.0.0.5,1,0".parse().unwrap();
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    route: Some(route.clone()),
+    ..ConsumerHint::default()
+  };
+
+  let path = build_connection_path(&CipRoute::backplane(3), &hint.forward_open_spec()).unwrap();
+  assert_eq!(path[..14], route.encode()[..]);
+  assert_eq!(path[14..16], [52, 4]);
+  assert_eq!(parse_symbolic_path(&path), Some(String::from("Test")));
+}
+
+#[test]
+fn test_build_keyed_connection_path() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    keying: Keying {
+      mode: crate::KeyingMode::CompatibleMatch,
+      vendor_id: 1,
+      device_type: 14,
+      product_code: 166,
+      major_revision: 32,
+      minor_revision: 11,
+    },
+    ..ConsumerHint::default()
+  };
+
+  assert_eq!(
+    build_connection_path(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap(),
+    vec![1, 0, 52, 4, 1, 0, 14, 0, 166, 0, 160, 11, 145, 4, 84, 101, 115, 116]
+  );
+}
+
+#[test]
+fn test_build_listen_only_connection_path() {
+  let hint = ConsumerHint {
+    tag: String::from("Test"),
+    kind: crate::ConnectionKind::ListenOnly,
+    ..ConsumerHint::default()
+  };
+
+  let error = build_connection_path(&CipRoute::backplane(0), &hint.forward_open_spec()).unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+
+/*
+Build the application path for an Assembly object connection
+Class, configuration instance, O->T and T->O connection points, then the
+configuration data (if any).
+*/
+fn build_assembly_path(assembly: &AssemblyPath, kind: ConnectionKind) -> Vec<u8> {
+  const CLASS_SEGMENT: u8 = 0x20;
+  const ASSEMBLY_CLASS: u8 = 0x04;
+  const INSTANCE_SEGMENT: u8 = 0x24;
+  const CONNECTION_POINT_SEGMENT: u8 = 0x2C;
+  const DATA_SEGMENT: u8 = 0x80;
+
+  let mut path = Vec::<u8>::with_capacity(16 + assembly.config_data.len());
+
+  path.write_u8(CLASS_SEGMENT).unwrap();
+  path.write_u8(ASSEMBLY_CLASS).unwrap();
+  write_logical_segment(&mut path, INSTANCE_SEGMENT, assembly.config);
+
+  let output = kind.heartbeat_point().map(u16::from).unwrap_or(assembly.output);
+  write_logical_segment(&mut path, CONNECTION_POINT_SEGMENT, output);
+  write_logical_segment(&mut path, CONNECTION_POINT_SEGMENT, assembly.input);
+
+  if !assembly.config_data.is_empty() {
+    let words = assembly.config_data.len().div_ceil(2);
+    path.write_u8(DATA_SEGMENT).unwrap();
+    path.write_u8(words.try_into().expect("configuration data is too long for a data segment")).unwrap();
+    path.extend_from_slice(&assembly.config_data);
+    if assembly.config_data.len() % 2 == 1 {
+      path.push(0x00);
+    }
+  }
+
+  return path;
+}
+
+/*
+Write an 8-bit logical segment, or the 16-bit form (with its pad byte) when the
+value doesn't fit
+*/
+fn write_logical_segment(path: &mut Vec<u8>, segment: u8, value: u16) {
+  if value <= 0xFF {
+    path.write_u8(segment).unwrap();
+    path.write_u8(value as u8).unwrap();
+  } else {
+    path.write_u8(segment | 0x01).unwrap();
+    path.write_u8(0x00).unwrap();
+    path.write_u16::<LittleEndian>(value).unwrap();
+  }
+}
 
-fn build_tag_ioi(tag: &str) -> Vec<u8> {
+#[test]
+fn test_build_assembly_path() {
+  let assembly = AssemblyPath {
+    config: 1,
+    output: 150,
+    input: 300,
+    config_data: vec![1, 2, 3],
+  };
+
+  assert_eq!(
+    build_assembly_path(&assembly, ConnectionKind::ExclusiveOwner),
+    vec![0x20, 0x04, 0x24, 1, 0x2C, 150, 0x2D, 0, 0x2C, 0x01, 0x80, 2, 1, 2, 3, 0]
+  );
+  assert_eq!(
+    build_assembly_path(&assembly, ConnectionKind::InputOnly)[4..6],
+    [0x2C, 198]
+  );
+
+  let path = build_connection_path(&CipRoute::backplane(0), &ConsumerHint { assembly: Some(assembly.clone()), ..ConsumerHint::default() }.forward_open_spec()).unwrap();
+  assert_eq!(parse_assembly_path(&path), Some(AssemblyPath { config_data: vec![1, 2, 3, 0], ..assembly }));
+  assert_eq!(parse_assembly_path(&[0x20, 0x04, 0x24, 1, 0x2C, 150]), None);
+  assert_eq!(parse_assembly_path(&[0x91, 0x01, b'A', 0]), None);
+}
+
+
+pub(crate) fn build_tag_ioi(tag: &str) -> Result<Vec<u8>> {
   /*
   Fron pyconpro:
 
@@ -228,31 +1282,62 @@ fn build_tag_ioi(tag: &str) -> Vec<u8> {
   TagName (DINT)
   TagName.1 (Bit of DINT) <- not handling this!
   TagName.Thing (UDT)
-  TagName[4].Thing[2].Length (more complex UDT) <- we're not going to handle this for now
+  TagName[4].Thing[2].Length (more complex UDT)
   We also might be reading arrays, a bool from arrays (atomic), strings.
       Oh and multi-dim arrays, program scope tags...
   */
 
   let mut ioi = vec![];
   for tag_component in tag.split(".") {
+    // Array indexes become element segments after the name
+    let (name, indexes) = match tag_component.find('[') {
+      Some(bracket) => (&tag_component[..bracket], &tag_component[bracket..]),
+      None => (tag_component, ""),
+    };
+
     ioi.write_u8(0x91).unwrap();
-    ioi.write_u8(tag_component.len().try_into().unwrap() ).unwrap();
-    ioi.append( &mut UTF_8.encode(tag_component, EncoderTrap::Strict).unwrap() );
+    ioi.write_u8(name.len().try_into().unwrap() ).unwrap();
+    ioi.append( &mut UTF_8.encode(name, EncoderTrap::Strict).unwrap() );
 
-    if tag_component.len() % 2 == 1 {
+    if name.len() % 2 == 1 {
       ioi.push(0x00);
     }
+
+    let indexes = indexes.trim_start_matches('[').trim_end_matches(']');
+    for index in indexes.split([',', '[', ']']).filter(|index| !index.is_empty()) {
+      let index: u32 = index.trim().parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{:?} isn't a valid array index in {}", index, tag)))?;
+      if index <= 0xFF {
+        ioi.write_u8(0x28).unwrap();
+        ioi.write_u8(index as u8).unwrap();
+      } else if index <= 0xFFFF {
+        ioi.write_u8(0x29).unwrap();
+        ioi.write_u8(0).unwrap();
+        ioi.write_u16::<LittleEndian>(index as u16).unwrap();
+      } else {
+        ioi.write_u8(0x2A).unwrap();
+        ioi.write_u8(0).unwrap();
+        ioi.write_u32::<LittleEndian>(index).unwrap();
+      }
+    }
   }
 
-  return ioi;
+  return Ok(ioi);
 }
 
 #[test]
 fn test_build_tag_ioi() {
   assert_eq!(
-    build_tag_ioi("Test"),
+    build_tag_ioi("Test").unwrap(),
     vec![145, 4, 84, 101, 115, 116]
   );
+  assert_eq!(
+    build_tag_ioi("Arr[2,300].X").unwrap(),
+    vec![145, 3, 65, 114, 114, 0, 0x28, 2, 0x29, 0, 0x2C, 0x01, 145, 1, 88, 0]
+  );
+  assert_eq!(build_tag_ioi("Arr[x]").unwrap_err().kind(), ErrorKind::InvalidInput);
+  assert!(build_tag_ioi("Arr[-1]").is_err());
+  assert!(build_tag_ioi("Arr[1,]").is_ok());
 }
 
 
@@ -263,14 +1348,36 @@ fn test_build_tag_ioi() {
 
 /* Keep-Alive packet */
 pub fn build_response_packet(ot_connection_id: u32, sequence_count: u32) -> Vec<u8> {
+  const CIP_SEQUENCE_COUNT: u16 = 1;
+
+  return build_output_packet(ot_connection_id, sequence_count, CIP_SEQUENCE_COUNT, None, &[]);
+}
+
+#[test]
+fn test_build_response_packet() {
+  assert_eq!(
+    build_response_packet(0, 0),
+    vec![2, 0, 2, 128, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 177, 0, 2, 0, 1, 0]
+  );
+}
+
+
+/*
+Class 1 O->T packet
+sequence_count is the encapsulation sequence number, which goes up with every
+packet; cip_sequence_count only goes up when the data changes. run_idle adds
+the 32-bit run/idle header (bit 0 set for run) in front of the data.
+*/
+pub fn build_output_packet(ot_connection_id: u32, sequence_count: u32, cip_sequence_count: u16, run_idle: Option<bool>, data: &[u8]) -> Vec<u8> {
   const ITEM_COUNT: u16 = 0x02;
   const TYPE_ID: u16 = 0x8002;
   const LENGTH: u16 = 0x08;
   const CONN_DATA: u16 = 0x00b1;
-  const DATA_LENGTH: u16 = 0x02;
-  const SEQUENCE_COUNT: u16 = 1;
 
-  let mut payload = Vec::<u8>::with_capacity(20);
+  let header_len = if run_idle.is_some() { 4 } else { 0 };
+  let data_length: u16 = (2 + header_len + data.len()).try_into().unwrap();
+
+  let mut payload = Vec::<u8>::with_capacity(18 + usize::from(data_length));
 
   payload.write_u16::<LittleEndian>(ITEM_COUNT).unwrap();
   payload.write_u16::<LittleEndian>(TYPE_ID).unwrap();
@@ -280,27 +1387,21 @@ pub fn build_response_packet(ot_connection_id: u32, sequence_count: u32) -> Vec<
   payload.write_u32::<LittleEndian>(sequence_count).unwrap();
 
   payload.write_u16::<LittleEndian>(CONN_DATA).unwrap();
-  payload.write_u16::<LittleEndian>(DATA_LENGTH).unwrap();
-  payload.write_u16::<LittleEndian>(SEQUENCE_COUNT).unwrap();
+  payload.write_u16::<LittleEndian>(data_length).unwrap();
+  payload.write_u16::<LittleEndian>(cip_sequence_count).unwrap();
+
+  if let Some(run) = run_idle {
+    payload.write_u32::<LittleEndian>(u32::from(run)).unwrap();
+  }
+  payload.extend_from_slice(data);
 
   return payload;
 }
 
 #[test]
-fn test_build_response_packet() {
+fn test_build_output_packet() {
   assert_eq!(
-    build_response_packet(0, 0),
-    vec![2, 0, 2, 128, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 177, 0, 2, 0, 1, 0]
+    build_output_packet(0x01020304, 9, 3, Some(true), &[0xAA, 0xBB]),
+    vec![2, 0, 2, 128, 8, 0, 4, 3, 2, 1, 9, 0, 0, 0, 177, 0, 8, 0, 3, 0, 1, 0, 0, 0, 0xAA, 0xBB]
   );
 }
-
-
-
-
-
-
-
-/* Get Tag List */
-pub fn build_get_tag_list_query() {
-  
-}
\ No newline at end of file
@@ -0,0 +1,222 @@
+use std::io::{Result, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+
+use crate::eip;
+
+/*
+A device's identity, as reported by ListIdentity
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct Identity {
+  pub protocol_version: u16,
+  pub socket_addr: SocketAddrV4,
+  pub vendor_id: u16,
+  pub device_type: u16,
+  pub product_code: u16,
+  pub major_revision: u8,
+  pub minor_revision: u8,
+  pub status: u16,
+  pub serial_number: u32,
+  pub product_name: String,
+  pub state: u8,
+}
+
+/* List Identity */
+pub fn build_list_identity(sender_context: u64) -> Vec<u8> {
+  const EIP_COMMAND: u16 = 0x63;
+
+  return eip::build_encapsulation(EIP_COMMAND, 0, 0, sender_context, &[]);
+}
+
+#[test]
+fn test_build_list_identity() {
+  assert_eq!(
+    build_list_identity(0),
+    vec![0x63, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+  );
+}
+
+/*
+Parse the identity items (0x0C) out of a ListIdentity reply
+*/
+pub fn parse_list_identity_reply(reply: &[u8]) -> Result<Vec<Identity>> {
+  const HEADER_SIZE: usize = 24;
+  const IDENTITY_ITEM: u16 = 0x0C;
+
+  if reply.len() < HEADER_SIZE + 2 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "ListIdentity reply is too short"));
+  }
+
+  let item_count = LittleEndian::read_u16(&reply[24..26]);
+  let mut identities = vec![];
+  let mut pos = 26;
+  for _ in 0..item_count {
+    if reply.len() < pos + 4 {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "identity item header is truncated"));
+    }
+    let item_type = LittleEndian::read_u16(&reply[pos..pos + 2]);
+    let item_len: usize = LittleEndian::read_u16(&reply[pos + 2..pos + 4]).into();
+    pos += 4;
+    if reply.len() < pos + item_len {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "identity item is truncated"));
+    }
+    if item_type == IDENTITY_ITEM {
+      identities.push(parse_identity_item(&reply[pos..pos + item_len])?);
+    }
+    pos += item_len;
+  }
+
+  return Ok(identities);
+}
+
+pub(crate) fn parse_identity_item(item: &[u8]) -> Result<Identity> {
+  let too_short = || Error::new(ErrorKind::UnexpectedEof, "identity item is too short");
+
+  if item.len() < 33 {
+    return Err(too_short());
+  }
+  let name_len = usize::from(item[32]);
+  if item.len() < 33 + name_len + 1 {
+    return Err(too_short());
+  }
+
+  return Ok(Identity {
+    protocol_version: LittleEndian::read_u16(&item[0..2]),
+    socket_addr: eip::parse_sockaddr_info(&item[2..18])?,
+    vendor_id: LittleEndian::read_u16(&item[18..20]),
+    device_type: LittleEndian::read_u16(&item[20..22]),
+    product_code: LittleEndian::read_u16(&item[22..24]),
+    major_revision: item[24],
+    minor_revision: item[25],
+    status: LittleEndian::read_u16(&item[26..28]),
+    serial_number: LittleEndian::read_u32(&item[28..32]),
+    product_name: String::from_utf8_lossy(&item[33..33 + name_len]).into_owned(),
+    state: item[33 + name_len],
+  });
+}
+
+/*
+Find devices with a ListIdentity broadcast
+Sends from bind_addr to the broadcast address (usually 255.255.255.255:44818)
+and collects replies until the timeout runs out. Each device is listed once,
+in the order it answered.
+*/
+pub fn discover(bind_addr: IpAddr, broadcast: SocketAddr, timeout: Duration) -> Result<Vec<Identity>> {
+  let socket = UdpSocket::bind((bind_addr, 0))?;
+  socket.set_broadcast(true)?;
+  socket.send_to(&build_list_identity(rand::random()), broadcast)?;
+
+  let deadline = Instant::now() + timeout;
+  let mut identities: Vec<Identity> = vec![];
+  let mut buf = [0u8; 1024];
+  loop {
+    let now = Instant::now();
+    if now >= deadline {
+      break;
+    }
+    socket.set_read_timeout(Some(deadline - now))?;
+    let size = match socket.recv_from(&mut buf) {
+      Ok((size, _)) => size,
+      Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+      Err(e) => return Err(e),
+    };
+
+    // Anything that isn't a ListIdentity reply is someone else's traffic
+    for identity in parse_list_identity_reply(&buf[..size]).unwrap_or_default() {
+      if !identities.contains(&identity) {
+        identities.push(identity);
+      }
+    }
+  }
+
+  return Ok(identities);
+}
+
+#[test]
+fn test_discover() {
+  use std::net::Ipv4Addr;
+  use std::thread;
+
+  let device = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let broadcast = device.local_addr().unwrap();
+  let responder = thread::spawn(move || {
+    let mut buf = [0u8; 64];
+    let (size, from) = device.recv_from(&mut buf).unwrap();
+    let context = LittleEndian::read_u64(&buf[12..20]);
+    assert_eq!(size, 24);
+    // Answer twice, as a device on two networks would
+    let reply = build_list_identity_reply(context, &identity_for_test());
+    device.send_to(&reply, from).unwrap();
+    device.send_to(&reply, from).unwrap();
+    device.send_to(b"noise", from).unwrap();
+  });
+
+  let identities = discover(IpAddr::V4(Ipv4Addr::LOCALHOST), broadcast, Duration::from_millis(300)).unwrap();
+  responder.join().unwrap();
+  assert_eq!(identities, vec![identity_for_test()]);
+}
+
+/*
+Build a ListIdentity reply
+The Target answers with this, and the tests use it.
+*/
+pub fn build_list_identity_reply(sender_context: u64, identity: &Identity) -> Vec<u8> {
+  const EIP_COMMAND: u16 = 0x63;
+  const IDENTITY_ITEM: u16 = 0x0C;
+  const AF_INET: u16 = 2;
+
+  let mut item = Vec::<u8>::with_capacity(40 + identity.product_name.len());
+  item.write_u16::<LittleEndian>(identity.protocol_version).unwrap();
+  item.extend_from_slice(&AF_INET.to_be_bytes());
+  item.extend_from_slice(&identity.socket_addr.port().to_be_bytes());
+  item.extend_from_slice(&identity.socket_addr.ip().octets());
+  item.extend_from_slice(&[0; 8]);
+  item.write_u16::<LittleEndian>(identity.vendor_id).unwrap();
+  item.write_u16::<LittleEndian>(identity.device_type).unwrap();
+  item.write_u16::<LittleEndian>(identity.product_code).unwrap();
+  item.write_u8(identity.major_revision).unwrap();
+  item.write_u8(identity.minor_revision).unwrap();
+  item.write_u16::<LittleEndian>(identity.status).unwrap();
+  item.write_u32::<LittleEndian>(identity.serial_number).unwrap();
+  item.write_u8(identity.product_name.len() as u8).unwrap();
+  item.extend_from_slice(identity.product_name.as_bytes());
+  item.write_u8(identity.state).unwrap();
+
+  let mut data = Vec::<u8>::with_capacity(6 + item.len());
+  data.write_u16::<LittleEndian>(1).unwrap();
+  data.write_u16::<LittleEndian>(IDENTITY_ITEM).unwrap();
+  data.write_u16::<LittleEndian>(item.len() as u16).unwrap();
+  data.append(&mut item);
+
+  return eip::build_encapsulation(EIP_COMMAND, 0, 0, sender_context, &data);
+}
+
+#[cfg(test)]
+pub(crate) fn identity_for_test() -> Identity {
+  Identity {
+    protocol_version: 1,
+    socket_addr: SocketAddrV4::new(std::net::Ipv4Addr::new(192, 168, 1, 10), 44818),
+    vendor_id: 1,
+    device_type: 14,
+    product_code: 166,
+    major_revision: 32,
+    minor_revision: 11,
+    status: 0x3060,
+    serial_number: 0xC0FFEE,
+    product_name: String::from("1756-L83E/B"),
+    state: 3,
+  }
+}
+
+#[test]
+fn test_list_identity_round_trip() {
+  let identity = identity_for_test();
+  assert_eq!(
+    parse_list_identity_reply(&build_list_identity_reply(9, &identity)).unwrap(),
+    vec![identity]
+  );
+}
@@ -1,14 +1,61 @@
+#![allow(clippy::needless_return)]
+
 pub mod sockets;
-pub use sockets::EipAddr;
+pub use sockets::{EipAddr, ServiceConfig};
+
+mod route;
+pub use route::{CipRoute, RouteHop, LinkAddress};
 
 pub mod eip;
 
+mod params;
+pub use params::*;
+
+mod profile;
+pub use profile::PlcProfile;
+
+mod status;
+pub use status::*;
+
+pub mod identity;
+pub use identity::Identity;
+
+pub mod tag;
+pub use tag::{TagDialect, TagInfo, TagValue};
+
+pub mod pccc;
+pub use pccc::{PcccAddress, PcccFileType};
+
 mod service;
 pub use service::*;
 
+mod reconciler;
+pub use reconciler::{ConsumerHandle, ConsumerState};
+
 mod plc;
 pub(crate) use plc::*;
 
 mod consumer;
 pub(crate) use consumer::*;
 pub use consumer::{ConsumerHint, ConsumerQueue};
+
+mod producer;
+pub(crate) use producer::*;
+pub use producer::{ProducerHint, OutputBuffer};
+
+mod target;
+pub use target::{Faults, Target, TargetConfig};
+
+pub mod sim;
+
+pub mod capture;
+pub use capture::{CaptureFormat, CaptureReader, Recorder};
+
+pub mod dissect;
+pub use dissect::{dissect, Dissection};
+
+pub mod replay;
+pub use replay::{Replay, ReplayConnection, ReplaySpeed};
+
+pub mod daemon;
+pub use daemon::{Daemon, DaemonConfig};
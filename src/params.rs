@@ -0,0 +1,445 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::Identity;
+
+/*
+How a connection is delivered in one direction
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum ConnectionType {
+  Null,
+  Multicast,
+  PointToPoint,
+}
+
+/*
+Network priority of a connection in one direction
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum Priority {
+  Low,
+  High,
+  Scheduled,
+  Urgent,
+}
+
+/*
+Whether the connection size is exact or a maximum
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum SizeType {
+  Fixed,
+  Variable,
+}
+
+/*
+The connection timeout as a multiple of the RPI
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub enum TimeoutMultiplier {
+  X4,
+  X8,
+  X16,
+  X32,
+  X64,
+  X128,
+  X256,
+  X512,
+}
+impl TimeoutMultiplier {
+  pub fn factor(self) -> u32 {
+    4 << (self as u32)
+  }
+}
+
+/*
+How a consumer relates to the producer's other connections
+ExclusiveOwner opens its own connection. InputOnly and ListenOnly connect to an
+assembly's heartbeat connection points (198 and 199) the way Logix does, and only
+add a consumer to a production; ListenOnly also needs someone else to own a
+multicast connection already, or the target answers "Non-listen only connection
+not opened". Tag connections have no heartbeat points, so they're always
+ExclusiveOwner.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum ConnectionKind {
+  #[default]
+  ExclusiveOwner,
+  InputOnly,
+  ListenOnly,
+}
+impl ConnectionKind {
+
+  /*
+  The O->T connection point for this kind of connection, if it has one
+  */
+  pub fn heartbeat_point(self) -> Option<u8> {
+    match self {
+      ConnectionKind::ExclusiveOwner => None,
+      ConnectionKind::InputOnly => Some(198),
+      ConnectionKind::ListenOnly => Some(199),
+    }
+  }
+}
+
+/*
+An Assembly object application path, for adapters that aren't Logix controllers
+config is the configuration assembly instance, output the O->T connection point
+and input the T->O one. Input-only and listen-only connections swap output for
+their heartbeat point. config_data is sent as a data segment at the end of the
+path when it isn't empty.
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Default, Deserialize, Serialize)]
+pub struct AssemblyPath {
+  pub config: u16,
+  pub output: u16,
+  pub input: u16,
+  #[serde(default)]
+  pub config_data: Vec<u8>,
+}
+
+/*
+How strictly the target has to match the electronic key
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum KeyingMode {
+  #[default]
+  Disabled,
+  ExactMatch,
+  CompatibleMatch,
+}
+
+/*
+The electronic key sent in the connection path
+With keying disabled the key is all zeros, which is what rconpro has always
+sent. A rejected key comes back as a CipError with extended status 0x0114,
+0x0115 or 0x0116.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub struct Keying {
+  pub mode: KeyingMode,
+  pub vendor_id: u16,
+  pub device_type: u16,
+  pub product_code: u16,
+  pub major_revision: u8,
+  pub minor_revision: u8,
+}
+impl Keying {
+  pub fn disabled() -> Keying {
+    Keying::default()
+  }
+
+  /*
+  Key on the device that answered a ListIdentity
+  */
+  pub fn from_identity(mode: KeyingMode, identity: &Identity) -> Keying {
+    Keying {
+      mode,
+      vendor_id: identity.vendor_id,
+      device_type: identity.device_type,
+      product_code: identity.product_code,
+      major_revision: identity.major_revision,
+      minor_revision: identity.minor_revision,
+    }
+  }
+
+  /*
+  The vendor ID through minor revision fields of the key segment
+  The compatibility bit is the high bit of the major revision.
+  */
+  pub(crate) fn encode(&self) -> [u8; 8] {
+    if self.mode == KeyingMode::Disabled {
+      return [0; 8];
+    }
+
+    let compatibility = if self.mode == KeyingMode::CompatibleMatch { 0x80 } else { 0x00 };
+    let vendor_id = self.vendor_id.to_le_bytes();
+    let device_type = self.device_type.to_le_bytes();
+    let product_code = self.product_code.to_le_bytes();
+
+    [
+      vendor_id[0], vendor_id[1],
+      device_type[0], device_type[1],
+      product_code[0], product_code[1],
+      (self.major_revision & 0x7F) | compatibility,
+      self.minor_revision,
+    ]
+  }
+}
+
+/*
+The network connection parameters for one direction of a connection
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub struct NetworkParams {
+  pub connection_type: ConnectionType,
+  pub priority: Priority,
+  pub size_type: SizeType,
+}
+impl NetworkParams {
+
+  /*
+  Encode as the 16-bit word used by Forward Open
+  Sizes over 511 bytes don't fit; use encode_large for those.
+  */
+  pub(crate) fn encode(&self, redundant_owner: bool, size: u16) -> u16 {
+    assert!(size <= 0x1FF, "connection size doesn't fit a Forward Open");
+
+    (u16::from(redundant_owner) << 15)
+      | ((self.connection_type as u16) << 13)
+      | ((self.priority as u16) << 10)
+      | ((self.size_type as u16) << 9)
+      | size
+  }
+
+  /*
+  Encode as the 32-bit word used by Large Forward Open
+  */
+  pub(crate) fn encode_large(&self, redundant_owner: bool, size: u16) -> u32 {
+    (u32::from(redundant_owner) << 31)
+      | ((self.connection_type as u32) << 29)
+      | ((self.priority as u32) << 26)
+      | ((self.size_type as u32) << 25)
+      | u32::from(size)
+  }
+}
+impl Default for NetworkParams {
+  fn default() -> NetworkParams {
+    NetworkParams {
+      connection_type: ConnectionType::PointToPoint,
+      priority: Priority::Scheduled,
+      size_type: SizeType::Fixed,
+    }
+  }
+}
+
+/*
+What makes the target produce on a connection
+Cyclic connections produce every RPI. Change-of-state connections produce when
+the data changes, but at least once per RPI, so the RPI is also the longest a
+healthy connection stays quiet. Application-triggered connections produce when
+the application says so, with the same RPI fallback.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum TransportTrigger {
+  #[default]
+  Cyclic,
+  ChangeOfState,
+  Application,
+}
+impl TransportTrigger {
+
+  /*
+  The transport class and trigger byte of a class 1 Forward Open
+  */
+  pub(crate) fn encode(&self) -> u8 {
+    const DIRECTION_SERVER: u8 = 0x80;
+    const CLASS_1: u8 = 0x01;
+
+    let trigger = match self {
+      TransportTrigger::Cyclic => 0x00,
+      TransportTrigger::ChangeOfState => 0x10,
+      TransportTrigger::Application => 0x20,
+    };
+
+    DIRECTION_SERVER | trigger | CLASS_1
+  }
+
+  pub(crate) fn decode(byte: u8) -> Option<TransportTrigger> {
+    match byte & 0x70 {
+      0x00 => Some(TransportTrigger::Cyclic),
+      0x10 => Some(TransportTrigger::ChangeOfState),
+      0x20 => Some(TransportTrigger::Application),
+      _ => None,
+    }
+  }
+}
+
+/*
+Everything in a Forward Open besides the RPIs, sizes and connection path
+The defaults are what rconpro has always sent.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize)]
+pub struct ForwardOpenParams {
+  pub ot: NetworkParams,
+  pub to: NetworkParams,
+  pub redundant_owner: bool,
+  pub timeout_multiplier: TimeoutMultiplier,
+  pub priority_time_tick: u8,
+  pub timeout_ticks: u8,
+  pub vendor_id: u16,
+  pub originator_serial: u32,
+  #[serde(default)]
+  pub trigger: TransportTrigger,
+  // Shortest time between productions in milliseconds, sent as a network segment
+  #[serde(default)]
+  pub production_inhibit: Option<u8>,
+}
+impl ForwardOpenParams {
+  pub fn builder() -> ForwardOpenParamsBuilder {
+    ForwardOpenParamsBuilder {
+      params: ForwardOpenParams::default()
+    }
+  }
+
+  /*
+  How long a connection with this RPI (in microseconds) can go without a packet
+  before it's dead
+  Only the RPI counts, not the trigger: change-of-state and application
+  triggered connections still produce at least once per RPI.
+  */
+  pub fn watchdog_timeout(&self, rpi: usize) -> Duration {
+    Duration::from_micros(rpi as u64) * self.timeout_multiplier.factor()
+  }
+}
+impl Default for ForwardOpenParams {
+  fn default() -> ForwardOpenParams {
+    ForwardOpenParams {
+      ot: NetworkParams::default(),
+      to: NetworkParams::default(),
+      redundant_owner: false,
+      timeout_multiplier: TimeoutMultiplier::X4,
+      priority_time_tick: 0x0A,
+      timeout_ticks: 0x0E,
+      vendor_id: 0x01,
+      originator_serial: 42,
+      trigger: TransportTrigger::Cyclic,
+      production_inhibit: None,
+    }
+  }
+}
+
+/*
+Builder for ForwardOpenParams
+Anything that isn't set keeps its default.
+*/
+pub struct ForwardOpenParamsBuilder {
+  params: ForwardOpenParams,
+}
+impl ForwardOpenParamsBuilder {
+  pub fn ot_connection_type(mut self, connection_type: ConnectionType) -> Self {
+    self.params.ot.connection_type = connection_type;
+    self
+  }
+
+  pub fn to_connection_type(mut self, connection_type: ConnectionType) -> Self {
+    self.params.to.connection_type = connection_type;
+    self
+  }
+
+  pub fn ot_priority(mut self, priority: Priority) -> Self {
+    self.params.ot.priority = priority;
+    self
+  }
+
+  pub fn to_priority(mut self, priority: Priority) -> Self {
+    self.params.to.priority = priority;
+    self
+  }
+
+  pub fn ot_size_type(mut self, size_type: SizeType) -> Self {
+    self.params.ot.size_type = size_type;
+    self
+  }
+
+  pub fn to_size_type(mut self, size_type: SizeType) -> Self {
+    self.params.to.size_type = size_type;
+    self
+  }
+
+  pub fn redundant_owner(mut self, redundant_owner: bool) -> Self {
+    self.params.redundant_owner = redundant_owner;
+    self
+  }
+
+  pub fn timeout_multiplier(mut self, timeout_multiplier: TimeoutMultiplier) -> Self {
+    self.params.timeout_multiplier = timeout_multiplier;
+    self
+  }
+
+  pub fn priority_time_tick(mut self, priority_time_tick: u8) -> Self {
+    self.params.priority_time_tick = priority_time_tick;
+    self
+  }
+
+  pub fn timeout_ticks(mut self, timeout_ticks: u8) -> Self {
+    self.params.timeout_ticks = timeout_ticks;
+    self
+  }
+
+  pub fn vendor_id(mut self, vendor_id: u16) -> Self {
+    self.params.vendor_id = vendor_id;
+    self
+  }
+
+  pub fn originator_serial(mut self, originator_serial: u32) -> Self {
+    self.params.originator_serial = originator_serial;
+    self
+  }
+
+  pub fn trigger(mut self, trigger: TransportTrigger) -> Self {
+    self.params.trigger = trigger;
+    self
+  }
+
+  pub fn production_inhibit(mut self, milliseconds: u8) -> Self {
+    self.params.production_inhibit = Some(milliseconds);
+    self
+  }
+
+  pub fn build(self) -> ForwardOpenParams {
+    self.params
+  }
+}
+
+#[test]
+fn test_default_network_params() {
+  let params = ForwardOpenParams::default();
+  assert_eq!(params.ot.encode(false, 2), 0x4802);
+  assert_eq!(params.to.encode_large(false, 1200), 0x4800_04B0);
+}
+
+#[test]
+fn test_built_network_params() {
+  let params = ForwardOpenParams::builder()
+    .to_connection_type(ConnectionType::Multicast)
+    .to_priority(Priority::High)
+    .to_size_type(SizeType::Variable)
+    .redundant_owner(true)
+    .timeout_multiplier(TimeoutMultiplier::X16)
+    .build();
+
+  assert_eq!(params.to.encode(false, 6), 0x2606);
+  assert_eq!(params.to.encode_large(true, 6), 0xA600_0006);
+  assert_eq!(params.timeout_multiplier.factor(), 16);
+}
+
+#[test]
+fn test_keying_encode() {
+  let identity = crate::identity::identity_for_test();
+
+  assert_eq!(Keying::disabled().encode(), [0; 8]);
+  assert_eq!(
+    Keying::from_identity(KeyingMode::ExactMatch, &identity).encode(),
+    [1, 0, 14, 0, 166, 0, 32, 11]
+  );
+  assert_eq!(
+    Keying::from_identity(KeyingMode::CompatibleMatch, &identity).encode(),
+    [1, 0, 14, 0, 166, 0, 0x80 | 32, 11]
+  );
+}
+
+#[test]
+fn test_transport_trigger() {
+  assert_eq!(ForwardOpenParams::default().trigger.encode(), 0x81);
+  assert_eq!(TransportTrigger::ChangeOfState.encode(), 0x91);
+  assert_eq!(TransportTrigger::decode(0xA1), Some(TransportTrigger::Application));
+
+  let params = ForwardOpenParams::builder()
+    .trigger(TransportTrigger::ChangeOfState)
+    .production_inhibit(50)
+    .timeout_multiplier(TimeoutMultiplier::X8)
+    .build();
+  assert_eq!(params.watchdog_timeout(100_000), Duration::from_millis(800));
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+use std::io::{Result, Error, ErrorKind};
+use std::str::FromStr;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::{CipError, PcccError, TagValue};
+
+/*
+The data table file types we can read and write
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PcccFileType {
+  Integer,
+  Float,
+  Bit,
+  Timer,
+  Counter,
+  String,
+  Long,
+}
+impl PcccFileType {
+  fn from_prefix(prefix: &str) -> Option<PcccFileType> {
+    Some(match prefix {
+      "N" => PcccFileType::Integer,
+      "F" => PcccFileType::Float,
+      "B" => PcccFileType::Bit,
+      "T" => PcccFileType::Timer,
+      "C" => PcccFileType::Counter,
+      "ST" => PcccFileType::String,
+      "L" => PcccFileType::Long,
+      _ => return None,
+    })
+  }
+
+  pub fn prefix(&self) -> &'static str {
+    match self {
+      PcccFileType::Integer => "N",
+      PcccFileType::Float => "F",
+      PcccFileType::Bit => "B",
+      PcccFileType::Timer => "T",
+      PcccFileType::Counter => "C",
+      PcccFileType::String => "ST",
+      PcccFileType::Long => "L",
+    }
+  }
+
+  /*
+  The file type byte of the typed logical read/write commands
+  */
+  pub fn code(&self) -> u8 {
+    match self {
+      PcccFileType::Integer => 0x89,
+      PcccFileType::Float => 0x8A,
+      PcccFileType::Bit => 0x85,
+      PcccFileType::Timer => 0x86,
+      PcccFileType::Counter => 0x87,
+      PcccFileType::String => 0x8D,
+      PcccFileType::Long => 0x91,
+    }
+  }
+
+  /*
+  Size in bytes of the smallest thing an address can point to in this file
+  Timer and counter addresses always point at one word of the element.
+  */
+  fn word_size(&self) -> usize {
+    match self {
+      PcccFileType::Float | PcccFileType::Long => 4,
+      PcccFileType::String => 84,
+      _ => 2,
+    }
+  }
+}
+
+/*
+A data table address, like N7:10, F8:0, B3:1/4 or T4:0.ACC
+Bit files can also be addressed by bit number alone (B3/20 is B3:1/4).
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PcccAddress {
+  pub file_type: PcccFileType,
+  pub file_number: u16,
+  pub element: u16,
+  pub sub_element: u16,
+  pub bit: Option<u8>,
+}
+impl PcccAddress {
+  fn size(&self) -> usize {
+    self.file_type.word_size()
+  }
+}
+impl FromStr for PcccAddress {
+  type Err = Error;
+
+  fn from_str(address: &str) -> std::result::Result<PcccAddress, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("{:?} is not a PCCC address", address));
+    let number = |digits: &str| digits.parse::<u16>().map_err(|_| invalid());
+
+    let upper = address.trim().to_ascii_uppercase();
+    let prefix_len = upper.find(|c: char| c.is_ascii_digit()).ok_or_else(invalid)?;
+    let file_type = PcccFileType::from_prefix(&upper[..prefix_len]).ok_or_else(invalid)?;
+    let rest = &upper[prefix_len..];
+
+    // B3/20: a bit number counted from the start of the file
+    if !rest.contains(':') {
+      let (file, bit) = rest.split_once('/').ok_or_else(invalid)?;
+      if file_type != PcccFileType::Bit {
+        return Err(invalid());
+      }
+      let bit = number(bit)?;
+      return Ok(PcccAddress {
+        file_type,
+        file_number: number(file)?,
+        element: bit / 16,
+        sub_element: 0,
+        bit: Some((bit % 16) as u8),
+      });
+    }
+
+    let (file, rest) = rest.split_once(':').ok_or_else(invalid)?;
+    let (element, sub_element, bit) = if let Some((element, bit)) = rest.split_once('/') {
+      (element, 0, Some(number(bit)?))
+    } else if let Some((element, member)) = rest.split_once('.') {
+      // Timer and counter members: the control word bits, then PRE and ACC
+      let (sub_element, bit) = match (file_type, member) {
+        (PcccFileType::Timer, "EN") | (PcccFileType::Counter, "CU") => (0, Some(15)),
+        (PcccFileType::Timer, "TT") | (PcccFileType::Counter, "CD") => (0, Some(14)),
+        (PcccFileType::Timer, "DN") | (PcccFileType::Counter, "DN") => (0, Some(13)),
+        (PcccFileType::Counter, "OV") => (0, Some(12)),
+        (PcccFileType::Counter, "UN") => (0, Some(11)),
+        (PcccFileType::Timer, "PRE") | (PcccFileType::Counter, "PRE") => (1, None),
+        (PcccFileType::Timer, "ACC") | (PcccFileType::Counter, "ACC") => (2, None),
+        _ => return Err(invalid()),
+      };
+      (element, sub_element, bit)
+    } else {
+      if file_type == PcccFileType::Timer || file_type == PcccFileType::Counter {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{:?} needs a member like .ACC or .DN", address)));
+      }
+      (rest, 0, None)
+    };
+
+    let max_bit = if file_type == PcccFileType::Long { 31 } else { 15 };
+    let bit = match bit {
+      Some(bit) if bit > max_bit || file_type == PcccFileType::Float || file_type == PcccFileType::String => {
+        return Err(invalid());
+      }
+      bit => bit.map(|bit| bit as u8),
+    };
+
+    Ok(PcccAddress {
+      file_type,
+      file_number: number(file)?,
+      element: number(element)?,
+      sub_element,
+      bit,
+    })
+  }
+}
+impl fmt::Display for PcccAddress {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}{}:{}", self.file_type.prefix(), self.file_number, self.element)?;
+
+    let timer = self.file_type == PcccFileType::Timer;
+    match (self.file_type, self.sub_element, self.bit) {
+      (PcccFileType::Timer, 0, Some(bit)) | (PcccFileType::Counter, 0, Some(bit)) => {
+        let member = match bit {
+          15 => if timer { "EN" } else { "CU" },
+          14 => if timer { "TT" } else { "CD" },
+          13 => "DN",
+          12 => "OV",
+          _ => "UN",
+        };
+        write!(f, ".{}", member)
+      }
+      (_, 1, _) => write!(f, ".PRE"),
+      (_, 2, _) => write!(f, ".ACC"),
+      (_, _, Some(bit)) => write!(f, "/{}", bit),
+      _ => Ok(()),
+    }
+  }
+}
+
+/*
+Requestor ID sent with every Execute PCCC
+Matches the vendor ID and serial number ForwardOpenParams defaults to.
+*/
+const REQUESTOR_VENDOR_ID: u16 = 1;
+const REQUESTOR_SERIAL: u32 = 42;
+
+/* Execute PCCC (to the PCCC object) */
+fn build_execute_pccc(tns: u16, function: u8, body: &[u8]) -> Vec<u8> {
+  const CIP_SERVICE: u8 = 0x4B;
+  const CIP_PATH_SIZE: u8 = 0x02;
+  const CIP_CLASS_TYPE: u8 = 0x20;
+  const CIP_CLASS: u8 = 0x67;
+  const CIP_INSTANCE_TYPE: u8 = 0x24;
+  const CIP_INSTANCE: u8 = 0x01;
+  const REQUESTOR_ID_SIZE: u8 = 7;
+  const PCCC_COMMAND: u8 = 0x0F;
+  const PCCC_STATUS: u8 = 0x00;
+
+  let mut request = Vec::<u8>::with_capacity(18 + body.len());
+  request.write_u8(CIP_SERVICE).unwrap();
+  request.write_u8(CIP_PATH_SIZE).unwrap();
+  request.write_u8(CIP_CLASS_TYPE).unwrap();
+  request.write_u8(CIP_CLASS).unwrap();
+  request.write_u8(CIP_INSTANCE_TYPE).unwrap();
+  request.write_u8(CIP_INSTANCE).unwrap();
+  request.write_u8(REQUESTOR_ID_SIZE).unwrap();
+  request.write_u16::<LittleEndian>(REQUESTOR_VENDOR_ID).unwrap();
+  request.write_u32::<LittleEndian>(REQUESTOR_SERIAL).unwrap();
+  request.write_u8(PCCC_COMMAND).unwrap();
+  request.write_u8(PCCC_STATUS).unwrap();
+  request.write_u16::<LittleEndian>(tns).unwrap();
+  request.write_u8(function).unwrap();
+  request.extend_from_slice(body);
+
+  return request;
+}
+
+/*
+Byte size and the three address fields of the typed logical commands
+Numbers of 255 and up are written as 0xFF and a u16.
+*/
+fn build_logical_address(address: &PcccAddress, size: usize) -> Vec<u8> {
+  let mut body = vec![size as u8];
+  write_address_field(&mut body, address.file_number);
+  body.write_u8(address.file_type.code()).unwrap();
+  write_address_field(&mut body, address.element);
+  write_address_field(&mut body, address.sub_element);
+
+  body
+}
+
+fn write_address_field(body: &mut Vec<u8>, value: u16) {
+  if value < 0xFF {
+    body.write_u8(value as u8).unwrap();
+  } else {
+    body.write_u8(0xFF).unwrap();
+    body.write_u16::<LittleEndian>(value).unwrap();
+  }
+}
+
+/* Protected Typed Logical Read with three address fields */
+pub fn build_pccc_read(address: &PcccAddress, tns: u16) -> Vec<u8> {
+  const PCCC_FUNCTION: u8 = 0xA2;
+
+  build_execute_pccc(tns, PCCC_FUNCTION, &build_logical_address(address, address.size()))
+}
+
+#[test]
+fn test_build_pccc_read() {
+  let address: PcccAddress = "N7:10".parse().unwrap();
+  assert_eq!(
+    build_pccc_read(&address, 0x1234),
+    vec![0x4B, 0x02, 0x20, 0x67, 0x24, 0x01, 7, 1, 0, 42, 0, 0, 0,
+         0x0F, 0, 0x34, 0x12, 0xA2, 2, 7, 0x89, 10, 0]
+  );
+}
+
+/*
+Protected Typed Logical Write with three address fields
+Bit addresses use the masked write, so the other bits of the word are left alone.
+*/
+pub fn build_pccc_write(address: &PcccAddress, value: &TagValue, tns: u16) -> Result<Vec<u8>> {
+  const PCCC_WRITE: u8 = 0xAA;
+  const PCCC_MASKED_WRITE: u8 = 0xAB;
+
+  let wrong_type = || Error::new(
+    ErrorKind::InvalidInput,
+    format!("{:?} can't be written to {}", value, address)
+  );
+
+  if let Some(bit) = address.bit {
+    let set = match value {
+      TagValue::Bool(set) => *set,
+      _ => return Err(wrong_type()),
+    };
+
+    let size = address.size();
+    let mut body = build_logical_address(address, size);
+    let mask = 1u32 << bit;
+    let data = if set { mask } else { 0 };
+    body.extend_from_slice(&mask.to_le_bytes()[..size]);
+    body.extend_from_slice(&data.to_le_bytes()[..size]);
+    return Ok(build_execute_pccc(tns, PCCC_MASKED_WRITE, &body));
+  }
+
+  let data = match (address.file_type, value) {
+    (PcccFileType::Float, TagValue::Real(value)) => value.to_le_bytes().to_vec(),
+    (PcccFileType::Long, TagValue::Dint(value)) => value.to_le_bytes().to_vec(),
+    (PcccFileType::String, TagValue::String(value)) => encode_string(value)?,
+    (PcccFileType::Integer, TagValue::Int(value)) |
+    (PcccFileType::Bit, TagValue::Int(value)) |
+    (PcccFileType::Timer, TagValue::Int(value)) |
+    (PcccFileType::Counter, TagValue::Int(value)) => value.to_le_bytes().to_vec(),
+    _ => return Err(wrong_type()),
+  };
+
+  let mut body = build_logical_address(address, data.len());
+  body.extend_from_slice(&data);
+  Ok(build_execute_pccc(tns, PCCC_WRITE, &body))
+}
+
+/*
+ST elements are a length word and 82 characters, stored with each pair of
+characters swapped
+*/
+const STRING_SIZE: usize = 82;
+
+fn encode_string(value: &str) -> Result<Vec<u8>> {
+  if value.len() > STRING_SIZE {
+    return Err(Error::new(ErrorKind::InvalidInput, "string is longer than an ST element"));
+  }
+
+  let mut chars = value.as_bytes().to_vec();
+  chars.resize(STRING_SIZE, 0);
+  for pair in chars.chunks_exact_mut(2) {
+    pair.swap(0, 1);
+  }
+
+  let mut data = (value.len() as u16).to_le_bytes().to_vec();
+  data.append(&mut chars);
+  Ok(data)
+}
+
+fn decode_string(data: &[u8]) -> String {
+  let len = usize::from(LittleEndian::read_u16(&data[0..2])).min(STRING_SIZE);
+  let mut chars = data[2..2 + STRING_SIZE].to_vec();
+  for pair in chars.chunks_exact_mut(2) {
+    pair.swap(0, 1);
+  }
+
+  String::from_utf8_lossy(&chars[..len]).into_owned()
+}
+
+/*
+Unwrap the PCCC reply out of an Execute PCCC reply
+Checks the CIP status, the transaction number and the PCCC status, and returns
+the reply data.
+*/
+fn parse_execute_pccc_reply(cip: &[u8], tns: u16) -> Result<&[u8]> {
+  const PCCC_EXTENDED_STATUS: u8 = 0xF0;
+
+  if let Some(error) = CipError::from_reply(cip) {
+    return Err(error.into());
+  }
+
+  // CIP header, then the requestor ID (its first byte is its own size)
+  if cip.len() < 5 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Execute PCCC reply is too short"));
+  }
+  let pccc = match cip.get(4 + usize::from(cip[4]).max(1)..) {
+    Some(pccc) if pccc.len() >= 4 => pccc,
+    _ => return Err(Error::new(ErrorKind::UnexpectedEof, "PCCC reply is too short")),
+  };
+
+  if LittleEndian::read_u16(&pccc[2..4]) != tns {
+    return Err(Error::new(ErrorKind::InvalidData, "PCCC reply is for a different transaction"));
+  }
+
+  let status = pccc[1];
+  if status == PCCC_EXTENDED_STATUS {
+    return Err(PcccError::new(status, pccc.get(4).copied()).into());
+  } else if status != 0 {
+    return Err(PcccError::new(status, None).into());
+  }
+
+  Ok(&pccc[4..])
+}
+
+/*
+Parse the reply to build_pccc_read into a TagValue
+*/
+pub fn parse_pccc_read_reply(address: &PcccAddress, cip: &[u8], tns: u16) -> Result<TagValue> {
+  let data = parse_execute_pccc_reply(cip, tns)?;
+  if data.len() < address.size() {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "PCCC reply is shorter than the element"));
+  }
+
+  if let Some(bit) = address.bit {
+    let word = if address.file_type == PcccFileType::Long {
+      LittleEndian::read_u32(data)
+    } else {
+      u32::from(LittleEndian::read_u16(data))
+    };
+    return Ok(TagValue::Bool(word & (1 << bit) != 0));
+  }
+
+  Ok(match address.file_type {
+    PcccFileType::Float => TagValue::Real(LittleEndian::read_f32(data)),
+    PcccFileType::Long => TagValue::Dint(LittleEndian::read_i32(data)),
+    PcccFileType::String => TagValue::String(decode_string(data)),
+    _ => TagValue::Int(LittleEndian::read_i16(data)),
+  })
+}
+
+/*
+Check the reply to build_pccc_write
+*/
+pub fn parse_pccc_write_reply(cip: &[u8], tns: u16) -> Result<()> {
+  parse_execute_pccc_reply(cip, tns).map(|_| ())
+}
+
+#[cfg(test)]
+fn pccc_reply_for_test(tns: u16, status: u8, data: &[u8]) -> Vec<u8> {
+  let mut reply = vec![0xCB, 0, 0, 0, 7, 1, 0, 42, 0, 0, 0, 0x4F, status];
+  reply.extend_from_slice(&tns.to_le_bytes());
+  reply.extend_from_slice(data);
+  reply
+}
+
+#[test]
+fn test_parse_pccc_address() {
+  let address: PcccAddress = "B3:1/4".parse().unwrap();
+  assert_eq!(address, PcccAddress {
+    file_type: PcccFileType::Bit,
+    file_number: 3,
+    element: 1,
+    sub_element: 0,
+    bit: Some(4),
+  });
+  assert_eq!("B3/20".parse::<PcccAddress>().unwrap(), address);
+  assert_eq!(address.to_string(), "B3:1/4");
+
+  let address: PcccAddress = "t4:0.acc".parse().unwrap();
+  assert_eq!((address.file_type, address.sub_element), (PcccFileType::Timer, 2));
+  assert_eq!(address.to_string(), "T4:0.ACC");
+  assert_eq!("C5:2.DN".parse::<PcccAddress>().unwrap().bit, Some(13));
+  assert_eq!("ST9:0".parse::<PcccAddress>().unwrap().file_type, PcccFileType::String);
+
+  assert!("Program:Main.Tag".parse::<PcccAddress>().is_err());
+  assert!("N7".parse::<PcccAddress>().is_err());
+  assert!("T4:0".parse::<PcccAddress>().is_err());
+  assert!("F8:0/1".parse::<PcccAddress>().is_err());
+}
+
+#[test]
+fn test_pccc_read_reply() {
+  let address: PcccAddress = "F8:0".parse().unwrap();
+  let reply = pccc_reply_for_test(5, 0, &1.5f32.to_le_bytes());
+  assert_eq!(parse_pccc_read_reply(&address, &reply, 5).unwrap(), TagValue::Real(1.5));
+  assert!(parse_pccc_read_reply(&address, &reply, 6).is_err());
+
+  let address: PcccAddress = "B3:1/4".parse().unwrap();
+  let reply = pccc_reply_for_test(5, 0, &[0x10, 0]);
+  assert_eq!(parse_pccc_read_reply(&address, &reply, 5).unwrap(), TagValue::Bool(true));
+
+  let reply = pccc_reply_for_test(5, 0xF0, &[0x06]);
+  let error = parse_pccc_read_reply(&address, &reply, 5).unwrap_err();
+  assert_eq!(PcccError::from_io(&error), Some(&PcccError::new(0xF0, Some(0x06))));
+
+  // The requestor ID's size points past the end
+  let error = parse_pccc_read_reply(&address, &[0xCB, 0, 0, 0, 200, 1, 0], 5).unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_pccc_write() {
+  let address: PcccAddress = "B3:1/4".parse().unwrap();
+  let request = build_pccc_write(&address, &TagValue::Bool(true), 1).unwrap();
+  assert_eq!(&request[17..], &[0xAB, 2, 3, 0x85, 1, 0, 0x10, 0, 0x10, 0]);
+  assert!(build_pccc_write(&address, &TagValue::Int(1), 1).is_err());
+
+  let address: PcccAddress = "ST9:0".parse().unwrap();
+  let request = build_pccc_write(&address, &TagValue::String(String::from("abc")), 1).unwrap();
+  assert_eq!(&request[17..26], &[0xAA, 84, 9, 0x8D, 0, 0, 3, 0, b'b']);
+
+  let reply = pccc_reply_for_test(1, 0, &request[23..]);
+  assert_eq!(parse_pccc_read_reply(&address, &reply, 1).unwrap(), TagValue::String(String::from("abc")));
+}
@@ -1,19 +1,26 @@
-use std::io::{Result, Cursor};
+use std::io::{Result, Error, ErrorKind};
 use std::collections::HashMap;
 use std::sync::Arc;
-use byteorder::{ReadBytesExt, LittleEndian};
+use std::net::{IpAddr, Ipv4Addr};
+use byteorder::{ByteOrder, LittleEndian};
 
-use crate::sockets::{EipAddr, SetupStream};
-use crate::eip::build_register_session;
-use crate::{Consumer, ConsumerHint, ConsumerQueue};
+use crate::sockets::{EipAddr, ServiceConfig, SetupStream};
+use crate::eip::{self, build_register_session, ForwardOpenSpec, ForwardOpenReply, ConnectionTriple};
+use crate::identity::{self, Identity};
+use crate::capture::RecorderHandle;
+use crate::{CipError, CipRoute, PlcProfile, Consumer, ConsumerHint, ConsumerQueue, Producer, ProducerHint, OutputBuffer};
 
 /*
 The struct representing PLCs
-All consumers are owned by a Plc struct
+All consumers and producers are owned by a Plc struct, keyed by their O->T connection ID. That
+one is unique per connection, while several multicast consumers can share a
+T->O connection ID.
 */
 pub(crate) struct Plc {
   pub(crate) addr: EipAddr,
+  pub(crate) profile: PlcProfile,
   pub(crate) consumers: HashMap<u32, Consumer>,
+  pub(crate) producers: HashMap<u32, Producer>,
   pub(crate) setup_stream: SetupStream,
   pub(crate) session_handle: u32
 }
@@ -22,53 +29,185 @@ impl Plc {
   /*
   Start socket and init Plc
   */
-  pub(crate) fn new(addr: EipAddr) -> std::io::Result<Plc> {
+  pub(crate) fn new(addr: EipAddr, profile: PlcProfile) -> std::io::Result<Plc> {
+    profile.check_addr(&addr)?;
+
     Ok(Plc {
-      addr: addr,
+      addr,
+      profile,
       consumers: HashMap::new(),
+      producers: HashMap::new(),
       setup_stream: SetupStream::new(),
       session_handle: 0
     })
   }
 
-  pub(crate) fn connect(&mut self) -> std::io::Result<()> {
-    self.setup_stream.connect(&self.addr)?;
+  pub(crate) fn connect(&mut self, config: &ServiceConfig, recorder: &RecorderHandle) -> std::io::Result<()> {
+    self.setup_stream.connect(&self.addr, config, recorder)?;
     
     Ok(())
   }
 
+  /*
+  False once the PLC has dropped the session, after which nothing sent on it
+  gets an answer
+  */
+  pub(crate) fn is_connected(&self) -> bool {
+    self.setup_stream.is_connected()
+  }
+
+  /*
+  Stop every consumer's and producer's thread, for a Plc being thrown away
+  */
+  pub(crate) fn stop_connections(&mut self) {
+    for consumer in self.consumers.values_mut() {
+      consumer.stop();
+    }
+    for producer in self.producers.values_mut() {
+      producer.stop();
+    }
+  }
+
   /*
   Register a connection with the actual PLC
   */
   pub(crate) fn register(&mut self) -> Result<()> {
     let reg_response = self.setup_stream.send_recieve(
-      &build_register_session().as_slice()
+      build_register_session().as_slice()
     )?;
 
-    if reg_response.len() > 0 {
-      let mut cursor = Cursor::new(reg_response);
-      cursor.set_position(4);
-      self.session_handle = cursor.read_u32::<LittleEndian>().unwrap();
-    } else {
-      // TODO: throw error...
+    let status = LittleEndian::read_u32(&reg_response[8..12]);
+    if status != 0 {
+      return Err(Error::new(ErrorKind::ConnectionRefused, format!("RegisterSession failed with status {:#x}", status)));
     }
+    self.session_handle = LittleEndian::read_u32(&reg_response[4..8]);
 
     Ok(())
   }
+
+  /*
+  Ask the PLC who it is
+  The result can be used to fill in a Keying.
+  */
+  pub(crate) fn list_identity(&self) -> Result<Identity> {
+    let msg = identity::build_list_identity(self.setup_stream.next_context());
+    let reply = self.setup_stream.send_recieve(msg.as_slice())?;
+
+    identity::parse_list_identity_reply(&reply)?
+      .into_iter()
+      .next()
+      .ok_or_else(|| Error::new(ErrorKind::InvalidData, "ListIdentity reply has no identity"))
+  }
+
+  /*
+  Send an explicit CIP request and return the CIP reply
+  Requests for anything past the endpoint itself are wrapped in an Unconnected
+  Send along the route, and the device's reply is unwrapped again.
+  This only needs a shared reference, so requests from several threads can be
+  in flight on the session at the same time.
+  */
+  pub(crate) fn send_explicit(&self, cip_request: &[u8], route: &CipRoute) -> Result<Vec<u8>> {
+    self.profile.check_route(route)?;
+
+    let wrapped;
+    let cip_request = if route.is_local() {
+      cip_request
+    } else {
+      wrapped = eip::build_unconnected_send(route, cip_request);
+      wrapped.as_slice()
+    };
+
+    let msg = eip::build_send_rr_data_packet(
+      self.session_handle,
+      self.setup_stream.next_context(),
+      cip_request
+    );
+    let reply = self.setup_stream.send_recieve(msg.as_slice())?;
+    let cip_reply = eip::parse_send_rr_data_reply(&reply)?;
+
+    if route.is_local() {
+      Ok(cip_reply.to_vec())
+    } else {
+      eip::parse_unconnected_send_reply(cip_reply).map(|cip_reply| cip_reply.to_vec())
+    }
+  }
   
+  /*
+  Send a (Large) Forward Open and parse the reply
+  The spec is checked against the PLC's profile first, so a connection the
+  device can't take fails here instead of with a Connection Manager error.
+  */
+  pub(crate) fn forward_open(&self, spec: &ForwardOpenSpec) -> Result<ForwardOpenReply> {
+    self.profile.check_forward_open(spec)?;
+
+    let msg = eip::build_forward_open_packet(
+      &self.profile.route(&self.addr),
+      self.session_handle,
+      self.setup_stream.next_context(),
+      spec
+    )?;
+    let response = self.setup_stream.send_recieve(msg.as_slice())?;
+
+    eip::parse_forward_open_reply(&response)
+  }
+
+  /*
+  Close a connection opened with forward_open
+  */
+  pub(crate) fn forward_close(&self, triple: &ConnectionTriple, route: Option<&CipRoute>) -> Result<()> {
+    let route = route.cloned().unwrap_or_else(|| self.profile.route(&self.addr));
+    let msg = eip::build_forward_close_packet(
+      &route,
+      self.session_handle,
+      self.setup_stream.next_context(),
+      triple
+    );
+    let response = self.setup_stream.send_recieve(msg.as_slice())?;
+
+    let cip = eip::parse_send_rr_data_reply(&response)?;
+    match CipError::from_reply(cip) {
+      Some(error) => Err(error.into()),
+      None => Ok(())
+    }
+  }
+
   /*
   Start a consumer and add it to the hashmap
   */
-  pub(crate) fn add_consumer(&mut self, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> (&Consumer, u32) {
+  pub(crate) fn add_consumer(&mut self, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> Result<(&Consumer, u32)> {
     let mut con = Consumer::new(hint, queue);
-    let to_connection_id = con.send_forward_open(&mut self.setup_stream, self.session_handle, self.addr.slot)
-      .unwrap();
+    let ot_connection_id = con.send_forward_open(self)?;
 
     self.consumers.insert(
-      to_connection_id,
+      ot_connection_id,
       con
     );
 
-    (&self.consumers[&to_connection_id], to_connection_id)
+    Ok((&self.consumers[&ot_connection_id], ot_connection_id))
+  }
+
+  /*
+  Start a producer and add it to the hashmap
+  */
+  pub(crate) fn add_producer(&mut self, hint: ProducerHint, buffer: &Arc<OutputBuffer>, inputs: Option<&Arc<ConsumerQueue>>) -> Result<(&Producer, u32)> {
+    let mut producer = Producer::new(hint, buffer, inputs);
+    let ot_connection_id = producer.send_forward_open(self)?;
+
+    self.producers.insert(
+      ot_connection_id,
+      producer
+    );
+
+    Ok((&self.producers[&ot_connection_id], ot_connection_id))
+  }
+
+  /*
+  The interface multicast groups from this PLC are joined on
+  */
+  pub(crate) fn interface(&self) -> Result<Ipv4Addr> {
+    match self.setup_stream.local_addr()?.ip() {
+      IpAddr::V4(ip) => Ok(ip),
+      IpAddr::V6(_) => Err(Error::new(ErrorKind::Unsupported, "multicast is only supported over IPv4")),
+    }
   }
 }
\ No newline at end of file
@@ -0,0 +1,279 @@
+use std::io::{Result, Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::convert::TryInto;
+
+use crate::eip::{self, ForwardOpenSpec, ConnectionTriple};
+use crate::sockets::{EipAddr, CPSocket};
+use crate::{AssemblyPath, CipRoute, ConsumerQueue, ForwardOpenParams, Keying, ConnectionKind, Plc};
+
+/*
+A struct specifying producer parameters
+data_size is the size of the application data, without the sequence count or
+run/idle header. rpi is the O->T RPI we send at, and torpi the T->O RPI.
+input_size is the size of the data the target sends back, also without the
+sequence count; it's 0 when the T->O side is only a heartbeat, and otherwise
+the connection is an adapter's outputs and inputs at once (see
+Service::add_io_connection).
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ProducerHint {
+  pub tag: String,
+  pub data_size: usize,
+  pub input_size: usize,
+  pub rpi: usize,
+  pub torpi: usize,
+  pub run_idle: bool,
+  pub params: ForwardOpenParams,
+  pub assembly: Option<AssemblyPath>,
+  pub route: Option<CipRoute>,
+  pub keying: Keying,
+}
+impl ProducerHint {
+  pub(crate) fn forward_open_spec(&self) -> ForwardOpenSpec<'_> {
+    // Sequence count, then the optional run/idle header
+    const SEQUENCE_COUNT_SIZE: usize = 2;
+    const RUN_IDLE_SIZE: usize = 4;
+
+    let run_idle_size = if self.run_idle { RUN_IDLE_SIZE } else { 0 };
+
+    ForwardOpenSpec {
+      tag: &self.tag,
+      assembly: self.assembly.as_ref(),
+      route: self.route.as_ref(),
+      keying: self.keying,
+      kind: ConnectionKind::ExclusiveOwner,
+      params: &self.params,
+      ot_rpi: self.rpi,
+      ot_size: SEQUENCE_COUNT_SIZE + run_idle_size + self.data_size,
+      to_rpi: self.torpi,
+      to_size: SEQUENCE_COUNT_SIZE + self.input_size,
+    }
+  }
+}
+
+/*
+The data a producer sends
+The buffer is always swapped as a whole, so the producer thread never sends a
+half-written update. version goes up whenever the contents actually change.
+*/
+pub struct OutputBuffer {
+  state: Mutex<OutputState>,
+}
+struct OutputState {
+  data: Vec<u8>,
+  run: bool,
+  version: u64,
+}
+impl OutputBuffer {
+  pub fn new(data_size: usize) -> OutputBuffer {
+    OutputBuffer {
+      state: Mutex::new(OutputState {
+        data: vec![0; data_size],
+        run: true,
+        version: 0,
+      })
+    }
+  }
+
+  /*
+  Replace the whole buffer
+  The new data has to be the same size as the buffer.
+  */
+  pub fn set(&self, data: &[u8]) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    if data.len() != state.data.len() {
+      return Err(Error::new(ErrorKind::InvalidInput, "data doesn't match the size of the output buffer"));
+    }
+
+    if state.data != data {
+      state.data.copy_from_slice(data);
+      state.version += 1;
+    }
+
+    Ok(())
+  }
+
+  /*
+  Change the buffer in place
+  Everything done in f is seen by the producer as one update.
+  */
+  pub fn update<F: FnOnce(&mut [u8])>(&self, f: F) {
+    let mut state = self.state.lock().unwrap();
+    let before = state.data.clone();
+    f(&mut state.data);
+
+    if state.data != before {
+      state.version += 1;
+    }
+  }
+
+  /*
+  Set the run/idle flag (only sent if the producer uses a run/idle header)
+  */
+  pub fn set_run(&self, run: bool) {
+    let mut state = self.state.lock().unwrap();
+    if state.run != run {
+      state.run = run;
+      state.version += 1;
+    }
+  }
+
+  pub fn get(&self) -> Vec<u8> {
+    self.state.lock().unwrap().data.clone()
+  }
+
+  pub(crate) fn snapshot(&self) -> (Vec<u8>, bool, u64) {
+    let state = self.state.lock().unwrap();
+    (state.data.clone(), state.run, state.version)
+  }
+}
+
+/*
+The producer struct
+Responsible for a single exclusive-owner connection that sends data to the PLC,
+and takes whatever the PLC sends back if there's an input queue
+*/
+pub(crate) struct Producer {
+  hint: Arc<ProducerHint>,
+  pub(crate) buffer: Arc<OutputBuffer>,
+  pub(crate) inputs: Option<Arc<ConsumerQueue>>,
+
+  pub(crate) ot_connection_id: u32,
+  pub(crate) to_connection_id: u32,
+  pub(crate) triple: Option<ConnectionTriple>,
+
+  alive: Arc<AtomicBool>,
+}
+impl Producer {
+
+  /*
+  Initiate a producer
+  */
+  pub(crate) fn new(hint: ProducerHint, buffer: &Arc<OutputBuffer>, inputs: Option<&Arc<ConsumerQueue>>) -> Producer {
+    Producer {
+      hint: Arc::new(hint),
+      buffer: buffer.clone(),
+      inputs: inputs.cloned(),
+      ot_connection_id: 0,
+      to_connection_id: 0,
+      triple: None,
+      alive: Arc::new(AtomicBool::new(true))
+    }
+  }
+
+  /*
+  Send a forward open to the PLC
+  This effectively starts the producer connection
+  */
+  pub(crate) fn send_forward_open(&mut self, plc: &Plc) -> Result<u32> {
+    if self.buffer.get().len() != self.hint.data_size {
+      return Err(Error::new(ErrorKind::InvalidInput, "output buffer doesn't match the producer's data_size"));
+    }
+    if self.inputs.is_none() && self.hint.input_size != 0 {
+      return Err(Error::new(ErrorKind::InvalidInput, "a producer with an input_size needs an input queue"));
+    }
+
+    let reply = plc.forward_open(&self.hint.forward_open_spec())?;
+    self.ot_connection_id = reply.ot_connection_id;
+    self.to_connection_id = reply.to_connection_id;
+    self.triple = Some(reply.triple());
+
+    Ok(self.ot_connection_id)
+  }
+
+  /*
+  Start the output thread
+  Sends the buffer every RPI; the CIP sequence count only goes up when the
+  buffer has changed since the last packet.
+  */
+  pub(crate) fn start_output_thread(&self, cpsocket: &Arc<Mutex<CPSocket>>, plc_addr: EipAddr, sequence_count: &Arc<AtomicU32>) {
+    // Get locks for the thread
+    let alive = self.alive.clone();
+    let cpsocket_lock = Arc::clone(cpsocket);
+    let sequence_count_lock = Arc::clone(sequence_count);
+    let buffer = Arc::clone(&self.buffer);
+
+    let hint = Arc::clone(&self.hint);
+    let ot_connection_id = self.ot_connection_id;
+
+    // Calculate the requested delay between packets
+    let duration = Duration::new(
+      (hint.rpi / 1_000_000).try_into().unwrap(),
+      ((hint.rpi % 1_000_000) * 1_000).try_into().unwrap()
+    );
+
+    thread::Builder::new().name(format!("Output thread for {}", hint.tag)).spawn(move || {
+      let mut cip_sequence_count: u16 = 0;
+      let mut sent_version = None;
+
+      while alive.load(Ordering::Relaxed) {
+
+        // Sleep
+        thread::sleep(duration);
+
+        // Only new data gets a new CIP sequence count
+        let (data, run, version) = buffer.snapshot();
+        if sent_version != Some(version) {
+          cip_sequence_count = cip_sequence_count.wrapping_add(1);
+          sent_version = Some(version);
+        }
+
+        let msg = eip::build_output_packet(
+          ot_connection_id,
+          sequence_count_lock.fetch_add(1, Ordering::SeqCst),
+          cip_sequence_count,
+          if hint.run_idle { Some(run) } else { None },
+          &data
+        );
+
+        // Aquire socket lock and send the packet
+        let cpsocket = cpsocket_lock.lock().unwrap();
+        if let Err(e) = cpsocket.send_to(msg.as_slice(), &plc_addr) {
+          eprintln!("Couldn't send output for {}: {}", hint.tag, e);
+        }
+      }
+    }).unwrap();
+  }
+
+  /*
+  The route this connection was opened over, if it wasn't the PLC's own slot
+  */
+  pub(crate) fn route(&self) -> Option<&CipRoute> {
+    self.hint.route.as_ref()
+  }
+
+  pub(crate) fn stop(&mut self) {
+    self.alive.store(false, Ordering::Release);
+  }
+}
+
+#[test]
+fn test_producer_forward_open_sizes() {
+  let hint = ProducerHint {
+    data_size: 10,
+    run_idle: true,
+    ..ProducerHint::default()
+  };
+  assert_eq!(hint.forward_open_spec().ot_size, 16);
+  assert_eq!(hint.forward_open_spec().to_size, 2);
+
+  let hint = ProducerHint { input_size: 8, ..hint };
+  assert_eq!(hint.forward_open_spec().to_size, 10);
+}
+
+#[test]
+fn test_output_buffer_version() {
+  let buffer = OutputBuffer::new(2);
+  buffer.set(&[0, 0]).unwrap();
+  assert_eq!(buffer.snapshot().2, 0);
+
+  buffer.set(&[1, 0]).unwrap();
+  buffer.update(|data| data[1] = 2);
+  buffer.update(|data| data[1] = 2);
+  assert_eq!(buffer.snapshot(), (vec![1, 2], true, 2));
+
+  assert!(buffer.set(&[1]).is_err());
+}
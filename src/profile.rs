@@ -0,0 +1,175 @@
+use std::fmt;
+use std::io::{Result, Error, ErrorKind};
+use serde::{Deserialize, Serialize};
+
+use crate::eip::{self, ForwardOpenSpec};
+use crate::{CipRoute, EipAddr, TagDialect};
+
+/*
+What kind of device an EipAddr points at
+The profile decides how the connection path starts, how big connections can
+be, and which explicit services the device understands. Asking a device for
+something its profile says it can't do fails before anything is sent.
+
+ControlLogix: an Ethernet module in a chassis; the controller is in the slot.
+CompactLogix: the controller is the Ethernet module, and always slot 0. Only
+  standard Forward Opens of up to 500 bytes.
+Micro800: the controller is the endpoint and rejects any port segment. Tag
+  services only, no produced tags or PCCC.
+GenericAdapter: an I/O adapter or other device that is the endpoint itself and
+  only has assembly connections.
+OmronNjNx: an Omron NJ/NX controller, the endpoint itself, whose tag services
+  encode values the Omron way (see TagDialect).
+*/
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum PlcProfile {
+  #[default]
+  ControlLogix,
+  CompactLogix,
+  Micro800,
+  GenericAdapter,
+  OmronNjNx,
+}
+impl PlcProfile {
+
+  /*
+  The route to the controller, for connections and explicit requests that
+  don't give their own
+  */
+  pub fn route(&self, addr: &EipAddr) -> CipRoute {
+    match self {
+      PlcProfile::ControlLogix => CipRoute::backplane(addr.slot),
+      PlcProfile::CompactLogix => CipRoute::backplane(0),
+      PlcProfile::Micro800 | PlcProfile::GenericAdapter | PlcProfile::OmronNjNx => CipRoute::default(),
+    }
+  }
+
+  /*
+  The largest connection size (in either direction) the device accepts
+  */
+  pub fn max_connection_size(&self) -> usize {
+    match self {
+      PlcProfile::ControlLogix => 4002,
+      PlcProfile::CompactLogix => 500,
+      PlcProfile::Micro800 => eip::MAX_FORWARD_OPEN_SIZE,
+      PlcProfile::GenericAdapter => eip::MAX_LARGE_FORWARD_OPEN_SIZE,
+      PlcProfile::OmronNjNx => 1444,
+    }
+  }
+
+  /*
+  How the device's Read Tag and Write Tag encode values
+  */
+  pub fn tag_dialect(&self) -> TagDialect {
+    match self {
+      PlcProfile::OmronNjNx => TagDialect::Omron,
+      _ => TagDialect::Logix,
+    }
+  }
+
+  /*
+  Read Tag and Write Tag
+  */
+  pub fn supports_tag_services(&self) -> bool {
+    *self != PlcProfile::GenericAdapter
+  }
+
+  /*
+  Execute PCCC, which Logix controllers answer from their mapped files
+  */
+  pub fn supports_pccc(&self) -> bool {
+    matches!(self, PlcProfile::ControlLogix | PlcProfile::CompactLogix)
+  }
+
+  /*
+  Connections to produced tags by name, rather than to assemblies
+  */
+  pub fn supports_tag_connections(&self) -> bool {
+    matches!(self, PlcProfile::ControlLogix | PlcProfile::CompactLogix | PlcProfile::OmronNjNx)
+  }
+
+  /*
+  Check an EipAddr makes sense for this profile
+  */
+  pub fn check_addr(&self, addr: &EipAddr) -> Result<()> {
+    if *self == PlcProfile::CompactLogix && addr.slot != 0 {
+      return Err(self.unsupported(format!("the controller is always in slot 0, not slot {}", addr.slot)));
+    }
+
+    Ok(())
+  }
+
+  /*
+  Check an explicit request can be routed to this device
+  */
+  pub fn check_route(&self, route: &CipRoute) -> Result<()> {
+    if *self == PlcProfile::Micro800 && !route.is_local() {
+      return Err(self.unsupported(String::from("the controller doesn't accept a port segment")));
+    }
+
+    Ok(())
+  }
+
+  /*
+  Check a Forward Open against what the device can do
+  */
+  pub(crate) fn check_forward_open(&self, spec: &ForwardOpenSpec) -> Result<()> {
+    if let Some(route) = spec.route {
+      self.check_route(route)?;
+    }
+
+    let max = self.max_connection_size();
+    if spec.ot_size > max || spec.to_size > max {
+      return Err(self.unsupported(format!(
+        "connections are limited to {} bytes, asked for {} O->T and {} T->O",
+        max, spec.ot_size, spec.to_size
+      )));
+    }
+
+    if spec.assembly.is_none() && !self.supports_tag_connections() {
+      return Err(self.unsupported(String::from("connections need an assembly path")));
+    }
+
+    Ok(())
+  }
+
+  pub(crate) fn unsupported(&self, reason: String) -> Error {
+    Error::new(ErrorKind::Unsupported, format!("{}: {}", self, reason))
+  }
+}
+impl fmt::Display for PlcProfile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PlcProfile::ControlLogix => write!(f, "ControlLogix"),
+      PlcProfile::CompactLogix => write!(f, "CompactLogix"),
+      PlcProfile::Micro800 => write!(f, "Micro800"),
+      PlcProfile::GenericAdapter => write!(f, "generic adapter"),
+      PlcProfile::OmronNjNx => write!(f, "Omron NJ/NX"),
+    }
+  }
+}
+
+#[test]
+fn test_profile_routes() {
+  let addr = EipAddr { addr: "10.0.0.1".parse().unwrap(), slot: 2 };
+  assert_eq!(PlcProfile::ControlLogix.route(&addr), CipRoute::backplane(2));
+  assert!(PlcProfile::Micro800.route(&addr).is_local());
+
+  assert!(PlcProfile::CompactLogix.check_addr(&addr).is_err());
+  assert!(PlcProfile::Micro800.check_route(&CipRoute::backplane(0)).is_err());
+}
+
+#[test]
+fn test_profile_forward_open() {
+  let hint = crate::ConsumerHint {
+    tag: String::from("Produced"),
+    data_size: 600,
+    ..crate::ConsumerHint::default()
+  };
+
+  assert!(PlcProfile::ControlLogix.check_forward_open(&hint.forward_open_spec()).is_ok());
+  let error = PlcProfile::CompactLogix.check_forward_open(&hint.forward_open_spec()).unwrap_err();
+  assert_eq!(error.kind(), ErrorKind::Unsupported);
+  assert!(PlcProfile::GenericAdapter.check_forward_open(&hint.forward_open_spec()).is_err());
+}
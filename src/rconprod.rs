@@ -0,0 +1,56 @@
+use rconpro::{Daemon, DaemonConfig};
+use rconpro::daemon::EventLog;
+use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+rconprod: consume tags from many PLCs as a long-running service
+*/
+#[derive(Parser)]
+#[command(name = "rconprod", version, about = "Consume tags from the PLCs in a config file until stopped")]
+struct Cli {
+  /// The config file, TOML or YAML (.yaml, .yml)
+  config: PathBuf,
+
+  /// Check the config and exit
+  #[arg(long)]
+  check: bool,
+}
+
+fn main() {
+  let cli = Cli::parse();
+  let mut log = EventLog::stderr();
+
+  let config = match DaemonConfig::load(&cli.config) {
+    Ok(config) => config,
+    Err(e) => {
+      log.error("config_invalid", &[("path", cli.config.display().to_string().into()), ("error", e.to_string().into())]);
+      std::process::exit(1);
+    },
+  };
+  if cli.check {
+    return;
+  }
+
+  // Ctrl-C and SIGTERM both stop the daemon cleanly
+  let running = Arc::new(AtomicBool::new(true));
+  let handler_running = Arc::clone(&running);
+  if let Err(e) = ctrlc::set_handler(move || handler_running.store(false, Ordering::Release)) {
+    log.error("signal_handler_failed", &[("error", e.to_string().into())]);
+    std::process::exit(1);
+  }
+
+  let result = Daemon::new(config, log).and_then(|mut daemon| {
+    // Edits to the config file, or a SIGHUP, reload it without a restart
+    daemon.watch(&cli.config);
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, daemon.reload_trigger())?;
+    daemon.run(&running)
+  });
+  if let Err(e) = result {
+    EventLog::stderr().error("daemon_failed", &[("error", e.to_string().into())]);
+    std::process::exit(1);
+  }
+}
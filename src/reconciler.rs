@@ -0,0 +1,329 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Result, Error, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::service::Connector;
+use crate::{ConsumerHint, ConsumerQueue, EipAddr};
+
+/*
+Where a consumer's connection is at
+Pending: not tried yet, because the service hasn't started or is about to.
+Connected: open, with its O->T connection ID.
+Retrying: the last attempt failed, or the connection timed out. The next try
+  comes after a backoff that doubles with every failed attempt.
+Failed: the hint can never work (it's invalid, or the PLC's profile can't do
+  it), so it isn't tried again.
+Stopped: stop_consumer was called.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsumerState {
+  Pending,
+  Connected { connection_id: u32 },
+  Retrying { attempts: u32, error: String },
+  Failed { error: String },
+  Stopped,
+}
+
+#[derive(Debug)]
+struct Status {
+  state: Mutex<ConsumerState>,
+  changed: Condvar,
+}
+
+/*
+A consumer added to a Service
+The Service keeps trying to open its connection until stop_consumer, and
+re-opens it if it times out. Clones are handles to the same consumer.
+*/
+#[derive(Debug, Clone)]
+pub struct ConsumerHandle {
+  id: u64,
+  addr: EipAddr,
+  status: Arc<Status>,
+}
+impl ConsumerHandle {
+  pub(crate) fn new(id: u64, addr: EipAddr) -> ConsumerHandle {
+    ConsumerHandle {
+      id,
+      addr,
+      status: Arc::new(Status {
+        state: Mutex::new(ConsumerState::Pending),
+        changed: Condvar::new(),
+      }),
+    }
+  }
+
+  pub fn addr(&self) -> EipAddr {
+    self.addr
+  }
+
+  pub fn state(&self) -> ConsumerState {
+    self.status.state.lock().unwrap().clone()
+  }
+
+  /*
+  The O->T connection ID, while the connection is open
+  */
+  pub fn connection_id(&self) -> Option<u32> {
+    match self.state() {
+      ConsumerState::Connected { connection_id } => Some(connection_id),
+      _ => None,
+    }
+  }
+
+  /*
+  Wait for the first attempt to open the connection, for up to the timeout
+  Returns the state then, which is still Pending if the timeout ran out.
+  */
+  pub fn wait(&self, timeout: Duration) -> ConsumerState {
+    let state = self.status.state.lock().unwrap();
+    let (state, _) = self.status.changed
+      .wait_timeout_while(state, timeout, |state| *state == ConsumerState::Pending)
+      .unwrap();
+    state.clone()
+  }
+
+  pub(crate) fn id(&self) -> u64 {
+    self.id
+  }
+
+  pub(crate) fn set(&self, state: ConsumerState) {
+    *self.status.state.lock().unwrap() = state;
+    self.status.changed.notify_all();
+  }
+}
+
+/*
+A consumer the service should have open
+*/
+pub(crate) struct DesiredConsumer {
+  pub(crate) handle: ConsumerHandle,
+  hint: ConsumerHint,
+  queue: Arc<ConsumerQueue>,
+  attempts: u32,
+  next_attempt: Instant,
+}
+impl DesiredConsumer {
+  pub(crate) fn new(handle: ConsumerHandle, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> DesiredConsumer {
+    DesiredConsumer {
+      handle,
+      hint,
+      queue: Arc::clone(queue),
+      attempts: 0,
+      next_attempt: Instant::now(),
+    }
+  }
+}
+
+/*
+Keeps the open connections in line with the desired consumers
+Every pass opens the consumers that are due an attempt and re-opens the ones
+that timed out. PLCs are only talked to with the list unlocked, so adding and
+stopping consumers never waits on one. Each PLC's consumers are opened on a
+worker of their own, so a PLC that's slow to answer (or doesn't) only holds up
+itself.
+*/
+#[derive(Clone)]
+pub(crate) struct Reconciler {
+  connector: Connector,
+  consumers: Arc<Mutex<Vec<DesiredConsumer>>>,
+  opening: Arc<Mutex<HashSet<EipAddr>>>,
+  retry_min: Duration,
+  retry_max: Duration,
+}
+impl Reconciler {
+  pub(crate) fn new(connector: Connector, consumers: &Arc<Mutex<Vec<DesiredConsumer>>>, retry_min: Duration, retry_max: Duration) -> Reconciler {
+    Reconciler {
+      connector,
+      consumers: Arc::clone(consumers),
+      opening: Arc::new(Mutex::new(HashSet::new())),
+      retry_min,
+      retry_max,
+    }
+  }
+
+  pub(crate) fn start(self, alive: Arc<AtomicBool>) -> Result<()> {
+    const PASS_INTERVAL: Duration = Duration::from_millis(10);
+
+    thread::Builder::new().name(String::from("Reconciler")).spawn(move || {
+      while alive.load(Ordering::Relaxed) {
+        self.reconcile();
+        thread::sleep(PASS_INTERVAL);
+      }
+    })?;
+
+    Ok(())
+  }
+
+  pub(crate) fn reconcile(&self) {
+    let now = Instant::now();
+    let mut timed_out = vec![];
+    let mut due: HashMap<EipAddr, Vec<_>> = HashMap::new();
+    {
+      let opening = self.opening.lock().unwrap();
+      for consumer in self.consumers.lock().unwrap().iter() {
+        let addr = consumer.handle.addr;
+        match consumer.handle.state() {
+          // A consumer that's gone missing went with its PLC's session
+          ConsumerState::Connected { connection_id }
+            if self.connector.consumer_timed_out(addr, connection_id) != Some(false) => {
+            timed_out.push((consumer.handle.clone(), connection_id));
+          },
+          // PLCs still working through the last lot wait for the next pass
+          ConsumerState::Pending | ConsumerState::Retrying { .. } if consumer.next_attempt <= now && !opening.contains(&addr) => {
+            due.entry(addr).or_default().push((consumer.handle.clone(), consumer.hint.clone(), Arc::clone(&consumer.queue)));
+          },
+          _ => (),
+        }
+      }
+    }
+
+    for (handle, connection_id) in timed_out {
+      self.connector.close_consumer(handle.addr, connection_id);
+      self.update(&handle, |consumer| {
+        consumer.attempts = 0;
+        consumer.next_attempt = Instant::now();
+        consumer.handle.set(ConsumerState::Retrying {
+          attempts: 0,
+          error: String::from("the connection timed out"),
+        });
+      });
+    }
+
+    for (addr, due) in due {
+      self.opening.lock().unwrap().insert(addr);
+      let worker = self.clone();
+      let spawned = thread::Builder::new().name(format!("Reconciler for {}", addr.addr)).spawn(move || {
+        worker.open(addr, due);
+        worker.opening.lock().unwrap().remove(&addr);
+      });
+      if let Err(e) = spawned {
+        eprintln!("Couldn't start opening consumers on {}: {}", addr.addr, e);
+        self.opening.lock().unwrap().remove(&addr);
+      }
+    }
+  }
+
+  /*
+  Open one PLC's due consumers
+  A PLC that can't be reached fails them all at once, instead of each one
+  waiting out the connect timeout in turn.
+  */
+  fn open(&self, addr: EipAddr, due: Vec<(ConsumerHandle, ConsumerHint, Arc<ConsumerQueue>)>) {
+    let reached = self.connector.ensure_plc(addr);
+    for (handle, hint, queue) in due {
+      let result = match &reached {
+        Ok(()) => self.connector.open_consumer(addr, hint, &queue),
+        Err(e) => Err(Error::new(e.kind(), e.to_string())),
+      };
+      let updated = self.update(&handle, |consumer| match &result {
+        Ok(connection_id) => {
+          consumer.attempts = 0;
+          consumer.handle.set(ConsumerState::Connected { connection_id: *connection_id });
+        },
+        Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::Unsupported) => {
+          consumer.handle.set(ConsumerState::Failed { error: e.to_string() });
+        },
+        Err(e) => {
+          consumer.attempts += 1;
+          consumer.next_attempt = Instant::now() + self.backoff(consumer.attempts);
+          consumer.handle.set(ConsumerState::Retrying { attempts: consumer.attempts, error: e.to_string() });
+        },
+      });
+
+      // Stopped while we were opening it
+      if let (false, Ok(connection_id)) = (updated, result) {
+        self.connector.close_consumer(handle.addr, connection_id);
+      }
+    }
+  }
+
+  /*
+  How long to wait after a number of failed attempts
+  */
+  fn backoff(&self, attempts: u32) -> Duration {
+    let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+    self.retry_min.saturating_mul(factor).min(self.retry_max)
+  }
+
+  /*
+  Change a consumer that's still wanted, returning whether it was
+  */
+  fn update<F: FnOnce(&mut DesiredConsumer)>(&self, handle: &ConsumerHandle, change: F) -> bool {
+    let mut consumers = self.consumers.lock().unwrap();
+    match consumers.iter_mut().find(|consumer| consumer.handle.id == handle.id) {
+      Some(consumer) => {
+        change(consumer);
+        true
+      },
+      None => false,
+    }
+  }
+}
+
+#[test]
+fn test_reconciler_with_simulator() {
+  use std::net::{IpAddr, Ipv4Addr, TcpListener};
+  use crate::sim::Simulator;
+  use crate::{ConnectionKind, Faults, Service, ServiceConfig};
+
+  let until = |condition: &dyn Fn() -> bool| {
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while !condition() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(5));
+    }
+    condition()
+  };
+
+  let sim = Simulator::loopback(0).unwrap();
+  let mut service = Service::with_config(ServiceConfig {
+    retry_min: 20,
+    retry_max: 100,
+    ..sim.service_config().unwrap()
+  });
+  let addr = EipAddr { addr: IpAddr::V4(Ipv4Addr::LOCALHOST), slot: 0 };
+  let hint = ConsumerHint { tag: String::from("Late"), data_size: 6, rpi: 10_000, otrpi: 10_000, ..ConsumerHint::default() };
+
+  // Nothing happens before the service starts
+  let queue = Arc::new(ConsumerQueue::new());
+  let handle = service.add_consumer(addr, hint.clone(), &queue);
+  assert_eq!(handle.wait(Duration::from_millis(50)), ConsumerState::Pending);
+
+  // A PLC that takes the setup stream but never answers doesn't hold up the others
+  let silent_ip = Ipv4Addr::new(127, 0, 0, 2);
+  let _silent = TcpListener::bind((silent_ip, service.config().encap_port)).unwrap();
+  let silent = service.add_consumer(EipAddr { addr: IpAddr::V4(silent_ip), slot: 0 }, hint.clone(), &queue);
+
+  // The tag doesn't exist yet, so the Forward Open is retried until it does
+  service.start().unwrap();
+  assert!(matches!(handle.wait(Duration::from_secs(2)), ConsumerState::Retrying { attempts: 1, .. }));
+  assert!(until(&|| matches!(handle.state(), ConsumerState::Retrying { attempts, .. } if attempts >= 3)));
+  sim.add_tag("Late", 4);
+  assert!(until(&|| handle.connection_id().is_some()));
+  assert!(until(&|| !queue.is_empty()));
+
+  // A PLC that drops the session and stops producing is reconnected, and the
+  // consumer re-opened
+  sim.inject_connection(handle.connection_id().unwrap(), Faults { stop_after: Some(0), ..Faults::default() });
+  sim.reset_sessions();
+  assert!(until(&|| handle.connection_id().is_none()));
+  assert!(until(&|| handle.connection_id().is_some() && sim.connected_tags() == vec!["Late"]));
+  while queue.pop().is_some() {}
+  assert!(until(&|| !queue.is_empty()));
+
+  // A hint that can never work isn't retried
+  let listen_only = service.add_consumer(addr, ConsumerHint { kind: ConnectionKind::ListenOnly, ..hint }, &queue);
+  assert!(matches!(listen_only.wait(Duration::from_secs(2)), ConsumerState::Failed { .. }));
+
+  assert_eq!(silent.state(), ConsumerState::Pending);
+
+  service.stop_consumer(&handle).unwrap();
+  service.stop_consumer(&listen_only).unwrap();
+  service.stop_consumer(&silent).unwrap();
+  assert_eq!(handle.state(), ConsumerState::Stopped);
+  assert!(sim.connected_tags().is_empty());
+  service.stop();
+}
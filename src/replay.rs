@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::capture::{CaptureReader, Direction, Record, Traffic};
+use crate::eip;
+use crate::{ConsumerHint, ConsumerQueue, EipAddr};
+
+// Where the data starts in a class 1 packet, after the CIP sequence count
+const IO_DATA_OFFSET: usize = 20;
+
+/*
+How fast a Replay plays a capture
+Original keeps the gaps between packets as they were recorded.
+*/
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum ReplaySpeed {
+  #[default]
+  Original,
+  Fast,
+}
+
+/*
+A connection opened in a capture: its tag and both connection IDs
+*/
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReplayConnection {
+  pub addr: EipAddr,
+  pub tag: Option<String>,
+  pub ot_connection_id: u32,
+  pub to_connection_id: u32,
+}
+
+/*
+Plays captured traffic into ConsumerQueues the way a live Service fills them
+Consumers are added as on a Service, with a ConsumerHint: its tag is looked up
+in the Forward Opens the capture recorded (every one, if the tag was opened more
+than once). Captures without them, or connections to assemblies, can name the
+T->O connection ID instead. Every received class 1 packet for a consumer is
+pushed onto its queue without the CIP sequence count, same as a Service does.
+PLCs are told apart by IP alone, like the Service's listener does.
+*/
+pub struct Replay {
+  records: Vec<Record>,
+  connections: Vec<ReplayConnection>,
+  consumers: HashMap<(IpAddr, u32), Vec<Arc<ConsumerQueue>>>,
+}
+impl Replay {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Replay> {
+    Ok(Replay::new(CaptureReader::open(path)?.collect::<Result<_>>()?))
+  }
+
+  pub fn new(records: Vec<Record>) -> Replay {
+    let connections = find_connections(&records);
+
+    Replay {
+      records,
+      connections,
+      consumers: HashMap::new(),
+    }
+  }
+
+  /*
+  The connections the capture saw being opened, in order
+  */
+  pub fn connections(&self) -> &[ReplayConnection] {
+    &self.connections
+  }
+
+  /*
+  Feed a queue with the data of a tag the capture has a connection to
+  Returns the O->T connection ID, as ConsumerHandle::connection_id does.
+  */
+  pub fn add_consumer(&mut self, addr: EipAddr, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> Result<u32> {
+    let matching: Vec<(u32, u32)> = self.connections.iter()
+      .filter(|connection| connection.addr.addr == addr.addr && connection.tag.as_deref() == Some(hint.tag.as_str()))
+      .map(|connection| (connection.ot_connection_id, connection.to_connection_id))
+      .collect();
+    let (ot_connection_id, _) = *matching.first()
+      .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the capture has no connection to {} on {}", hint.tag, addr.addr)))?;
+
+    for (_, to_connection_id) in matching {
+      self.add_connection(addr, to_connection_id, queue);
+    }
+
+    Ok(ot_connection_id)
+  }
+
+  /*
+  Feed a queue with the data of a T->O connection ID
+  */
+  pub fn add_connection(&mut self, addr: EipAddr, to_connection_id: u32, queue: &Arc<ConsumerQueue>) {
+    self.consumers.entry((addr.addr, to_connection_id))
+      .or_default()
+      .push(Arc::clone(queue));
+  }
+
+  /*
+  Play the capture through once, returning how many packets were pushed
+  */
+  pub fn run(&self, speed: ReplaySpeed) -> usize {
+    let started = Instant::now();
+    let first = self.records.first().map(|record| record.timestamp);
+    let mut pushed = 0;
+
+    for record in &self.records {
+      if record.direction != Direction::Received || record.traffic != Traffic::Io || record.data.len() < IO_DATA_OFFSET {
+        continue;
+      }
+      let queues = match self.consumers.get(&(record.addr.addr, record.connection_id)) {
+        Some(queues) => queues,
+        None => continue,
+      };
+
+      if let (ReplaySpeed::Original, Some(first)) = (speed, first) {
+        let due = started + offset(first, record.timestamp);
+        let now = Instant::now();
+        if due > now {
+          thread::sleep(due - now);
+        }
+      }
+
+      for queue in queues {
+        queue.push(record.data[IO_DATA_OFFSET..].to_vec());
+        pushed += 1;
+      }
+    }
+
+    pushed
+  }
+
+  /*
+  Play the capture on its own thread, as live traffic would arrive
+  */
+  pub fn start(self, speed: ReplaySpeed) -> Result<JoinHandle<usize>> {
+    thread::Builder::new().name(String::from("Replay")).spawn(move || self.run(speed))
+  }
+}
+
+fn offset(first: SystemTime, timestamp: SystemTime) -> Duration {
+  timestamp.duration_since(first).unwrap_or_default()
+}
+
+/*
+Pair up the Forward Opens we sent with their successful replies
+Requests and replies are matched by PLC and sender context.
+*/
+fn find_connections(records: &[Record]) -> Vec<ReplayConnection> {
+  const HEADER_SIZE: usize = 24;
+
+  let mut requests: HashMap<(IpAddr, u64), Option<String>> = HashMap::new();
+  let mut connections = vec![];
+  for record in records.iter().filter(|record| record.traffic == Traffic::Explicit && record.data.len() >= HEADER_SIZE) {
+    let context = LittleEndian::read_u64(&record.data[12..20]);
+    match record.direction {
+      Direction::Sent => {
+        let request = eip::parse_send_rr_data_reply(&record.data)
+          .and_then(eip::parse_forward_open_request);
+        if let Ok(request) = request {
+          requests.insert((record.addr.addr, context), eip::parse_symbolic_path(&request.path));
+        }
+      },
+      Direction::Received => {
+        if let Some(tag) = requests.remove(&(record.addr.addr, context)) {
+          if let Ok(reply) = eip::parse_forward_open_reply(&record.data) {
+            connections.push(ReplayConnection {
+              addr: record.addr,
+              tag,
+              ot_connection_id: reply.ot_connection_id,
+              to_connection_id: reply.to_connection_id,
+            });
+          }
+        }
+      },
+    }
+  }
+
+  connections
+}
+
+#[test]
+fn test_replay() {
+  use std::net::Ipv4Addr;
+  use std::time::UNIX_EPOCH;
+  use crate::{eip::ForwardOpenRequest, CipRoute};
+
+  let addr = EipAddr { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), slot: 0 };
+  let hint = ConsumerHint {
+    tag: String::from("Count"),
+    data_size: 6,
+    rpi: 10_000,
+    otrpi: 10_000,
+    ..ConsumerHint::default()
+  };
+  let record = |millis, direction, traffic, connection_id, data| Record {
+    timestamp: UNIX_EPOCH + Duration::from_millis(millis),
+    direction,
+    traffic,
+    connection_id,
+    addr,
+    data,
+  };
+
+  let request = eip::build_forward_open_packet(&CipRoute::backplane(0), 1, 7, &hint.forward_open_spec()).unwrap();
+  let parsed: ForwardOpenRequest = eip::parse_forward_open_request(eip::parse_send_rr_data_reply(&request).unwrap()).unwrap();
+  let reply = eip::build_send_rr_data_packet(1, 7, &eip::build_cip_forward_open_reply(&parsed, 0x1111, 0x2222));
+
+  let mut records = vec![
+    record(0, Direction::Sent, Traffic::Explicit, 0, request),
+    record(1, Direction::Received, Traffic::Explicit, 0, reply),
+  ];
+  for i in 0..5u32 {
+    let mut packet = vec![0x02, 0x00, 0x02, 0x80, 0x08, 0x00];
+    packet.extend_from_slice(&0x2222u32.to_le_bytes());
+    packet.extend_from_slice(&i.to_le_bytes());
+    packet.extend_from_slice(&[0xB1, 0x00, 0x06, 0x00, i as u8, 0x00]);
+    packet.extend_from_slice(&i.to_le_bytes());
+    records.push(record(10 + 20 * u64::from(i), Direction::Received, Traffic::Io, 0x2222, packet));
+  }
+
+  let mut replay = Replay::new(records);
+  assert_eq!(replay.connections().len(), 1);
+  let queue = Arc::new(ConsumerQueue::new());
+  assert_eq!(replay.add_consumer(addr, hint.clone(), &queue).unwrap(), 0x1111);
+  assert!(replay.add_consumer(addr, ConsumerHint { tag: String::from("Other"), ..hint }, &queue).is_err());
+
+  // Fast, then at the recorded pace
+  assert_eq!(replay.run(ReplaySpeed::Fast), 5);
+  assert_eq!(queue.pop(), Some(0u32.to_le_bytes().to_vec()));
+
+  let started = Instant::now();
+  assert_eq!(replay.start(ReplaySpeed::Original).unwrap().join().unwrap(), 5);
+  assert!(started.elapsed() >= Duration::from_millis(90));
+  assert_eq!(queue.len(), 9);
+}
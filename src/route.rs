@@ -0,0 +1,193 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+use crate::EipAddr;
+
+/*
+Where a hop leaves the device it starts on
+Backplane slots and DH+/ControlNet node numbers are plain link addresses;
+Ethernet ports need the next device's IP address.
+*/
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub enum LinkAddress {
+  Slot(u8),
+  Ip(Ipv4Addr),
+}
+
+/*
+One hop of a route: out of a port to a link address
+*/
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct RouteHop {
+  pub port: u16,
+  pub link: LinkAddress,
+}
+
+/*
+A route from the device we talk to over TCP to the device we actually want
+Written as comma separated port,link pairs, like "1,0,2,192.168.1.20,1,3": out
+of the backplane to slot 0, out of that module's Ethernet port to 192.168.1.20,
+then out of the remote backplane to slot 3. An empty route is the endpoint itself.
+*/
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CipRoute {
+  pub hops: Vec<RouteHop>,
+}
+impl CipRoute {
+
+  /*
+  The route to a slot of the local backplane, which is what EipAddr describes
+  */
+  pub fn backplane(slot: u8) -> CipRoute {
+    CipRoute {
+      hops: vec![RouteHop { port: 1, link: LinkAddress::Slot(slot) }]
+    }
+  }
+
+  /*
+  True for the empty route, which needs no routing at all
+  */
+  pub fn is_local(&self) -> bool {
+    self.hops.is_empty()
+  }
+
+  /*
+  Encode the route as port segments
+  The result always has an even length; its size in words is len() / 2.
+  */
+  pub fn encode(&self) -> Vec<u8> {
+    const EXTENDED_LINK_ADDRESS: u8 = 0x10;
+    const EXTENDED_PORT: u8 = 0x0F;
+
+    let mut path = vec![];
+    for hop in self.hops.iter() {
+      let link = match &hop.link {
+        LinkAddress::Slot(slot) => vec![*slot],
+        LinkAddress::Ip(ip) => ip.to_string().into_bytes(),
+      };
+      let extended_link = link.len() > 1;
+
+      // Ports 15 and up don't fit the segment byte and follow it as a u16
+      let port_bits = if hop.port < 15 { hop.port as u8 } else { EXTENDED_PORT };
+      let segment = port_bits | if extended_link { EXTENDED_LINK_ADDRESS } else { 0 };
+      path.push(segment);
+      if extended_link {
+        path.push(link.len() as u8);
+      }
+      if hop.port >= 15 {
+        path.extend_from_slice(&hop.port.to_le_bytes());
+      }
+      path.extend_from_slice(&link);
+
+      if path.len() % 2 == 1 {
+        path.push(0x00);
+      }
+    }
+
+    path
+  }
+}
+impl From<EipAddr> for CipRoute {
+  fn from(addr: EipAddr) -> CipRoute {
+    CipRoute::backplane(addr.slot)
+  }
+}
+impl FromStr for CipRoute {
+  type Err = Error;
+
+  fn from_str(route: &str) -> std::result::Result<CipRoute, Error> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidInput, message);
+
+    let parts: Vec<&str> = route.split(',')
+      .map(|part| part.trim())
+      .filter(|part| !part.is_empty())
+      .collect();
+    if parts.len() % 2 == 1 {
+      return Err(invalid(format!("route {:?} has a port without a link address", route)));
+    }
+
+    let mut hops = vec![];
+    for pair in parts.chunks(2) {
+      let port = pair[0].parse::<u16>()
+        .map_err(|_| invalid(format!("{:?} is not a port number", pair[0])))?;
+      if port == 0 {
+        return Err(invalid(String::from("port 0 is reserved")));
+      }
+
+      let link = if let Ok(slot) = pair[1].parse::<u8>() {
+        LinkAddress::Slot(slot)
+      } else if let Ok(ip) = pair[1].parse::<Ipv4Addr>() {
+        LinkAddress::Ip(ip)
+      } else {
+        return Err(invalid(format!("{:?} is not a slot or IP address", pair[1])));
+      };
+
+      hops.push(RouteHop { port, link });
+    }
+
+    Ok(CipRoute { hops })
+  }
+}
+impl fmt::Display for CipRoute {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let parts: Vec<String> = self.hops.iter()
+      .map(|hop| match &hop.link {
+        LinkAddress::Slot(slot) => format!("{},{}", hop.port, slot),
+        LinkAddress::Ip(ip) => format!("{},{}", hop.port, ip),
+      })
+      .collect();
+
+    write!(f, "{}", parts.join(","))
+  }
+}
+impl TryFrom<String> for CipRoute {
+  type Error = Error;
+
+  fn try_from(route: String) -> std::result::Result<CipRoute, Error> {
+    route.parse()
+  }
+}
+impl From<CipRoute> for String {
+  fn from(route: CipRoute) -> String {
+    route.to_string()
+  }
+}
+
+#[test]
+fn test_parse_route() {
+  let route: CipRoute = "1,0,2,192.168.1.20,1,3".parse().unwrap();
+  assert_eq!(route.hops, vec![
+    RouteHop { port: 1, link: LinkAddress::Slot(0) },
+    RouteHop { port: 2, link: LinkAddress::Ip(Ipv4Addr::new(192, 168, 1, 20)) },
+    RouteHop { port: 1, link: LinkAddress::Slot(3) },
+  ]);
+  assert_eq!(route.to_string(), "1,0,2,192.168.1.20,1,3");
+
+  assert!("".parse::<CipRoute>().unwrap().is_local());
+  assert!("1".parse::<CipRoute>().is_err());
+  assert!("1,plc".parse::<CipRoute>().is_err());
+}
+
+#[test]
+fn test_encode_route() {
+  let route: CipRoute = "1,0,2,192.168.1.20,1,3".parse().unwrap();
+  let mut expected = vec![0x01, 0x00, 0x12, 12];
+  expected.extend_from_slice(b"192.168.1.20");
+  expected.extend_from_slice(&[0x01, 0x03]);
+  assert_eq!(route.encode(), expected);
+
+  // Odd-length addresses are padded
+  let route: CipRoute = "2,10.0.0.1".parse().unwrap();
+  assert_eq!(route.encode(), vec![0x12, 8, b'1', b'0', b'.', b'0', b'.', b'0', b'.', b'1']);
+  let route: CipRoute = "2,10.0.0.10".parse().unwrap();
+  assert_eq!(route.encode().len(), 12);
+
+  // Ports 15 and up
+  let route: CipRoute = "18,4".parse().unwrap();
+  assert_eq!(route.encode(), vec![0x0F, 18, 0, 4]);
+}
@@ -3,20 +3,29 @@ use std::time::Duration;
 use std::sync::atomic::{AtomicU32, AtomicBool, Ordering};
 use std::sync::{Arc, RwLock, Mutex};
 use std::io::{Result, Cursor, ErrorKind};
+use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use byteorder::{ReadBytesExt, LittleEndian};
 
-use crate::sockets::{EipAddr, CPSocket};
-use crate::{ConsumerHint, Plc, ConsumerQueue};
+use crate::sockets::{EipAddr, CPSocket, ServiceConfig};
+use crate::capture::{Direction, RecorderHandle, Traffic};
+use crate::reconciler::{ConsumerHandle, ConsumerState, DesiredConsumer, Reconciler};
+use crate::{pccc, tag, CipRoute, Recorder, ConsumerHint, Identity, PcccAddress, PlcProfile, TagInfo, TagValue, Plc, ConsumerQueue, ProducerHint, OutputBuffer};
 
 /*
 Entrypoint of rconpro
 Manages PLCs, Consumers, and incomming Consumer/Producer packets.
 */
 pub struct Service { 
+  config: ServiceConfig,
   pub(crate) plcs: Arc<RwLock<HashMap<EipAddr, Plc>>>,
+  profiles: Arc<RwLock<HashMap<EipAddr, PlcProfile>>>,
   pub(crate) cpsocket: Arc<Mutex<CPSocket>>,
   pub(crate) sequence_count: Arc<AtomicU32>,
+  recorder: RecorderHandle,
+  consumers: Arc<Mutex<Vec<DesiredConsumer>>>,
+  next_handle: u64,
   alive: Arc<AtomicBool>,
 }
 impl Service {
@@ -27,53 +36,269 @@ impl Service {
   is unwrapped.
   */
   pub fn new() -> Service {
+    Service::with_config(ServiceConfig::default())
+  }
+
+  /*
+  Initiate the service with its own interface, ports and buffer sizes
+  */
+  pub fn with_config(config: ServiceConfig) -> Service {
+    let recorder = RecorderHandle::default();
+    let mut cpsocket = CPSocket::new();
+    cpsocket.recorder = recorder.clone();
+
     Service {
+      config,
       plcs: Arc::new(RwLock::new(HashMap::new())),
-      cpsocket: Arc::new(Mutex::new(CPSocket::new())),
+      profiles: Arc::new(RwLock::new(HashMap::new())),
+      cpsocket: Arc::new(Mutex::new(cpsocket)),
       sequence_count: Arc::new(AtomicU32::new(0)),
+      recorder,
+      consumers: Arc::new(Mutex::new(vec![])),
+      next_handle: 0,
       alive: Arc::new(AtomicBool::new(true)),
     }
   }
 
+  pub fn config(&self) -> &ServiceConfig {
+    &self.config
+  }
+
+  /*
+  The address the I/O socket is bound to, once the service is started
+  */
+  pub fn io_addr(&self) -> Result<SocketAddr> {
+    self.cpsocket.lock().unwrap().local_addr()
+  }
+
+  /*
+  Record every class 1 packet sent and received, and every explicit message,
+  until stop_recording
+  Replaces (and returns) the recorder already in use, if any.
+  */
+  pub fn start_recording(&self, recorder: Recorder) -> Option<Recorder> {
+    self.recorder.set(Some(recorder))
+  }
+
+  /*
+  Stop recording, handing back the flushed recorder
+  */
+  pub fn stop_recording(&self) -> Option<Recorder> {
+    self.recorder.set(None)
+  }
+
+  /*
+  Say what kind of device is at an address
+  PLCs without a profile are treated as ControlLogix. Set the profile before
+  the first request or connection to the PLC; it also applies to a PLC that is
+  already connected.
+  */
+  pub fn set_profile(&self, addr: EipAddr, profile: PlcProfile) -> Result<()> {
+    profile.check_addr(&addr)?;
+
+    self.profiles.write()
+      .expect("PLC profile Lock is poisened")
+      .insert(addr, profile);
+    if let Some(plc) = self.plcs.write().expect("PLC HashMap Lock is poisened").get_mut(&addr) {
+      plc.profile = profile;
+    }
+
+    Ok(())
+  }
+
+  pub fn profile(&self, addr: EipAddr) -> PlcProfile {
+    self.connector().profile(addr)
+  }
+
+  /*
+  Send an explicit CIP request to a PLC and return the CIP reply
+  Connects and registers a session first if this PLC hasn't been seen yet.
+  Requests from different threads share the session and don't wait on each other.
+  */
+  pub fn send_explicit(&self, addr: EipAddr, cip_request: &[u8]) -> Result<Vec<u8>> {
+    self.send_routed(addr, &CipRoute::default(), cip_request)
+  }
+
+  /*
+  Send an explicit CIP request to a device behind a PLC and return its reply
+  Unless the route is empty, the request goes out wrapped in an Unconnected
+  Send; the PLC's profile gives the route to its controller.
+  Failures along the route come back as a RoutingError inside the io::Error.
+  */
+  pub fn send_routed(&self, addr: EipAddr, route: &CipRoute, cip_request: &[u8]) -> Result<Vec<u8>> {
+    self.ensure_plc(addr)?;
+
+    let plcs = self.plcs.read()
+      .expect("PLC HashMap Lock is poisened");
+    plcs[&addr].send_explicit(cip_request, route)
+  }
+
+  /*
+  Read a tag
+  Data table addresses like N7:10 or B3:1/4 are read with PCCC from the endpoint
+  itself, which is how SLC, MicroLogix and PLC-5 processors are reached.
+  Anything else is a symbolic tag, read from the controller the profile routes
+  to and decoded the way the profile's vendor encodes it.
+  */
+  pub fn read_tag(&self, addr: EipAddr, tag: &str) -> Result<TagValue> {
+    let profile = self.profile(addr);
+    if let Ok(address) = tag.parse::<PcccAddress>() {
+      if !profile.supports_pccc() {
+        return Err(profile.unsupported(String::from("PCCC isn't supported")));
+      }
+      let tns = rand::random();
+      let reply = self.send_routed(addr, &CipRoute::default(), &pccc::build_pccc_read(&address, tns))?;
+      return pccc::parse_pccc_read_reply(&address, &reply, tns);
+    }
+
+    if !profile.supports_tag_services() {
+      return Err(profile.unsupported(String::from("tag services aren't supported")));
+    }
+    let reply = self.send_routed(addr, &profile.route(&addr), &tag::build_read_tag(tag)?)?;
+    tag::parse_read_tag_reply(&reply, profile.tag_dialect())
+  }
+
+  /*
+  Write a tag
+  Tags are addressed the same way as for read_tag. The value has to have the
+  tag's type.
+  */
+  pub fn write_tag(&self, addr: EipAddr, tag: &str, value: &TagValue) -> Result<()> {
+    let profile = self.profile(addr);
+    if let Ok(address) = tag.parse::<PcccAddress>() {
+      if !profile.supports_pccc() {
+        return Err(profile.unsupported(String::from("PCCC isn't supported")));
+      }
+      let tns = rand::random();
+      let reply = self.send_routed(addr, &CipRoute::default(), &pccc::build_pccc_write(&address, value, tns)?)?;
+      return pccc::parse_pccc_write_reply(&reply, tns);
+    }
+
+    if !profile.supports_tag_services() {
+      return Err(profile.unsupported(String::from("tag services aren't supported")));
+    }
+    let reply = self.send_routed(addr, &profile.route(&addr), &tag::build_write_tag(tag, value, profile.tag_dialect())?)?;
+    tag::parse_write_tag_reply(&reply)
+  }
+
+  /*
+  List the controller's tags
+  Pages through the symbol table until the controller says it's done.
+  Only controller-scoped tags are listed; each program shows up as a Program:Name entry.
+  */
+  pub fn list_tags(&self, addr: EipAddr) -> Result<Vec<TagInfo>> {
+    self.list_tags_routed(addr, &self.profile(addr).route(&addr))
+  }
+
+  /*
+  List the tags of the controller at the end of a route
+  */
+  pub fn list_tags_routed(&self, addr: EipAddr, route: &CipRoute) -> Result<Vec<TagInfo>> {
+    let profile = self.profile(addr);
+    if !profile.supports_tag_services() {
+      return Err(profile.unsupported(String::from("tag services aren't supported")));
+    }
+
+    let mut tags = vec![];
+    let mut start_instance = 0;
+    loop {
+      let reply = self.send_routed(addr, route, &tag::build_get_tag_list_query(start_instance))?;
+      let (mut page, more) = tag::parse_get_tag_list_reply(&reply)?;
+      let last = page.last().map(|tag| tag.instance);
+      tags.append(&mut page);
+
+      // There's nothing past the last instance
+      match last.and_then(|last| last.checked_add(1)) {
+        Some(next) if more => start_instance = next,
+        _ => return Ok(tags),
+      }
+    }
+  }
+
+  /*
+  Get a PLC's identity with ListIdentity
+  Useful for filling in the Keying of a ConsumerHint.
+  */
+  pub fn identity(&self, addr: EipAddr) -> Result<Identity> {
+    self.ensure_plc(addr)?;
+
+    let plcs = self.plcs.read()
+      .expect("PLC HashMap Lock is poisened");
+    plcs[&addr].list_identity()
+  }
+
+  /*
+  Make sure there is a connected and registered Plc for this address
+  */
+  fn ensure_plc(&self, addr: EipAddr) -> Result<()> {
+    self.connector().ensure_plc(addr)
+  }
+
   /*
   Adds a consumer
-  This function calls all of the logic required to add and start a Consumer, regardless
-  of whether or not a connection has already been made with the target PLC.
+  The consumer is opened in the background once the service is started, and
+  retried with a backoff for as long as the PLC can't be reached or the
+  connection is refused. A connection that times out is opened again.
+  Returns right away with a handle that reports how far it got, which also
+  identifies the consumer to stop_consumer.
+  */
+  pub fn add_consumer(&mut self, addr: EipAddr, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> ConsumerHandle {
+    self.next_handle += 1;
+    let handle = ConsumerHandle::new(self.next_handle, addr);
+    self.consumers.lock().unwrap().push(DesiredConsumer::new(handle.clone(), hint, queue));
+
+    handle
+  }
+
+  /*
+  Adds a producer
+  Opens an exclusive-owner connection to the PLC and sends the contents of buffer
+  every RPI. Returns the O->T connection ID, which identifies the producer to
+  stop_producer.
+  */
+  pub fn add_producer(&mut self, addr: EipAddr, hint: ProducerHint, buffer: &Arc<OutputBuffer>) -> Result<u32> {
+    self.open_producer(addr, hint, buffer, None)
+  }
+
+  /*
+  Adds an adapter's I/O connection
+  One exclusive-owner connection carries the outputs O->T and the inputs T->O,
+  the way an adapter's output and input assemblies are scanned together. outputs
+  is sent every RPI like a producer's buffer, and every input packet is pushed
+  to inputs with its CIP sequence count, like a consumer's. hint.input_size is
+  the input assembly's size. Stopped with stop_producer.
   */
-  pub fn add_consumer(&mut self, addr: EipAddr, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> Result<u32> {
+  pub fn add_io_connection(&mut self, addr: EipAddr, hint: ProducerHint, outputs: &Arc<OutputBuffer>, inputs: &Arc<ConsumerQueue>) -> Result<u32> {
+    self.open_producer(addr, hint, outputs, Some(inputs))
+  }
+
+  fn open_producer(&mut self, addr: EipAddr, hint: ProducerHint, buffer: &Arc<OutputBuffer>, inputs: Option<&Arc<ConsumerQueue>>) -> Result<u32> {
+    self.ensure_plc(addr)?;
+
     // Get lock on plcs list
     let mut plcs = self.plcs.write()
       .expect("PLC HashMap Lock is poisened");
 
-    // Get PLC
-    let plc = if plcs.contains_key(&addr) {
-      plcs.get_mut(&addr).unwrap()
-    } else {
-      let mut plc = Plc::new(addr)?;
-      plc.connect()?;
-      plc.register()?;
-
-      plcs.insert(plc.addr, plc);
-      plcs.get_mut(&addr).unwrap()
-    };
-
-    // Create consumer
-    let (con, to_connection_id) = plc.add_consumer(hint, queue);
-    
-    // Start keep alive response thread
-    con.start_response_thread(&self.cpsocket, addr, &self.sequence_count);
+    // Create producer and start sending
+    let (producer, ot_connection_id) = plcs.get_mut(&addr).unwrap().add_producer(hint, buffer, inputs)?;
+    producer.start_output_thread(&self.cpsocket, addr, &self.sequence_count);
 
-    Ok(to_connection_id)
+    Ok(ot_connection_id)
   }
 
   pub fn start(&mut self) -> Result<()> {
     // Bind Socket
     let timeout = Duration::new(1,0);
-    self.cpsocket.lock().unwrap().bind(timeout)?;
+    self.cpsocket.lock().unwrap().bind(&self.config, timeout)?;
 
     // Start listener
     self.start_listener();
 
+    // Start opening consumers
+    let retry_min = Duration::from_millis(self.config.retry_min);
+    let retry_max = Duration::from_millis(self.config.retry_max);
+    Reconciler::new(self.connector(), &self.consumers, retry_min, retry_max).start(Arc::clone(&self.alive))?;
+
     Ok(())
   }
 
@@ -85,6 +310,7 @@ impl Service {
     let alive = self.alive.clone();
     let plcs_lock = Arc::clone(&self.plcs);
     let cpsocket_lock = Arc::clone(&self.cpsocket);
+    let recorder = self.recorder.clone();
 
     /*
     Listens to producers, parses incomming data, and sends parsed data to the
@@ -107,21 +333,41 @@ impl Service {
             cursor.set_position(6);
             let connection_id = cursor.read_u32::<LittleEndian>().unwrap();
 
-            // Send data to appropriate consumer
+            // Send data to every consumer of this connection; multicast
+            // consumers can share a T->O connection ID
             let plcs = plcs_lock.read().unwrap();
-            if plcs.contains_key(&src_addr) {
-              let plc = &plcs[&src_addr];
+            let mut plc_addr = None;
+            let mut multicast = false;
+            for (addr, plc) in plcs.iter().filter(|(addr, _)| addr.addr == src_addr.addr) {
+              for con in plc.consumers.values() {
+                multicast |= con.to_multicast.is_some();
+                if con.to_connection_id == connection_id {
+                  // Push to the queue
+                  con.heard();
+                  con.queue.push(d[20..].to_vec());
+                  plc_addr = Some(*addr);
+                }
+              }
 
-              if plc.consumers.contains_key(&connection_id) {
-                // Push to the queue
-                plc.consumers[&connection_id].queue.push(d[20..].to_vec());
-                continue;
+              // Producers' T->O side is a heartbeat, unless it's an I/O connection
+              for producer in plc.producers.values().filter(|producer| producer.to_connection_id == connection_id) {
+                if let Some(inputs) = &producer.inputs {
+                  inputs.push(d[20..].to_vec());
+                }
+                plc_addr = Some(*addr);
               }
             }
+            let found = plc_addr.is_some();
+
+            // Record it against the PLC whose connection it's on, slot and all
+            recorder.record(Direction::Received, Traffic::Io, plc_addr.unwrap_or(src_addr), &d);
 
             // No consumer was found
-            // This should happen.
-            eprintln!("That was weird. We didn't find an active consumer for the data we recieved")
+            // Other originators' connections in a multicast group we joined are
+            // expected; anything else shouldn't happen.
+            if !found && !multicast {
+              eprintln!("That was weird. We didn't find an active consumer for the data we recieved")
+            }
           },
           Err(e) => {
             if e.kind() == ErrorKind::WouldBlock {
@@ -135,11 +381,51 @@ impl Service {
     }).unwrap();
   }
 
-  pub fn stop_consumer(&mut self, plc: EipAddr, to_connection_id: u32) -> Option<()> {
+  /*
+  Check whether a consumer's connection has gone quiet for longer than its
+  watchdog timeout (the T->O RPI times the timeout multiplier)
+  Returns None if there is no such consumer.
+  */
+  pub fn consumer_timed_out(&self, plc: EipAddr, connection_id: u32) -> Option<bool> {
+    self.connector().consumer_timed_out(plc, connection_id)
+  }
+
+  /*
+  Stops a consumer
+  Closes its connection if it's open, and stops any further attempts to open it.
+  Returns None if the consumer was already stopped.
+  */
+  pub fn stop_consumer(&mut self, handle: &ConsumerHandle) -> Option<()> {
+    let mut consumers = self.consumers.lock().unwrap();
+    let index = consumers.iter().position(|consumer| consumer.handle.id() == handle.id())?;
+    let consumer = consumers.remove(index);
+    drop(consumers);
+
+    let state = consumer.handle.state();
+    consumer.handle.set(ConsumerState::Stopped);
+    if let ConsumerState::Connected { connection_id } = state {
+      self.connector().close_consumer(handle.addr(), connection_id);
+    }
+
+    Some(())
+  }
+
+  /*
+  Stops a producer
+  connection_id is the O->T connection ID returned by add_producer or
+  add_io_connection.
+  */
+  pub fn stop_producer(&mut self, plc: EipAddr, connection_id: u32) -> Option<()> {
     let mut plcs = self.plcs.write().unwrap();
-    let mut con = plcs.get_mut(&plc)?
-      .consumers.remove(&to_connection_id)?;
-    con.stop();
+    let plc = plcs.get_mut(&plc)?;
+    let mut producer = plc.producers.remove(&connection_id)?;
+    producer.stop();
+
+    if let Some(triple) = producer.triple {
+      if let Err(e) = plc.forward_close(&triple, producer.route()) {
+        eprintln!("Forward close for {} failed: {}", connection_id, e);
+      }
+    }
 
     Some(())
   }
@@ -147,4 +433,259 @@ impl Service {
   pub fn stop(&mut self) {
     self.alive.store(false, Ordering::Release);
   }
-}
\ No newline at end of file
+}
+impl Service {
+  pub(crate) fn connector(&self) -> Connector {
+    Connector {
+      config: self.config.clone(),
+      plcs: Arc::clone(&self.plcs),
+      profiles: Arc::clone(&self.profiles),
+      cpsocket: Arc::clone(&self.cpsocket),
+      sequence_count: Arc::clone(&self.sequence_count),
+      recorder: self.recorder.clone(),
+    }
+  }
+}
+impl Default for Service {
+  fn default() -> Service {
+    Service::new()
+  }
+}
+
+/*
+What it takes to open and close a PLC's connections
+Shares the Service's PLCs, so the reconciler thread can open consumers while
+the Service is used from elsewhere.
+*/
+#[derive(Clone)]
+pub(crate) struct Connector {
+  config: ServiceConfig,
+  plcs: Arc<RwLock<HashMap<EipAddr, Plc>>>,
+  profiles: Arc<RwLock<HashMap<EipAddr, PlcProfile>>>,
+  cpsocket: Arc<Mutex<CPSocket>>,
+  sequence_count: Arc<AtomicU32>,
+  recorder: RecorderHandle,
+}
+impl Connector {
+  pub(crate) fn profile(&self, addr: EipAddr) -> PlcProfile {
+    self.profiles.read()
+      .expect("PLC profile Lock is poisened")
+      .get(&addr)
+      .copied()
+      .unwrap_or_default()
+  }
+
+  /*
+  Make sure there is a connected and registered Plc for this address
+  A Plc whose session was dropped is replaced, and its connections with it.
+  The PLC is connected to without holding the lock, so one that doesn't answer
+  doesn't hold up the others.
+  */
+  pub(crate) fn ensure_plc(&self, addr: EipAddr) -> Result<()> {
+    if self.plcs.read().expect("PLC HashMap Lock is poisened").get(&addr).is_some_and(|plc| plc.is_connected()) {
+      return Ok(());
+    }
+
+    let mut plc = Plc::new(addr, self.profile(addr))?;
+    plc.connect(&self.config, &self.recorder)?;
+    plc.register()?;
+
+    let mut plcs = self.plcs.write()
+      .expect("PLC HashMap Lock is poisened");
+    match plcs.entry(addr) {
+      Entry::Vacant(entry) => {
+        entry.insert(plc);
+      },
+      Entry::Occupied(mut entry) if !entry.get().is_connected() => {
+        entry.get_mut().stop_connections();
+        entry.insert(plc);
+      },
+      // Another thread got there first
+      Entry::Occupied(_) => (),
+    }
+
+    Ok(())
+  }
+
+  /*
+  Open a consumer's connection and start answering it
+  Returns the O->T connection ID.
+  */
+  pub(crate) fn open_consumer(&self, addr: EipAddr, hint: ConsumerHint, queue: &Arc<ConsumerQueue>) -> Result<u32> {
+    self.ensure_plc(addr)?;
+
+    // Get lock on plcs list
+    let mut plcs = self.plcs.write()
+      .expect("PLC HashMap Lock is poisened");
+
+    // Get PLC
+    let plc = plcs.get_mut(&addr).unwrap();
+
+    // Create consumer
+    let interface = plc.interface();
+    let (con, ot_connection_id) = plc.add_consumer(hint, queue)?;
+
+    // Multicast production only reaches us once we're in the group
+    if let Some(group) = con.to_multicast {
+      let joined = interface.and_then(|interface| self.cpsocket.lock().unwrap().join_multicast(group, interface));
+      if let Err(e) = joined {
+        plc.consumers.remove(&ot_connection_id);
+        return Err(e);
+      }
+    }
+
+    // Start keep alive response thread
+    plc.consumers[&ot_connection_id].start_response_thread(&self.cpsocket, addr, &self.sequence_count);
+
+    Ok(ot_connection_id)
+  }
+
+  /*
+  Close a consumer's connection
+  Returns None if there is no such consumer, as after its PLC was replaced.
+  */
+  pub(crate) fn close_consumer(&self, addr: EipAddr, connection_id: u32) -> Option<()> {
+    let mut plcs = self.plcs.write().unwrap();
+    let plc = plcs.get_mut(&addr)?;
+    let mut con = plc.consumers.remove(&connection_id)?;
+    con.stop();
+
+    // A PLC that dropped the session has forgotten the connection already
+    if let (Some(triple), true) = (con.triple, plc.is_connected()) {
+      if let Err(e) = plc.forward_close(&triple, con.route()) {
+        eprintln!("Forward close for {} failed: {}", connection_id, e);
+      }
+    }
+
+    if let (Some(group), Ok(interface)) = (con.to_multicast, plc.interface()) {
+      if let Err(e) = self.cpsocket.lock().unwrap().leave_multicast(group, interface) {
+        eprintln!("Couldn't leave multicast group {}: {}", group, e);
+      }
+    }
+
+    Some(())
+  }
+
+  pub(crate) fn consumer_timed_out(&self, addr: EipAddr, connection_id: u32) -> Option<bool> {
+    let plcs = self.plcs.read().unwrap();
+    plcs.get(&addr)?
+      .consumers
+      .get(&connection_id)
+      .map(|con| con.timed_out())
+  }
+}
+
+#[test]
+fn test_service_with_simulator() {
+  use std::net::{IpAddr, Ipv4Addr};
+  use std::time::Instant;
+  use crate::sim::Simulator;
+
+  let sim = Simulator::loopback(0).unwrap();
+  sim.add_tag("Count", 4);
+  sim.script("Count", Duration::from_millis(5), |tick, data| {
+    data.copy_from_slice(&(tick as u32).to_le_bytes());
+  }).unwrap();
+
+  let config = sim.service_config().unwrap();
+  let mut service = Service::with_config(config.clone());
+  service.start().unwrap();
+  assert_eq!(service.io_addr().unwrap().port(), config.io_port);
+
+  let addr = EipAddr { addr: IpAddr::V4(Ipv4Addr::LOCALHOST), slot: 0 };
+  assert_eq!(service.identity(addr).unwrap(), sim.identity());
+
+  let capture = std::env::temp_dir().join(format!("rconpro-test-service-{}", std::process::id()));
+  service.start_recording(Recorder::create(&capture, crate::CaptureFormat::Native).unwrap());
+
+  let queue = Arc::new(ConsumerQueue::new());
+  let handle = service.add_consumer(addr, sim.consumer_hint("Count", 10_000).unwrap(), &queue);
+  assert!(matches!(handle.wait(Duration::from_secs(2)), ConsumerState::Connected { .. }));
+  let connection_id = handle.connection_id().unwrap();
+
+  let deadline = Instant::now() + Duration::from_secs(2);
+  while queue.len() < 10 && Instant::now() < deadline {
+    thread::sleep(Duration::from_millis(10));
+  }
+  assert!(queue.len() >= 10);
+  assert_eq!(queue.pop().unwrap().len(), 4);
+  assert!(sim.heartbeats("Count") > 0);
+  assert_eq!(service.consumer_timed_out(addr, connection_id), Some(false));
+
+  // The Forward Open, its reply, production and heartbeats were all recorded
+  drop(service.stop_recording());
+  let records: Vec<_> = crate::CaptureReader::open(&capture).unwrap().collect::<Result<_>>().unwrap();
+  std::fs::remove_file(&capture).unwrap();
+  let count = |direction, traffic| records.iter().filter(|record| record.direction == direction && record.traffic == traffic).count();
+  assert_eq!(count(Direction::Sent, Traffic::Explicit), 1);
+  assert_eq!(count(Direction::Received, Traffic::Explicit), 1);
+  assert!(count(Direction::Received, Traffic::Io) >= 10);
+  assert!(count(Direction::Sent, Traffic::Io) > 0);
+  assert!(records.iter().all(|record| record.addr == addr));
+
+  service.stop_consumer(&handle).unwrap();
+  assert!(sim.connected_tags().is_empty());
+  assert_eq!(handle.state(), ConsumerState::Stopped);
+  assert!(service.stop_consumer(&handle).is_none());
+  service.stop();
+}
+
+#[test]
+fn test_service_io_connection_with_simulator() {
+  use std::net::{IpAddr, Ipv4Addr};
+  use std::time::Instant;
+  use crate::AssemblyPath;
+  use crate::sim::Simulator;
+
+  let sim = Simulator::loopback(0).unwrap();
+  let inputs_assembly = sim.add_assembly(100, 6);
+  let outputs_assembly = sim.add_assembly(150, 4);
+  inputs_assembly.set(&[1, 2, 3, 4, 5, 6]).unwrap();
+
+  let mut service = Service::with_config(sim.service_config().unwrap());
+  service.start().unwrap();
+  let addr = EipAddr { addr: IpAddr::V4(Ipv4Addr::LOCALHOST), slot: 0 };
+
+  let hint = ProducerHint {
+    tag: String::from("Adapter"),
+    data_size: 4,
+    input_size: 6,
+    rpi: 10_000,
+    torpi: 10_000,
+    run_idle: true,
+    assembly: Some(AssemblyPath { config: 1, output: 150, input: 100, config_data: vec![] }),
+    ..ProducerHint::default()
+  };
+  let outputs = Arc::new(OutputBuffer::new(4));
+  outputs.set(&[9, 8, 7, 6]).unwrap();
+  let inputs = Arc::new(ConsumerQueue::new());
+
+  // Input data needs somewhere to go
+  assert_eq!(service.add_producer(addr, hint.clone(), &outputs).unwrap_err().kind(), ErrorKind::InvalidInput);
+  let connection_id = service.add_io_connection(addr, hint, &outputs, &inputs).unwrap();
+  assert_eq!(sim.connected_tags(), vec![String::from("Assembly 100")]);
+
+  let deadline = Instant::now() + Duration::from_secs(2);
+  while (inputs.len() < 5 || outputs_assembly.get() != [9, 8, 7, 6]) && Instant::now() < deadline {
+    thread::sleep(Duration::from_millis(10));
+  }
+  assert!(inputs.len() >= 5);
+  assert_eq!(inputs.pop().unwrap(), vec![1, 2, 3, 4, 5, 6]);
+  assert_eq!(outputs_assembly.get(), vec![9, 8, 7, 6]);
+
+  // A size the adapter doesn't have is refused
+  let hint = ProducerHint {
+    tag: String::from("Adapter"),
+    data_size: 2,
+    input_size: 6,
+    rpi: 10_000,
+    torpi: 10_000,
+    assembly: Some(AssemblyPath { config: 1, output: 150, input: 100, config_data: vec![] }),
+    ..ProducerHint::default()
+  };
+  assert!(service.add_io_connection(addr, hint, &Arc::new(OutputBuffer::new(2)), &inputs).is_err());
+
+  service.stop_producer(addr, connection_id).unwrap();
+  assert!(sim.connected_tags().is_empty());
+  service.stop();
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::{ConsumerHint, Identity, OutputBuffer, ServiceConfig, Target, TargetConfig};
+pub use crate::Faults;
+
+/*
+A simulated PLC for integration tests
+It is a Target on the loopback interface with an identity, plus tags whose
+values can be set directly or scripted to change on their own. Tests open
+connections to it the same way they would to a real controller, and can check
+afterwards that heartbeats arrived. Faults can be injected per tag or per
+connection to make it misbehave on cue.
+*/
+pub struct Simulator {
+  target: Target,
+  originator_io_port: u16,
+  tags: Mutex<HashMap<String, Arc<OutputBuffer>>>,
+  scripts_alive: Arc<AtomicBool>,
+}
+impl Simulator {
+  pub fn new(config: TargetConfig) -> Simulator {
+    Simulator {
+      originator_io_port: config.originator_io_port,
+      target: Target::new(config),
+      tags: Mutex::new(HashMap::new()),
+      scripts_alive: Arc::new(AtomicBool::new(true)),
+    }
+  }
+
+  /*
+  A started simulator on 127.0.0.1 with ephemeral ports
+  T->O packets go to originator_io_port on the originator's side, or to a port
+  that was free just now if it's 0.
+  */
+  pub fn loopback(originator_io_port: u16) -> Result<Simulator> {
+    let originator_io_port = match originator_io_port {
+      0 => UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()?.port(),
+      port => port,
+    };
+
+    let mut sim = Simulator::new(TargetConfig {
+      bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+      encap_port: 0,
+      io_port: 0,
+      originator_io_port,
+      identity: Some(sim_identity()),
+      ..TargetConfig::default()
+    });
+    sim.start()?;
+
+    Ok(sim)
+  }
+
+  pub fn start(&mut self) -> Result<()> {
+    self.target.start()
+  }
+
+  /*
+  Add a produced tag of data_size bytes, initially zero
+  The returned buffer is the tag's value; setting it changes what's produced.
+  */
+  pub fn add_tag(&self, tag: &str, data_size: usize) -> Arc<OutputBuffer> {
+    let buffer = Arc::new(OutputBuffer::new(data_size));
+    self.target.publish(tag, &buffer);
+    self.tags.lock().unwrap().insert(String::from(tag), Arc::clone(&buffer));
+
+    buffer
+  }
+
+  /*
+  Add an assembly instance of data_size bytes, initially zero
+  Input assemblies are produced to the originator; output assemblies take
+  whatever an exclusive-owner connection sends, which the returned buffer shows.
+  */
+  pub fn add_assembly(&self, instance: u16, data_size: usize) -> Arc<OutputBuffer> {
+    let buffer = Arc::new(OutputBuffer::new(data_size));
+    self.target.publish_assembly(instance, &buffer);
+
+    buffer
+  }
+
+  pub fn remove_tag(&self, tag: &str) {
+    self.tags.lock().unwrap().remove(tag);
+    self.target.unpublish(tag);
+  }
+
+  /*
+  Set a tag's value
+  */
+  pub fn set(&self, tag: &str, data: &[u8]) -> Result<()> {
+    self.buffer(tag)?.set(data)
+  }
+
+  pub fn get(&self, tag: &str) -> Result<Vec<u8>> {
+    Ok(self.buffer(tag)?.get())
+  }
+
+  /*
+  Change a tag's value every period
+  The script gets the number of times it has run and the value to change.
+  Scripts run until the simulator is stopped.
+  */
+  pub fn script<F>(&self, tag: &str, period: Duration, mut script: F) -> Result<()>
+  where F: FnMut(u64, &mut [u8]) + Send + 'static {
+    let buffer = self.buffer(tag)?;
+    let alive = Arc::clone(&self.scripts_alive);
+
+    thread::Builder::new().name(format!("Simulator script for {}", tag)).spawn(move || {
+      let mut tick = 0;
+      while alive.load(Ordering::Relaxed) {
+        buffer.update(|data| script(tick, data));
+        tick += 1;
+        thread::sleep(period);
+      }
+    })?;
+
+    Ok(())
+  }
+
+  /*
+  A ConsumerHint for one of the simulator's tags
+  The data size includes the CIP sequence count, as the Forward Open needs.
+  */
+  pub fn consumer_hint(&self, tag: &str, rpi: usize) -> Result<ConsumerHint> {
+    Ok(ConsumerHint {
+      tag: String::from(tag),
+      data_size: 2 + self.buffer(tag)?.get().len(),
+      rpi,
+      otrpi: rpi,
+      ..ConsumerHint::default()
+    })
+  }
+
+  /*
+  Make every connection to a tag misbehave, including ones opened later
+  */
+  pub fn inject(&self, tag: &str, faults: Faults) {
+    self.target.set_faults(tag, faults);
+  }
+
+  /*
+  Make one connection misbehave, by its O->T connection ID
+  */
+  pub fn inject_connection(&self, ot_connection_id: u32, faults: Faults) {
+    self.target.set_connection_faults(ot_connection_id, faults);
+  }
+
+  pub fn clear_faults(&self) {
+    self.target.clear_faults();
+  }
+
+  /*
+  Drop every TCP session now, as if the PLC had reset
+  */
+  pub fn reset_sessions(&self) {
+    self.target.reset_sessions();
+  }
+
+  pub fn identity(&self) -> Identity {
+    sim_identity()
+  }
+
+  /*
+  The address sessions are opened on
+  */
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.target.local_addr()
+  }
+
+  /*
+  The address O->T heartbeats are sent to
+  */
+  pub fn io_addr(&self) -> Option<SocketAddr> {
+    self.target.io_addr()
+  }
+
+  /*
+  A ServiceConfig for talking to the simulator over loopback, once it's started
+  */
+  pub fn service_config(&self) -> Option<ServiceConfig> {
+    Some(ServiceConfig {
+      bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+      io_port: self.originator_io_port,
+      encap_port: self.local_addr()?.port(),
+      remote_io_port: self.io_addr()?.port(),
+      ..ServiceConfig::default()
+    })
+  }
+
+  /*
+  The tags with open connections, one entry per connection
+  */
+  pub fn connected_tags(&self) -> Vec<String> {
+    self.target.connected_tags()
+  }
+
+  pub fn heartbeats(&self, tag: &str) -> u64 {
+    self.target.heartbeats(tag)
+  }
+
+  /*
+  How many connections were dropped because their heartbeats stopped
+  */
+  pub fn timeouts(&self) -> u64 {
+    self.target.timeouts()
+  }
+
+  pub fn stop(&mut self) {
+    self.scripts_alive.store(false, Ordering::Release);
+    self.target.stop();
+  }
+
+  fn buffer(&self, tag: &str) -> Result<Arc<OutputBuffer>> {
+    self.tags.lock().unwrap()
+      .get(tag)
+      .cloned()
+      .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the simulator has no tag {:?}", tag)))
+  }
+}
+impl Drop for Simulator {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+fn sim_identity() -> Identity {
+  Identity {
+    protocol_version: 1,
+    socket_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 44818),
+    vendor_id: 1,
+    device_type: 14,
+    product_code: 0xFFFF,
+    major_revision: 1,
+    minor_revision: 0,
+    status: 0,
+    serial_number: 0x5151_5151,
+    product_name: String::from("rconpro simulator"),
+    state: 3,
+  }
+}
+
+#[test]
+fn test_simulator_scripted_tag() {
+  use std::io::Write;
+  use std::net::{TcpStream, UdpSocket};
+  use byteorder::{ByteOrder, LittleEndian};
+  use crate::{eip, identity, CipRoute};
+  use crate::sockets::read_frame;
+
+  let originator = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  originator.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  let sim = Simulator::loopback(originator.local_addr().unwrap().port()).unwrap();
+
+  sim.add_tag("Count", 4);
+  sim.script("Count", Duration::from_millis(5), |tick, data| {
+    data.copy_from_slice(&(tick as u32).to_le_bytes());
+  }).unwrap();
+
+  let mut stream = TcpStream::connect(sim.local_addr().unwrap()).unwrap();
+  let mut send = |msg: &[u8]| {
+    stream.write_all(msg).unwrap();
+    read_frame(&mut stream).unwrap()
+  };
+
+  let identities = identity::parse_list_identity_reply(&send(&identity::build_list_identity(1))).unwrap();
+  assert_eq!(identities, vec![sim.identity()]);
+
+  let session_handle = LittleEndian::read_u32(&send(&eip::build_register_session())[4..8]);
+  let hint = sim.consumer_hint("Count", 10_000).unwrap();
+  let msg = eip::build_forward_open_packet(&CipRoute::backplane(0), session_handle, 2, &hint.forward_open_spec()).unwrap();
+  let reply = eip::parse_forward_open_reply(&send(&msg)).unwrap();
+
+  // The value keeps counting while we heartbeat
+  let mut last = None;
+  for sequence in 0..20 {
+    originator.send_to(&eip::build_response_packet(reply.ot_connection_id, sequence), sim.io_addr().unwrap()).unwrap();
+
+    let mut buf = [0u8; 64];
+    let (size, _) = originator.recv_from(&mut buf).unwrap();
+    assert_eq!(size, 24);
+    last = Some(LittleEndian::read_u32(&buf[20..24]));
+  }
+  assert!(last.unwrap() > 0);
+  assert!(sim.heartbeats("Count") > 0);
+
+  // Without heartbeats the connection times out
+  thread::sleep(Duration::from_millis(300));
+  assert!(sim.connected_tags().is_empty());
+  assert_eq!(sim.timeouts(), 1);
+}
+
+#[test]
+fn test_simulator_faults() {
+  use std::io::{Read, Write};
+  use std::net::{TcpStream, UdpSocket};
+  use std::time::Instant;
+  use byteorder::{ByteOrder, LittleEndian};
+  use crate::{eip, CipError, CipRoute};
+  use crate::sockets::read_frame;
+
+  let originator = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  originator.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  let sim = Simulator::loopback(originator.local_addr().unwrap().port()).unwrap();
+  sim.add_tag("Good", 2);
+  sim.add_tag("Bad", 2);
+  sim.inject("Bad", Faults {
+    reject_forward_open: Some((0x01, vec![0x0113])),
+    ..Faults::default()
+  });
+
+  let mut stream = TcpStream::connect(sim.local_addr().unwrap()).unwrap();
+  let mut send = |msg: &[u8]| {
+    stream.write_all(msg).unwrap();
+    read_frame(&mut stream).unwrap()
+  };
+  let session_handle = LittleEndian::read_u32(&send(&eip::build_register_session())[4..8]);
+
+  let hint = sim.consumer_hint("Bad", 5_000).unwrap();
+  let msg = eip::build_forward_open_packet(&CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+  let error = eip::parse_forward_open_reply(&send(&msg)).unwrap_err();
+  assert_eq!(CipError::from_io(&error).unwrap().extended(), Some(0x0113));
+
+  // Packets on one connection, with their sequence counts and when they came
+  let mut buf = [0u8; 64];
+  let receive = |buf: &mut [u8], to_connection_id: u32| loop {
+    let (len, _) = originator.recv_from(buf).unwrap();
+    if LittleEndian::read_u32(&buf[6..10]) == to_connection_id {
+      return (len, LittleEndian::read_u32(&buf[10..14]), Instant::now());
+    }
+  };
+  let mut open = |tag: &str, context: u64| {
+    let hint = ConsumerHint {
+      otrpi: 500_000,
+      ..sim.consumer_hint(tag, 10_000).unwrap()
+    };
+    let msg = eip::build_forward_open_packet(&CipRoute::backplane(0), session_handle, context, &hint.forward_open_spec()).unwrap();
+    let reply = eip::parse_forward_open_reply(&send(&msg)).unwrap();
+    originator.send_to(&eip::build_response_packet(reply.ot_connection_id, 0), sim.io_addr().unwrap()).unwrap();
+    reply
+  };
+
+  // Reordered packets come in swapped pairs
+  sim.add_tag("Shuffled", 2);
+  sim.inject("Shuffled", Faults { reorder: true, ..Faults::default() });
+  let shuffled = open("Shuffled", 3);
+  let sequences: Vec<u32> = (0..4).map(|_| receive(&mut buf, shuffled.to_connection_id).1).collect();
+  let first = sequences[1];
+  assert_eq!(sequences, vec![first + 1, first, first + 3, first + 2]);
+
+  // Jitter delays each packet without holding up the ones after it, so 20
+  // packets at a 10ms RPI take about 200ms however much each one is delayed
+  sim.add_tag("Jittery", 2);
+  sim.inject("Jittery", Faults {
+    jitter: Some(Duration::from_millis(40)),
+    jitter_seed: 7,
+    ..Faults::default()
+  });
+  let jittery = open("Jittery", 4);
+  let (_, _, started) = receive(&mut buf, jittery.to_connection_id);
+  let mut arrived = vec![];
+  for _ in 1..20 {
+    arrived.push(receive(&mut buf, jittery.to_connection_id).2);
+  }
+  let elapsed = arrived.last().unwrap().duration_since(started);
+  assert!(elapsed < Duration::from_millis(190 + 40 + 100), "20 packets took {:?}", elapsed);
+
+  for reply in [shuffled, jittery] {
+    send(&eip::build_forward_close_packet(&CipRoute::backplane(0), session_handle, 5, &reply.triple()));
+  }
+
+  // Drop every other packet and truncate the rest, then reset the session
+  sim.inject("Good", Faults {
+    drop_every: Some(2),
+    truncate: Some(18),
+    reset_session_after: Some(6),
+    ..Faults::default()
+  });
+  let hint = ConsumerHint {
+    otrpi: 50_000,
+    ..sim.consumer_hint("Good", 5_000).unwrap()
+  };
+  let msg = eip::build_forward_open_packet(&CipRoute::backplane(0), session_handle, 2, &hint.forward_open_spec()).unwrap();
+  let reply = eip::parse_forward_open_reply(&send(&msg)).unwrap();
+  let heartbeat = |sequence| {
+    originator.send_to(&eip::build_response_packet(reply.ot_connection_id, sequence), sim.io_addr().unwrap()).unwrap();
+  };
+  heartbeat(0);
+
+  let (first, first_sequence, _) = receive(&mut buf, reply.to_connection_id);
+  let (second, second_sequence, _) = receive(&mut buf, reply.to_connection_id);
+  assert_eq!((first, second), (18, 18));
+  assert_eq!(second_sequence, first_sequence + 2);
+
+  stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+  // Ignored heartbeats let the connection time out
+  sim.inject_connection(reply.ot_connection_id, Faults {
+    ignore_heartbeats: true,
+    ..Faults::default()
+  });
+  let heard = sim.heartbeats("Good");
+  for sequence in 1..40 {
+    heartbeat(sequence);
+    thread::sleep(Duration::from_millis(10));
+  }
+  assert!(sim.connected_tags().is_empty());
+  assert_eq!(sim.heartbeats("Good"), heard);
+  assert_eq!(sim.timeouts(), 1);
+}
@@ -1,10 +1,18 @@
-use std::net::{TcpStream, UdpSocket, IpAddr, SocketAddr};
-use std::io::{Read, Write, Result, Cursor};
+use std::net::{TcpStream, UdpSocket, IpAddr, Ipv4Addr, SocketAddr, Shutdown};
+use std::io::{Read, Write, Result, Error, ErrorKind};
 use std::time::Duration;
 use std::hash::Hash;
 use std::cmp::Eq;
-use byteorder::{ReadBytesExt, LittleEndian};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use byteorder::{ByteOrder, LittleEndian};
+use crossbeam::channel::{bounded, Sender, RecvTimeoutError};
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
+
+use crate::capture::{Direction, RecorderHandle, Traffic};
 
 // CIP/EIP protocol constants
 const SETUP_PORT: u16 = 44818;
@@ -15,6 +23,9 @@ const HEADER_SIZE: usize = 24;
 // Buf size (arbitrary)
 const BUF_SIZE: usize = 4096;
 
+// How long to wait for the reply to an explicit request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
 
 
 /*
@@ -35,67 +46,300 @@ pub struct EipAddr {
 
 
 
+/*
+Where a Service's sockets bind and which ports they talk to
+bind_addr pins both the setup streams and the I/O socket to one interface; the
+unspecified address leaves it to the OS. io_port is the local UDP port for
+class 1 packets, 0 for any free one. Devices send point-to-point T->O packets to
+2222 on the originator, so only move it when the targets are told where to send
+(as a Simulator can be). encap_port and remote_io_port are the ports on the
+PLCs. Buffer sizes left as None keep the OS defaults. A PLC that hasn't
+accepted the setup stream after connect_timeout milliseconds counts as offline.
+Consumers that can't be opened are retried after retry_min milliseconds,
+doubling up to retry_max.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+  pub bind_addr: IpAddr,
+  pub io_port: u16,
+  pub encap_port: u16,
+  pub remote_io_port: u16,
+  pub recv_buffer_size: Option<usize>,
+  pub send_buffer_size: Option<usize>,
+  pub connect_timeout: u64,
+  pub retry_min: u64,
+  pub retry_max: u64,
+}
+impl ServiceConfig {
+
+  /*
+  A socket with the configured buffer sizes, bound to local if given
+  */
+  fn socket(&self, ty: Type, domain: Domain, local: Option<SocketAddr>) -> Result<Socket> {
+    let socket = Socket::new(domain, ty, None)?;
+    if let Some(size) = self.recv_buffer_size {
+      socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = self.send_buffer_size {
+      socket.set_send_buffer_size(size)?;
+    }
+    if let Some(local) = local {
+      socket.bind(&local.into())?;
+    }
+
+    Ok(socket)
+  }
+}
+impl Default for ServiceConfig {
+  fn default() -> ServiceConfig {
+    ServiceConfig {
+      bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      io_port: CONPRO_PORT,
+      encap_port: SETUP_PORT,
+      remote_io_port: CONPRO_PORT,
+      recv_buffer_size: None,
+      send_buffer_size: None,
+      connect_timeout: 5_000,
+      retry_min: 500,
+      retry_max: 30_000,
+    }
+  }
+}
+
+
+
 /*
 The SetupStream struct contains a TcpStream and some methods that use that stream
 to set up a consumer/producer connection
+
+Every request carries a sender context (bytes 12..20 of the encapsulation header),
+and the target echoes it back in the reply. A reader thread pulls whole frames off
+the stream and hands each one to whoever is waiting on that context, so several
+requests can be in flight at once without holding a lock across the round trip.
 */
 pub(crate) struct SetupStream {
-  stream: Option<TcpStream>,
+  stream: Mutex<Option<TcpStream>>,
+  peer: Option<EipAddr>,
+  recorder: RecorderHandle,
+  pending: Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>,
+  next_context: AtomicU64,
+  closed: Arc<AtomicBool>,
 }
 impl SetupStream {
 
   /*
   Creates a new instance
-  This actually returns a result because the connection could fail; make sure that
-  result is unwrapped.
+  The stream isn't connected until connect is called.
   */
   pub(crate) fn new() -> SetupStream {
     SetupStream {
-      stream: None
+      stream: Mutex::new(None),
+      peer: None,
+      recorder: RecorderHandle::default(),
+      pending: Arc::new(Mutex::new(HashMap::new())),
+      next_context: AtomicU64::new(1),
+      closed: Arc::new(AtomicBool::new(false)),
     }
   }
 
-  pub(crate) fn connect(&mut self, host: &EipAddr) -> Result<()> {
-    // Try to connect
-    let socket_addr = SocketAddr::new(host.addr, SETUP_PORT);
-    self.stream = Some(TcpStream::connect(socket_addr)?);
+  /*
+  Connect to a host, recording the explicit messages to and from it if the
+  recorder is set
+  */
+  pub(crate) fn connect(&mut self, host: &EipAddr, config: &ServiceConfig, recorder: &RecorderHandle) -> Result<()> {
+    self.peer = Some(*host);
+    self.recorder = recorder.clone();
+
+    // Leave the local port to the OS, and the interface too unless it's pinned
+    let socket_addr = SocketAddr::new(host.addr, config.encap_port);
+    let local = Some(SocketAddr::new(config.bind_addr, 0)).filter(|local| !local.ip().is_unspecified());
+    let socket = config.socket(Type::STREAM, Domain::for_address(socket_addr), local)?;
+
+    // Try to connect, giving up well before the OS would
+    socket.connect_timeout(&socket_addr.into(), Duration::from_millis(config.connect_timeout))?;
+    self.attach(socket.into())
+  }
+
+  /*
+  Use an already connected stream and start the reader thread on a clone of it
+  */
+  fn attach(&mut self, stream: TcpStream) -> Result<()> {
+    let reader = stream.try_clone()?;
+    let pending = Arc::clone(&self.pending);
+    let closed = Arc::clone(&self.closed);
+    thread::Builder::new().name(format!("Setup stream reader for {}", stream.peer_addr()?)).spawn(move || {
+      read_replies(reader, pending);
+      closed.store(true, Ordering::Release);
+    })?;
+
+    *self.stream.lock().unwrap() = Some(stream);
 
     Ok(())
   }
 
+  /*
+  The local address of the stream
+  Its IP is the interface we reach this PLC on, which is also the one to join
+  its multicast groups on.
+  */
+  pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+    match self.stream.lock().unwrap().as_ref() {
+      Some(stream) => stream.local_addr(),
+      None => Err(Error::new(ErrorKind::NotConnected, "setup stream is not connected")),
+    }
+  }
+
+  /*
+  True from connect until the PLC closes the stream (or it breaks)
+  */
+  pub(crate) fn is_connected(&self) -> bool {
+    self.stream.lock().unwrap().is_some() && !self.closed.load(Ordering::Acquire)
+  }
+
+  /*
+  Get a sender context that no other request on this stream is using
+  Context 0 is left for RegisterSession, which is always the first request.
+  */
+  pub(crate) fn next_context(&self) -> u64 {
+    self.next_context.fetch_add(1, Ordering::Relaxed)
+  }
+
   /*
   Send a msg to the host and get the reply
-  This function hang until all data is recieved.
+  The sender context is read from the msg header; this function hangs until the
+  reply with the same context arrives (or REPLY_TIMEOUT runs out).
   */
-  pub(crate) fn send_recieve(&mut self, msg: &[u8]) -> Result<Vec<u8>> {
-    // Send the message
-    self.stream.as_ref().unwrap().write(msg)
-      .expect("Couldn't send data");
-  
-    // Get the response
-    // Set up vars
-    let mut response = vec![];
-    let mut buf = [0 as u8; BUF_SIZE];
+  pub(crate) fn send_recieve(&self, msg: &[u8]) -> Result<Vec<u8>> {
+    if msg.len() < HEADER_SIZE {
+      return Err(Error::new(ErrorKind::InvalidInput, "message is shorter than an encapsulation header"));
+    }
+    let context = LittleEndian::read_u64(&msg[12..20]);
 
-    // Get first data packet
-    let size = self.stream.as_ref().unwrap().read(&mut buf)?;
-    response.extend_from_slice(&buf[0..size]);
+    // Register interest in the reply before sending
+    let (tx, rx) = bounded(1);
+    {
+      let mut pending = self.pending.lock().unwrap();
+      if pending.contains_key(&context) {
+        return Err(Error::new(ErrorKind::AlreadyExists, "sender context is already in flight"));
+      }
+      pending.insert(context, tx);
+    }
+
+    // Send the message
+    self.record(Direction::Sent, msg);
+    let sent = match self.stream.lock().unwrap().as_mut() {
+      Some(stream) => stream.write_all(msg),
+      None => Err(Error::new(ErrorKind::NotConnected, "setup stream is not connected")),
+    };
+    if let Err(e) = sent {
+      self.pending.lock().unwrap().remove(&context);
+      return Err(e);
+    }
 
-    // Parse with file cursor
-    let mut cursor = Cursor::new(&response);
+    // Wait for the reader thread to hand over the reply
+    match rx.recv_timeout(REPLY_TIMEOUT) {
+      Ok(reply) => {
+        self.record(Direction::Received, &reply);
+        Ok(reply)
+      },
+      Err(RecvTimeoutError::Timeout) => {
+        self.pending.lock().unwrap().remove(&context);
+        Err(Error::new(ErrorKind::TimedOut, "no reply for sender context"))
+      },
+      Err(RecvTimeoutError::Disconnected) => {
+        Err(Error::new(ErrorKind::ConnectionAborted, "setup stream closed before the reply arrived"))
+      }
+    }
+  }
 
-    // Get the size of the entire method (a field of the protocol)
-    cursor.set_position(2);
-    let data_len: usize = cursor.read_u16::<LittleEndian>().unwrap().into();
-    println!("Message size: {:}", data_len);
+  fn record(&self, direction: Direction, msg: &[u8]) {
+    if let Some(peer) = self.peer {
+      self.recorder.record(direction, Traffic::Explicit, peer, msg);
+    }
+  }
+}
+impl Drop for SetupStream {
+  fn drop(&mut self) {
+    // Shutting down the stream also stops the reader thread
+    if let Some(stream) = self.stream.lock().unwrap().take() {
+      let _ = stream.shutdown(Shutdown::Both);
+    }
+  }
+}
 
-    // Get the rest of the message
-    while response.len() - HEADER_SIZE < data_len {
-      let size = self.stream.as_ref().unwrap().read(&mut buf)?;
-      response.extend_from_slice( &buf[0..size] );
+/*
+Read encapsulation frames until the stream closes
+Each frame goes to the request waiting on its sender context. Once the stream
+is gone, every waiting request is dropped so it fails instead of hanging.
+*/
+fn read_replies(mut stream: TcpStream, pending: Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>) {
+  loop {
+    match read_frame(&mut stream) {
+      Ok(frame) => {
+        let context = LittleEndian::read_u64(&frame[12..20]);
+        match pending.lock().unwrap().remove(&context) {
+          Some(tx) => { let _ = tx.send(frame); },
+          None => eprintln!("Dropping reply for unknown sender context {:#x}", context),
+        }
+      },
+      Err(_) => {
+        pending.lock().unwrap().clear();
+        return;
+      }
     }
+  }
+}
+
+/*
+Read one whole encapsulation frame (header plus data)
+*/
+pub(crate) fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+  let mut frame = vec![0; HEADER_SIZE];
+  stream.read_exact(&mut frame)?;
+
+  // Get the size of the rest of the message (a field of the protocol)
+  let data_len: usize = LittleEndian::read_u16(&frame[2..4]).into();
+  frame.resize(HEADER_SIZE + data_len, 0);
+  stream.read_exact(&mut frame[HEADER_SIZE..])?;
+
+  Ok(frame)
+}
+
+#[test]
+fn test_setup_stream_out_of_order_replies() {
+  use std::net::TcpListener;
+
+  let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let port = listener.local_addr().unwrap().port();
+
+  // Answer two requests in reverse order
+  thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let first = read_frame(&mut stream).unwrap();
+    let second = read_frame(&mut stream).unwrap();
+    stream.write_all(&second).unwrap();
+    stream.write_all(&first).unwrap();
+  });
+
+  let mut setup_stream = SetupStream::new();
+  setup_stream.attach(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap()).unwrap();
+
+  let setup_stream = Arc::new(setup_stream);
+  let handles: Vec<_> = (0..2).map(|i| {
+    let setup_stream = Arc::clone(&setup_stream);
+    thread::spawn(move || {
+      let mut msg = vec![0; HEADER_SIZE + 1];
+      msg[2] = 1;
+      msg[12..20].copy_from_slice(&setup_stream.next_context().to_le_bytes());
+      msg[HEADER_SIZE] = i;
+      let reply = setup_stream.send_recieve(&msg).unwrap();
+      assert_eq!(reply, msg);
+    })
+  }).collect();
 
-    Ok(response)
+  for handle in handles {
+    handle.join().unwrap();
   }
 }
 
@@ -104,6 +348,9 @@ A struct for recieving producer data and sending keep alive packets
 */
 pub struct CPSocket {
   socket: Option<UdpSocket>,
+  remote_port: u16,
+  groups: HashMap<(Ipv4Addr, Ipv4Addr), usize>,
+  pub(crate) recorder: RecorderHandle,
 }
 impl CPSocket {
 
@@ -114,15 +361,61 @@ impl CPSocket {
   */
   pub fn new() -> CPSocket {
     CPSocket {
-      socket: None
+      socket: None,
+      remote_port: CONPRO_PORT,
+      groups: HashMap::new(),
+      recorder: RecorderHandle::default(),
     }
   }
 
-  pub fn bind(&mut self, timeout: Duration) -> Result<()> {
+  pub fn bind(&mut self, config: &ServiceConfig, timeout: Duration) -> Result<()> {
     // Create, bind socket and set timeout
-    self.socket = Some( UdpSocket::bind(("0.0.0.0", CONPRO_PORT))? );
-    self.socket.as_ref().unwrap().set_read_timeout(Some(timeout)).unwrap();
-    
+    let local = SocketAddr::new(config.bind_addr, config.io_port);
+    let socket: UdpSocket = config.socket(Type::DGRAM, Domain::for_address(local), Some(local))?.into();
+    socket.set_read_timeout(Some(timeout))?;
+    self.socket = Some(socket);
+    self.remote_port = config.remote_io_port;
+
+    Ok(())
+  }
+
+  /*
+  The address the socket is bound to
+  */
+  pub fn local_addr(&self) -> Result<SocketAddr> {
+    match self.socket.as_ref() {
+      Some(socket) => socket.local_addr(),
+      None => Err(Error::new(ErrorKind::NotConnected, "I/O socket is not bound")),
+    }
+  }
+
+  /*
+  Join a multicast group on an interface
+  Several consumers can share a group, so joins are counted and only the first
+  one actually joins.
+  */
+  pub fn join_multicast(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+    let count = self.groups.entry((group, interface)).or_insert(0);
+    if *count == 0 {
+      self.socket.as_ref().unwrap().join_multicast_v4(&group, &interface)?;
+    }
+    *count += 1;
+
+    Ok(())
+  }
+
+  /*
+  Leave a multicast group once the last consumer using it is gone
+  */
+  pub fn leave_multicast(&mut self, group: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+    if let Some(count) = self.groups.get_mut(&(group, interface)) {
+      *count -= 1;
+      if *count == 0 {
+        self.groups.remove(&(group, interface));
+        self.socket.as_ref().unwrap().leave_multicast_v4(&group, &interface)?;
+      }
+    }
+
     Ok(())
   }
 
@@ -131,7 +424,8 @@ impl CPSocket {
   This is used to send keep-alive packets
   */
   pub fn send_to(&self, msg: &[u8], host: &EipAddr) -> Result<()> {
-    self.socket.as_ref().unwrap().send_to(msg, SocketAddr::new(host.addr, 2222))?;
+    self.recorder.record(Direction::Sent, Traffic::Io, *host, msg);
+    self.socket.as_ref().unwrap().send_to(msg, SocketAddr::new(host.addr, self.remote_port))?;
     Ok(())
   }
 
@@ -142,7 +436,7 @@ impl CPSocket {
   */
   pub fn recieve(&mut self) -> Result<(Vec<u8>, EipAddr)> {
     let mut response = vec![];
-    let mut buf = [0 as u8; BUF_SIZE];
+    let mut buf = [0u8; BUF_SIZE];
     
     // Get data
     let (size, src) = self.socket.as_ref().unwrap().recv_from(&mut buf)?;
@@ -154,4 +448,9 @@ impl CPSocket {
       slot: 0
     }))
   }
-}
\ No newline at end of file
+}
+impl Default for CPSocket {
+  fn default() -> CPSocket {
+    CPSocket::new()
+  }
+}
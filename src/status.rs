@@ -0,0 +1,367 @@
+use std::fmt;
+use std::io;
+
+/*
+A CIP error reply
+general_status is the general status byte of the reply and extended_status
+holds the additional status words, if there were any.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CipError {
+  pub general_status: u8,
+  pub extended_status: Vec<u16>,
+}
+impl CipError {
+  pub fn new(general_status: u8, extended_status: Vec<u16>) -> CipError {
+    CipError {
+      general_status,
+      extended_status,
+    }
+  }
+
+  /*
+  Parse the status out of a CIP reply
+  Returns None if the reply was a success.
+  */
+  pub fn from_reply(reply: &[u8]) -> Option<CipError> {
+    if reply.len() < 4 || reply[2] == 0 {
+      return None;
+    }
+
+    let words = usize::from(reply[3]);
+    let extended_status = reply[4..]
+      .chunks_exact(2)
+      .take(words)
+      .map(|word| u16::from_le_bytes([word[0], word[1]]))
+      .collect();
+
+    Some(CipError::new(reply[2], extended_status))
+  }
+
+  /*
+  Get the CipError back out of an io::Error, if that's what it is
+  */
+  pub fn from_io(error: &io::Error) -> Option<&CipError> {
+    error.get_ref()?.downcast_ref::<CipError>()
+  }
+
+  /*
+  The first extended status word (the one that matters for Connection Manager errors)
+  */
+  pub fn extended(&self) -> Option<u16> {
+    self.extended_status.first().copied()
+  }
+
+  /*
+  A listen-only connection was refused because nobody owns the connection it
+  would listen to
+  */
+  pub fn is_owner_not_found(&self) -> bool {
+    self.general_status == 0x01 && self.extended() == Some(0x0119)
+  }
+
+  pub fn general_description(&self) -> &'static str {
+    general_status_description(self.general_status)
+  }
+
+  pub fn extended_description(&self) -> Option<&'static str> {
+    if self.general_status == 0x01 {
+      self.extended().and_then(connection_manager_status_description)
+    } else {
+      None
+    }
+  }
+}
+impl fmt::Display for CipError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "general status {:#04x} ({})", self.general_status, self.general_description())?;
+
+    if let Some(extended) = self.extended() {
+      write!(f, ", extended status {:#06x}", extended)?;
+      if let Some(description) = self.extended_description() {
+        write!(f, " ({})", description)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+impl std::error::Error for CipError {}
+impl From<CipError> for io::Error {
+  fn from(error: CipError) -> io::Error {
+    let kind = if error.is_owner_not_found() {
+      io::ErrorKind::NotFound
+    } else {
+      io::ErrorKind::Other
+    };
+
+    io::Error::new(kind, error)
+  }
+}
+
+/*
+Why an Unconnected Send couldn't be delivered
+These come from the Connection Manager of a module along the route, not from
+the device at the end of it.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingErrorKind {
+  // 0x0204: nothing answered in time, e.g. an unreachable IP along the route
+  Timeout,
+  // 0x0311: the module has no such port
+  PortNotAvailable,
+  // 0x0312: nothing at that link address, e.g. no module in the slot
+  LinkAddressNotValid,
+  // 0x0315: the route has a segment the module doesn't understand
+  InvalidSegment,
+  // Anything else the Connection Manager rejected
+  Other,
+}
+
+/*
+An Unconnected Send that failed along the route
+remaining_path_size is how many words of the route were left when it failed,
+which says how far the request got.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingError {
+  pub kind: RoutingErrorKind,
+  pub error: CipError,
+  pub remaining_path_size: Option<u8>,
+}
+impl RoutingError {
+  pub fn new(error: CipError, remaining_path_size: Option<u8>) -> RoutingError {
+    let kind = match (error.general_status, error.extended()) {
+      (0x01, Some(0x0204)) => RoutingErrorKind::Timeout,
+      (0x01, Some(0x0311)) => RoutingErrorKind::PortNotAvailable,
+      (0x01, Some(0x0312)) => RoutingErrorKind::LinkAddressNotValid,
+      (0x01, Some(0x0315)) => RoutingErrorKind::InvalidSegment,
+      _ => RoutingErrorKind::Other,
+    };
+
+    RoutingError {
+      kind,
+      error,
+      remaining_path_size,
+    }
+  }
+
+  /*
+  Get the RoutingError back out of an io::Error, if that's what it is
+  */
+  pub fn from_io(error: &io::Error) -> Option<&RoutingError> {
+    error.get_ref()?.downcast_ref::<RoutingError>()
+  }
+}
+impl fmt::Display for RoutingError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let description = match self.kind {
+      RoutingErrorKind::Timeout => "unconnected send timed out",
+      RoutingErrorKind::PortNotAvailable => "port not available",
+      RoutingErrorKind::LinkAddressNotValid => "link address not valid (no module in slot?)",
+      RoutingErrorKind::InvalidSegment => "invalid segment in route",
+      RoutingErrorKind::Other => "routing failed",
+    };
+    write!(f, "{}: {}", description, self.error)?;
+
+    if let Some(remaining) = self.remaining_path_size {
+      write!(f, " with {} route words left", remaining)?;
+    }
+
+    Ok(())
+  }
+}
+impl std::error::Error for RoutingError {}
+impl From<RoutingError> for io::Error {
+  fn from(error: RoutingError) -> io::Error {
+    let kind = match error.kind {
+      RoutingErrorKind::Timeout => io::ErrorKind::TimedOut,
+      RoutingErrorKind::PortNotAvailable | RoutingErrorKind::LinkAddressNotValid => io::ErrorKind::NotFound,
+      RoutingErrorKind::InvalidSegment => io::ErrorKind::InvalidInput,
+      RoutingErrorKind::Other => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, error)
+  }
+}
+
+/*
+A PCCC error status from an SLC, MicroLogix or PLC-5
+status is the STS byte of the PCCC reply; extended_status is the EXT STS byte
+that follows when STS is 0xF0.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcccError {
+  pub status: u8,
+  pub extended_status: Option<u8>,
+}
+impl PcccError {
+  pub fn new(status: u8, extended_status: Option<u8>) -> PcccError {
+    PcccError {
+      status,
+      extended_status,
+    }
+  }
+
+  /*
+  Get the PcccError back out of an io::Error, if that's what it is
+  */
+  pub fn from_io(error: &io::Error) -> Option<&PcccError> {
+    error.get_ref()?.downcast_ref::<PcccError>()
+  }
+
+  pub fn description(&self) -> &'static str {
+    match (self.status, self.extended_status) {
+      (0xF0, Some(extended)) => pccc_extended_status_description(extended),
+      (status, _) => pccc_status_description(status),
+    }
+  }
+}
+impl fmt::Display for PcccError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "PCCC status {:#04x}", self.status)?;
+    if let Some(extended) = self.extended_status {
+      write!(f, ", extended status {:#04x}", extended)?;
+    }
+
+    write!(f, " ({})", self.description())
+  }
+}
+impl std::error::Error for PcccError {}
+impl From<PcccError> for io::Error {
+  fn from(error: PcccError) -> io::Error {
+    io::Error::other(error)
+  }
+}
+
+fn pccc_status_description(status: u8) -> &'static str {
+  match status {
+    0x10 => "Illegal command or format",
+    0x20 => "Host has a problem and will not communicate",
+    0x30 => "Remote node host is missing, disconnected or shut down",
+    0x40 => "Host could not complete function due to hardware fault",
+    0x50 => "Addressing problem or memory protect rungs",
+    0x60 => "Function not allowed due to command protection selection",
+    0x70 => "Processor is in program mode",
+    0x80 => "Compatibility mode file missing or communication zone problem",
+    0x90 => "Remote node cannot buffer command",
+    0xB0 => "Remote node problem due to download",
+    0xF0 => "Error in extended status",
+    _ => "Unknown status",
+  }
+}
+
+fn pccc_extended_status_description(status: u8) -> &'static str {
+  match status {
+    0x01 => "A field has an illegal value",
+    0x02 => "Fewer levels specified in address than minimum for any address",
+    0x03 => "More levels specified in address than system supports",
+    0x04 => "Symbol not found",
+    0x05 => "Symbol is of improper format",
+    0x06 => "Address doesn't point to something usable",
+    0x07 => "File is wrong size",
+    0x08 => "Cannot complete request, situation has changed since the start of the command",
+    0x09 => "Data or file is too large",
+    0x0A => "Transaction size plus word address is too large",
+    0x0B => "Access denied, improper privilege",
+    0x0C => "Condition cannot be generated, resource is not available",
+    0x0D => "Condition already exists, resource is already available",
+    0x0E => "Command cannot be executed",
+    0x10 => "No access",
+    0x11 => "Illegal data type",
+    0x12 => "Invalid parameter or invalid data",
+    0x13 => "Address reference exists to deleted area",
+    0x14 => "Command execution failure for unknown reason",
+    0x15 => "Data conversion error",
+    _ => "Unknown extended status",
+  }
+}
+
+fn general_status_description(status: u8) -> &'static str {
+  match status {
+    0x00 => "Success",
+    0x01 => "Connection failure",
+    0x02 => "Resource unavailable",
+    0x03 => "Invalid parameter value",
+    0x04 => "Path segment error",
+    0x05 => "Path destination unknown",
+    0x06 => "Partial transfer",
+    0x07 => "Connection lost",
+    0x08 => "Service not supported",
+    0x09 => "Invalid attribute value",
+    0x0A => "Attribute list error",
+    0x0B => "Already in requested mode/state",
+    0x0C => "Object state conflict",
+    0x0D => "Object already exists",
+    0x0E => "Attribute not settable",
+    0x0F => "Privilege violation",
+    0x10 => "Device state conflict",
+    0x11 => "Reply data too large",
+    0x12 => "Fragmentation of a primitive value",
+    0x13 => "Not enough data",
+    0x14 => "Attribute not supported",
+    0x15 => "Too much data",
+    0x16 => "Object does not exist",
+    0x1E => "Embedded service error",
+    0x20 => "Invalid parameter",
+    0x26 => "Path size invalid",
+    0xFF => "General error",
+    _ => "Unknown status",
+  }
+}
+
+fn connection_manager_status_description(status: u16) -> Option<&'static str> {
+  Some(match status {
+    0x0100 => "Connection in use or duplicate Forward Open",
+    0x0103 => "Transport class and trigger combination not supported",
+    0x0106 => "Ownership conflict",
+    0x0107 => "Target connection not found",
+    0x0108 => "Invalid network connection parameter",
+    0x0109 => "Invalid connection size",
+    0x0110 => "Target for connection not configured",
+    0x0111 => "RPI not supported",
+    0x0113 => "Out of connections",
+    0x0114 => "Vendor ID or product code mismatch",
+    0x0115 => "Device type mismatch",
+    0x0116 => "Revision mismatch",
+    0x0117 => "Invalid produced or consumed application path",
+    0x0118 => "Invalid or inconsistent configuration application path",
+    0x0119 => "Non-listen only connection not opened",
+    0x011A => "Target object out of connections",
+    0x011B => "RPI is smaller than the production inhibit time",
+    0x0203 => "Connection timed out",
+    0x0204 => "Unconnected request timed out",
+    0x0205 => "Parameter error in unconnected request",
+    0x0301 => "No buffer memory available",
+    0x0311 => "Invalid port in route path",
+    0x0312 => "Invalid link address in route path",
+    0x0315 => "Invalid segment in connection path",
+    _ => return None,
+  })
+}
+
+#[test]
+fn test_cip_error_from_reply() {
+  assert_eq!(CipError::from_reply(&[0xD4, 0, 0, 0]), None);
+
+  let error = CipError::from_reply(&[0xD4, 0, 0x01, 1, 0x19, 0x01]).unwrap();
+  assert!(error.is_owner_not_found());
+  assert_eq!(
+    error.to_string(),
+    "general status 0x01 (Connection failure), extended status 0x0119 (Non-listen only connection not opened)"
+  );
+
+  let io_error: io::Error = error.clone().into();
+  assert_eq!(io_error.kind(), io::ErrorKind::NotFound);
+  assert_eq!(CipError::from_io(&io_error), Some(&error));
+}
+
+#[test]
+fn test_routing_error() {
+  let error = RoutingError::new(CipError::new(0x01, vec![0x0312]), Some(2));
+  assert_eq!(error.kind, RoutingErrorKind::LinkAddressNotValid);
+
+  let io_error: io::Error = error.clone().into();
+  assert_eq!(io_error.kind(), io::ErrorKind::NotFound);
+  assert_eq!(RoutingError::from_io(&io_error), Some(&error));
+}
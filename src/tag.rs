@@ -0,0 +1,634 @@
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{Result, Error, ErrorKind};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::eip;
+use crate::CipError;
+
+/*
+A typed tag value
+Both Logix tags and PCCC data table addresses are read and written as one of these.
+Structures the library doesn't know about come back as their structure handle
+and raw bytes.
+*/
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum TagValue {
+  Bool(bool),
+  Sint(i8),
+  Int(i16),
+  Dint(i32),
+  Lint(i64),
+  Usint(u8),
+  Uint(u16),
+  Udint(u32),
+  Ulint(u64),
+  Real(f32),
+  Lreal(f64),
+  Byte(u8),
+  Word(u16),
+  Dword(u32),
+  Lword(u64),
+  String(String),
+  Struct { handle: u16, data: Vec<u8> },
+}
+impl fmt::Display for TagValue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TagValue::Bool(value) => write!(f, "{}", value),
+      TagValue::Sint(value) => write!(f, "{}", value),
+      TagValue::Int(value) => write!(f, "{}", value),
+      TagValue::Dint(value) => write!(f, "{}", value),
+      TagValue::Lint(value) => write!(f, "{}", value),
+      TagValue::Usint(value) => write!(f, "{}", value),
+      TagValue::Uint(value) => write!(f, "{}", value),
+      TagValue::Udint(value) => write!(f, "{}", value),
+      TagValue::Ulint(value) => write!(f, "{}", value),
+      TagValue::Real(value) => write!(f, "{}", value),
+      TagValue::Lreal(value) => write!(f, "{}", value),
+      TagValue::Byte(value) => write!(f, "{:#04x}", value),
+      TagValue::Word(value) => write!(f, "{:#06x}", value),
+      TagValue::Dword(value) => write!(f, "{:#010x}", value),
+      TagValue::Lword(value) => write!(f, "{:#018x}", value),
+      TagValue::String(value) => write!(f, "{:?}", value),
+      TagValue::Struct { handle, data } => {
+        write!(f, "struct {:#06x} [", handle)?;
+        for (i, byte) in data.iter().enumerate() {
+          if i > 0 {
+            write!(f, " ")?;
+          }
+          write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "]")
+      }
+    }
+  }
+}
+
+impl TagValue {
+
+  /*
+  The name of the value's type, like DINT, or STRUCT for structures
+  */
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      TagValue::String(_) => "STRING",
+      TagValue::Struct { .. } => "STRUCT",
+      atomic => type_name(encode_atomic(atomic).0).unwrap_or("STRUCT"),
+    }
+  }
+
+  /*
+  The value as JSON: numbers, booleans and strings as themselves, structures
+  as hex
+  */
+  pub fn to_json(&self) -> serde_json::Value {
+    match self {
+      TagValue::Bool(value) => json!(value),
+      TagValue::Sint(value) => json!(value),
+      TagValue::Int(value) => json!(value),
+      TagValue::Dint(value) => json!(value),
+      TagValue::Lint(value) => json!(value),
+      TagValue::Usint(value) | TagValue::Byte(value) => json!(value),
+      TagValue::Uint(value) | TagValue::Word(value) => json!(value),
+      TagValue::Udint(value) | TagValue::Dword(value) => json!(value),
+      TagValue::Ulint(value) | TagValue::Lword(value) => json!(value),
+      TagValue::Real(value) => json!(value),
+      TagValue::Lreal(value) => json!(value),
+      TagValue::String(value) => json!(value),
+      TagValue::Struct { data, .. } => {
+        json!(data.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" "))
+      },
+    }
+  }
+
+  /*
+  Parse a value of the named type from text
+  Integers can be decimal or 0x hex, BOOL is true/false or 1/0, and STRING
+  takes the text as it is. Structures can't be parsed.
+  */
+  pub fn parse(type_name: &str, text: &str) -> Result<TagValue> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("{:?} isn't a valid {}", text, type_name.to_uppercase()));
+    let data_type = type_code(type_name)
+      .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("can't parse a value of type {}", type_name)))?;
+    let int = || -> Result<i128> {
+      let text = text.trim();
+      let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+      };
+      let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i128::from_str_radix(hex, 16),
+        None => digits.parse::<i128>(),
+      }.map_err(|_| invalid())?;
+      Ok(if negative { -value } else { value })
+    };
+
+    Ok(match data_type {
+      TYPE_BOOL => match text.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" => TagValue::Bool(true),
+        "false" | "0" => TagValue::Bool(false),
+        _ => return Err(invalid()),
+      },
+      TYPE_SINT => TagValue::Sint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_INT => TagValue::Int(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_DINT => TagValue::Dint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_LINT => TagValue::Lint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_USINT => TagValue::Usint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_UINT => TagValue::Uint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_UDINT => TagValue::Udint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_ULINT => TagValue::Ulint(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_BYTE => TagValue::Byte(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_WORD => TagValue::Word(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_DWORD => TagValue::Dword(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_LWORD => TagValue::Lword(int()?.try_into().map_err(|_| invalid())?),
+      TYPE_REAL => TagValue::Real(text.trim().parse().map_err(|_| invalid())?),
+      TYPE_LREAL => TagValue::Lreal(text.trim().parse().map_err(|_| invalid())?),
+      _ => TagValue::String(String::from(text)),
+    })
+  }
+}
+
+/*
+CIP elementary data type codes
+*/
+pub const TYPE_BOOL: u16 = 0xC1;
+pub const TYPE_SINT: u16 = 0xC2;
+pub const TYPE_INT: u16 = 0xC3;
+pub const TYPE_DINT: u16 = 0xC4;
+pub const TYPE_LINT: u16 = 0xC5;
+pub const TYPE_USINT: u16 = 0xC6;
+pub const TYPE_UINT: u16 = 0xC7;
+pub const TYPE_UDINT: u16 = 0xC8;
+pub const TYPE_ULINT: u16 = 0xC9;
+pub const TYPE_REAL: u16 = 0xCA;
+pub const TYPE_LREAL: u16 = 0xCB;
+// Length-prefixed STRING and bit strings, which Omron uses and Logix doesn't
+pub const TYPE_STRING: u16 = 0xD0;
+pub const TYPE_BYTE: u16 = 0xD1;
+pub const TYPE_WORD: u16 = 0xD2;
+pub const TYPE_DWORD: u16 = 0xD3;
+pub const TYPE_LWORD: u16 = 0xD4;
+// Structures are 0x02A0 followed by the structure handle
+pub const TYPE_STRUCT: u16 = 0x02A0;
+// The structure handle of the built-in Logix STRING (DINT length, SINT[82])
+pub const LOGIX_STRING_HANDLE: u16 = 0x0FCE;
+const LOGIX_STRING_SIZE: usize = 82;
+
+// Names of the elementary types, as Logix Designer writes them
+const TYPE_NAMES: [(u16, &str); 16] = [
+  (TYPE_BOOL, "BOOL"), (TYPE_SINT, "SINT"), (TYPE_INT, "INT"), (TYPE_DINT, "DINT"),
+  (TYPE_LINT, "LINT"), (TYPE_USINT, "USINT"), (TYPE_UINT, "UINT"), (TYPE_UDINT, "UDINT"),
+  (TYPE_ULINT, "ULINT"), (TYPE_REAL, "REAL"), (TYPE_LREAL, "LREAL"), (TYPE_STRING, "STRING"),
+  (TYPE_BYTE, "BYTE"), (TYPE_WORD, "WORD"), (TYPE_DWORD, "DWORD"), (TYPE_LWORD, "LWORD"),
+];
+
+/*
+The name of an elementary type code, like DINT for 0xC4
+*/
+pub fn type_name(data_type: u16) -> Option<&'static str> {
+  TYPE_NAMES.iter().find(|(code, _)| *code == data_type).map(|(_, name)| *name)
+}
+
+/*
+The type code of an elementary type name, in any case
+*/
+pub fn type_code(name: &str) -> Option<u16> {
+  TYPE_NAMES.iter().find(|(_, known)| known.eq_ignore_ascii_case(name)).map(|(code, _)| *code)
+}
+
+/*
+The size in bytes of an elementary type; STRING has none
+*/
+pub fn atomic_size(data_type: u16) -> Option<usize> {
+  match data_type {
+    TYPE_BOOL | TYPE_SINT | TYPE_USINT | TYPE_BYTE => Some(1),
+    TYPE_INT | TYPE_UINT | TYPE_WORD => Some(2),
+    TYPE_DINT | TYPE_UDINT | TYPE_REAL | TYPE_DWORD => Some(4),
+    TYPE_LINT | TYPE_ULINT | TYPE_LREAL | TYPE_LWORD => Some(8),
+    _ => None,
+  }
+}
+
+/*
+How a vendor's tag services encode values
+Logix: STRING is a structure (handle 0x0FCE) of a DINT length and 82 characters,
+  and BOOL is one byte.
+Omron: STRING is type 0xD0 with a UINT length, structure handles are the CRC
+  of the structure's layout, BOOL is two bytes, and BYTE/WORD/DWORD/LWORD exist.
+*/
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum TagDialect {
+  #[default]
+  Logix,
+  Omron,
+}
+
+/* Read Tag (one element) */
+pub fn build_read_tag(tag: &str) -> Result<Vec<u8>> {
+  const CIP_SERVICE: u8 = 0x4C;
+  const CIP_ELEMENTS: u16 = 1;
+
+  let mut ioi = eip::build_tag_ioi(tag)?;
+  let mut request = Vec::<u8>::with_capacity(4 + ioi.len());
+  request.write_u8(CIP_SERVICE).unwrap();
+  request.write_u8( (ioi.len()/2).try_into().unwrap() ).unwrap();
+  request.append(&mut ioi);
+  request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+
+  return Ok(request);
+}
+
+#[test]
+fn test_build_read_tag() {
+  assert_eq!(
+    build_read_tag("Test").unwrap(),
+    vec![0x4C, 3, 0x91, 4, 84, 101, 115, 116, 1, 0]
+  );
+}
+
+/*
+Parse the reply to a Read Tag into a TagValue
+*/
+pub fn parse_read_tag_reply(cip: &[u8], dialect: TagDialect) -> Result<TagValue> {
+  if let Some(error) = CipError::from_reply(cip) {
+    return Err(error.into());
+  }
+  if cip.len() < 6 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "Read Tag reply is too short"));
+  }
+
+  let data_type = LittleEndian::read_u16(&cip[4..6]);
+  if data_type == TYPE_STRUCT {
+    if cip.len() < 8 {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "Read Tag reply is missing the structure handle"));
+    }
+    return decode_struct(LittleEndian::read_u16(&cip[6..8]), &cip[8..], dialect);
+  }
+  if data_type == TYPE_STRING {
+    return decode_string(&cip[6..]);
+  }
+
+  decode_atomic(data_type, &cip[6..])
+}
+
+/*
+Decode an elementary value of the given type code
+*/
+pub fn decode_atomic(data_type: u16, data: &[u8]) -> Result<TagValue> {
+  let size = atomic_size(data_type)
+    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown data type {:#06x}", data_type)))?;
+  if data.len() < size {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "tag data is shorter than its type"));
+  }
+
+  Ok(match data_type {
+    TYPE_BOOL => TagValue::Bool(data[0] != 0),
+    TYPE_SINT => TagValue::Sint(data[0] as i8),
+    TYPE_USINT => TagValue::Usint(data[0]),
+    TYPE_INT => TagValue::Int(LittleEndian::read_i16(data)),
+    TYPE_UINT => TagValue::Uint(LittleEndian::read_u16(data)),
+    TYPE_DINT => TagValue::Dint(LittleEndian::read_i32(data)),
+    TYPE_UDINT => TagValue::Udint(LittleEndian::read_u32(data)),
+    TYPE_REAL => TagValue::Real(LittleEndian::read_f32(data)),
+    TYPE_LINT => TagValue::Lint(LittleEndian::read_i64(data)),
+    TYPE_ULINT => TagValue::Ulint(LittleEndian::read_u64(data)),
+    TYPE_BYTE => TagValue::Byte(data[0]),
+    TYPE_WORD => TagValue::Word(LittleEndian::read_u16(data)),
+    TYPE_DWORD => TagValue::Dword(LittleEndian::read_u32(data)),
+    TYPE_LWORD => TagValue::Lword(LittleEndian::read_u64(data)),
+    _ => TagValue::Lreal(LittleEndian::read_f64(data)),
+  })
+}
+
+fn decode_struct(handle: u16, data: &[u8], dialect: TagDialect) -> Result<TagValue> {
+  // Omron handles are CRCs, so 0x0FCE is only a STRING on Logix
+  if dialect == TagDialect::Logix && handle == LOGIX_STRING_HANDLE && data.len() >= 4 {
+    let len = (LittleEndian::read_u32(&data[0..4]) as usize).min(data.len() - 4);
+    return Ok(TagValue::String(String::from_utf8_lossy(&data[4..4 + len]).into_owned()));
+  }
+
+  Ok(TagValue::Struct { handle, data: data.to_vec() })
+}
+
+fn decode_string(data: &[u8]) -> Result<TagValue> {
+  if data.len() < 2 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "STRING is missing its length"));
+  }
+
+  let len = usize::from(LittleEndian::read_u16(&data[0..2])).min(data.len() - 2);
+  Ok(TagValue::String(String::from_utf8_lossy(&data[2..2 + len]).into_owned()))
+}
+
+/* Write Tag (one element) */
+pub fn build_write_tag(tag: &str, value: &TagValue, dialect: TagDialect) -> Result<Vec<u8>> {
+  const CIP_SERVICE: u8 = 0x4D;
+  const CIP_ELEMENTS: u16 = 1;
+
+  let mut ioi = eip::build_tag_ioi(tag)?;
+  let mut request = Vec::<u8>::with_capacity(8 + ioi.len());
+  request.write_u8(CIP_SERVICE).unwrap();
+  request.write_u8( (ioi.len()/2).try_into().unwrap() ).unwrap();
+  request.append(&mut ioi);
+
+  match value {
+    TagValue::String(string) if dialect == TagDialect::Omron => {
+      let len: u16 = string.len().try_into()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "string is too long for a STRING"))?;
+      request.write_u16::<LittleEndian>(TYPE_STRING).unwrap();
+      request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+      request.write_u16::<LittleEndian>(len).unwrap();
+      request.extend_from_slice(string.as_bytes());
+    }
+    TagValue::String(string) => {
+      if string.len() > LOGIX_STRING_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "string is longer than a Logix STRING"));
+      }
+      request.write_u16::<LittleEndian>(TYPE_STRUCT).unwrap();
+      request.write_u16::<LittleEndian>(LOGIX_STRING_HANDLE).unwrap();
+      request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+      request.write_u32::<LittleEndian>(string.len() as u32).unwrap();
+      request.extend_from_slice(string.as_bytes());
+      request.resize(request.len() + LOGIX_STRING_SIZE - string.len(), 0);
+    }
+    TagValue::Struct { handle, data } => {
+      request.write_u16::<LittleEndian>(TYPE_STRUCT).unwrap();
+      request.write_u16::<LittleEndian>(*handle).unwrap();
+      request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+      request.extend_from_slice(data);
+    }
+    TagValue::Bool(value) if dialect == TagDialect::Omron => {
+      request.write_u16::<LittleEndian>(TYPE_BOOL).unwrap();
+      request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+      request.write_u16::<LittleEndian>(u16::from(*value)).unwrap();
+    }
+    atomic => {
+      let (data_type, mut data) = encode_atomic(atomic);
+      request.write_u16::<LittleEndian>(data_type).unwrap();
+      request.write_u16::<LittleEndian>(CIP_ELEMENTS).unwrap();
+      request.append(&mut data);
+    }
+  }
+
+  Ok(request)
+}
+
+/*
+The type code and little-endian bytes of an elementary value
+Strings and structures aren't elementary; they encode as an empty SINT.
+*/
+pub fn encode_atomic(value: &TagValue) -> (u16, Vec<u8>) {
+  match value {
+    TagValue::Bool(value) => (TYPE_BOOL, vec![if *value { 0xFF } else { 0 }]),
+    TagValue::Sint(value) => (TYPE_SINT, value.to_le_bytes().to_vec()),
+    TagValue::Int(value) => (TYPE_INT, value.to_le_bytes().to_vec()),
+    TagValue::Dint(value) => (TYPE_DINT, value.to_le_bytes().to_vec()),
+    TagValue::Lint(value) => (TYPE_LINT, value.to_le_bytes().to_vec()),
+    TagValue::Usint(value) => (TYPE_USINT, value.to_le_bytes().to_vec()),
+    TagValue::Uint(value) => (TYPE_UINT, value.to_le_bytes().to_vec()),
+    TagValue::Udint(value) => (TYPE_UDINT, value.to_le_bytes().to_vec()),
+    TagValue::Ulint(value) => (TYPE_ULINT, value.to_le_bytes().to_vec()),
+    TagValue::Real(value) => (TYPE_REAL, value.to_le_bytes().to_vec()),
+    TagValue::Lreal(value) => (TYPE_LREAL, value.to_le_bytes().to_vec()),
+    TagValue::Byte(value) => (TYPE_BYTE, value.to_le_bytes().to_vec()),
+    TagValue::Word(value) => (TYPE_WORD, value.to_le_bytes().to_vec()),
+    TagValue::Dword(value) => (TYPE_DWORD, value.to_le_bytes().to_vec()),
+    TagValue::Lword(value) => (TYPE_LWORD, value.to_le_bytes().to_vec()),
+    TagValue::String(_) | TagValue::Struct { .. } => (TYPE_SINT, vec![]),
+  }
+}
+
+/*
+Check the reply to a Write Tag
+*/
+pub fn parse_write_tag_reply(cip: &[u8]) -> Result<()> {
+  match CipError::from_reply(cip) {
+    Some(error) => Err(error.into()),
+    None if cip.len() < 4 => Err(Error::new(ErrorKind::UnexpectedEof, "Write Tag reply is too short")),
+    None => Ok(()),
+  }
+}
+
+#[test]
+fn test_write_tag_round_trip() {
+  assert_eq!(
+    build_write_tag("Test", &TagValue::Dint(-2), TagDialect::Logix).unwrap(),
+    vec![0x4D, 3, 0x91, 4, 84, 101, 115, 116, 0xC4, 0, 1, 0, 0xFE, 0xFF, 0xFF, 0xFF]
+  );
+
+  let reply = [0xCC, 0, 0, 0, 0xCA, 0, 0, 0, 0x20, 0x41];
+  assert_eq!(parse_read_tag_reply(&reply, TagDialect::Logix).unwrap(), TagValue::Real(10.0));
+
+  // A STRING is a structure with a length and 82 characters
+  let request = build_write_tag("S", &TagValue::String(String::from("hi")), TagDialect::Logix).unwrap();
+  let mut reply = vec![0xCC, 0, 0, 0];
+  reply.extend_from_slice(&request[6..10]);
+  reply.extend_from_slice(&request[12..]);
+  assert_eq!(parse_read_tag_reply(&reply, TagDialect::Logix).unwrap(), TagValue::String(String::from("hi")));
+
+  assert!(parse_read_tag_reply(&[0xCC, 0, 0x04, 0], TagDialect::Logix).is_err());
+  assert!(parse_write_tag_reply(&[0xCD, 0, 0, 0]).is_ok());
+}
+
+#[test]
+fn test_omron_tags() {
+  // STRING is length-prefixed
+  let request = build_write_tag("S", &TagValue::String(String::from("abc")), TagDialect::Omron).unwrap();
+  assert_eq!(&request[6..], &[0xD0, 0, 1, 0, 3, 0, b'a', b'b', b'c']);
+  let reply = [0xCC, 0, 0, 0, 0xD0, 0, 3, 0, b'a', b'b', b'c'];
+  assert_eq!(parse_read_tag_reply(&reply, TagDialect::Omron).unwrap(), TagValue::String(String::from("abc")));
+
+  // BOOL is a word
+  let request = build_write_tag("B", &TagValue::Bool(true), TagDialect::Omron).unwrap();
+  assert_eq!(&request[6..], &[0xC1, 0, 1, 0, 1, 0]);
+
+  // A structure whose CRC happens to be the Logix STRING handle is still a structure
+  let reply = [0xCC, 0, 0, 0, 0xA0, 0x02, 0xCE, 0x0F, 2, 0, 0, 0, b'h', b'i'];
+  assert_eq!(
+    parse_read_tag_reply(&reply, TagDialect::Omron).unwrap(),
+    TagValue::Struct { handle: 0x0FCE, data: vec![2, 0, 0, 0, b'h', b'i'] }
+  );
+
+  let reply = [0xCC, 0, 0, 0, 0xD2, 0, 0x34, 0x12];
+  assert_eq!(parse_read_tag_reply(&reply, TagDialect::Omron).unwrap(), TagValue::Word(0x1234));
+}
+
+/*
+A tag in a controller's symbol table, as Get Instance Attribute List lists it
+The symbol type is the Logix one: the low 12 bits are the elementary type code
+or structure handle, bit 12 marks system tags, bits 13-14 count the array
+dimensions, and bit 15 marks a structure.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TagInfo {
+  pub instance: u32,
+  pub name: String,
+  pub symbol_type: u16,
+}
+impl TagInfo {
+  pub fn is_struct(&self) -> bool {
+    self.symbol_type & 0x8000 != 0
+  }
+
+  pub fn dimensions(&self) -> u8 {
+    ((self.symbol_type >> 13) & 0x03) as u8
+  }
+
+  /*
+  Tags the controller keeps for itself, like routines and I/O module data
+  */
+  pub fn is_system(&self) -> bool {
+    self.symbol_type & 0x1000 != 0 || self.name.starts_with("__")
+  }
+
+  /*
+  The elementary type code or structure handle
+  */
+  pub fn type_code(&self) -> u16 {
+    self.symbol_type & 0x0FFF
+  }
+
+  /*
+  The type as text, like DINT, REAL[,] or STRUCT 0x0fce
+  */
+  pub fn type_description(&self) -> String {
+    let mut description = match (self.is_struct(), type_name(self.type_code())) {
+      (false, Some(name)) => String::from(name),
+      _ if self.is_struct() && self.type_code() == LOGIX_STRING_HANDLE => String::from("STRING"),
+      (true, _) => format!("STRUCT {:#06x}", self.type_code()),
+      (false, None) => format!("{:#06x}", self.type_code()),
+    };
+    if self.dimensions() > 0 {
+      description.push('[');
+      description.push_str(&",".repeat(usize::from(self.dimensions() - 1)));
+      description.push(']');
+    }
+
+    description
+  }
+}
+
+/*
+Get Instance Attribute List on the Symbol class, from an instance onwards
+Asks for each symbol's name (attribute 1) and type (attribute 2).
+*/
+pub fn build_get_tag_list_query(start_instance: u32) -> Vec<u8> {
+  const CIP_SERVICE: u8 = 0x55;
+  const SYMBOL_CLASS: u8 = 0x6B;
+  const CLASS_SEGMENT: u8 = 0x20;
+  const INSTANCE_SEGMENT_16: u8 = 0x25;
+  const INSTANCE_SEGMENT_32: u8 = 0x26;
+  const ATTRIBUTES: [u16; 2] = [1, 2];
+
+  let mut path = vec![CLASS_SEGMENT, SYMBOL_CLASS];
+  match u16::try_from(start_instance) {
+    Ok(instance) => {
+      path.extend_from_slice(&[INSTANCE_SEGMENT_16, 0]);
+      path.write_u16::<LittleEndian>(instance).unwrap();
+    }
+    Err(_) => {
+      path.extend_from_slice(&[INSTANCE_SEGMENT_32, 0]);
+      path.write_u32::<LittleEndian>(start_instance).unwrap();
+    }
+  }
+
+  let mut request = Vec::<u8>::with_capacity(4 + path.len() + 2 * ATTRIBUTES.len());
+  request.write_u8(CIP_SERVICE).unwrap();
+  request.write_u8((path.len() / 2) as u8).unwrap();
+  request.append(&mut path);
+  request.write_u16::<LittleEndian>(ATTRIBUTES.len() as u16).unwrap();
+  for attribute in &ATTRIBUTES {
+    request.write_u16::<LittleEndian>(*attribute).unwrap();
+  }
+
+  return request;
+}
+
+/*
+Parse the tags out of a Get Instance Attribute List reply
+The flag is true when the reply was partial (status 0x06) and the list
+continues after the last instance returned.
+*/
+pub fn parse_get_tag_list_reply(cip: &[u8]) -> Result<(Vec<TagInfo>, bool)> {
+  const PARTIAL_TRANSFER: u8 = 0x06;
+
+  let more = match CipError::from_reply(cip) {
+    None => false,
+    Some(error) if error.general_status == PARTIAL_TRANSFER => true,
+    Some(error) => return Err(error.into()),
+  };
+  if cip.len() < 4 {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "tag list reply is too short"));
+  }
+  let data_start = 4 + 2 * usize::from(cip[3]);
+  if cip.len() < data_start {
+    return Err(Error::new(ErrorKind::UnexpectedEof, "tag list reply extended status is truncated"));
+  }
+
+  let data = &cip[data_start..];
+  let truncated = || Error::new(ErrorKind::UnexpectedEof, "tag list entry is truncated");
+  let mut tags = vec![];
+  let mut pos = 0;
+  while pos < data.len() {
+    if data.len() < pos + 6 {
+      return Err(truncated());
+    }
+    let instance = LittleEndian::read_u32(&data[pos..pos + 4]);
+    let name_len = usize::from(LittleEndian::read_u16(&data[pos + 4..pos + 6]));
+    pos += 6;
+    if data.len() < pos + name_len + 2 {
+      return Err(truncated());
+    }
+    let name = String::from_utf8_lossy(&data[pos..pos + name_len]).into_owned();
+    let symbol_type = LittleEndian::read_u16(&data[pos + name_len..pos + name_len + 2]);
+    pos += name_len + 2;
+
+    tags.push(TagInfo { instance, name, symbol_type });
+  }
+
+  Ok((tags, more))
+}
+
+#[test]
+fn test_tag_list() {
+  assert_eq!(
+    build_get_tag_list_query(0),
+    vec![0x55, 3, 0x20, 0x6B, 0x25, 0, 0, 0, 2, 0, 1, 0, 2, 0]
+  );
+  assert_eq!(&build_get_tag_list_query(0x10000)[2..10], &[0x20, 0x6B, 0x26, 0, 0, 0, 1, 0]);
+
+  let mut reply = vec![0xD5, 0, 0x06, 0];
+  for (instance, name, symbol_type) in [(3u32, "Count", 0x00C4u16), (9, "Names", 0xAFCE), (12, "__Hidden", 0x10C1)] {
+    reply.extend_from_slice(&instance.to_le_bytes());
+    reply.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    reply.extend_from_slice(name.as_bytes());
+    reply.extend_from_slice(&symbol_type.to_le_bytes());
+  }
+
+  let (tags, more) = parse_get_tag_list_reply(&reply).unwrap();
+  assert!(more);
+  assert_eq!(tags.len(), 3);
+  assert_eq!((tags[0].instance, tags[0].name.as_str()), (3, "Count"));
+  assert_eq!(tags[0].type_description(), "DINT");
+  assert_eq!(tags[1].type_description(), "STRING[]");
+  assert!(tags[2].is_system() && !tags[1].is_system());
+
+  reply[2] = 0;
+  assert!(parse_get_tag_list_reply(&reply[..reply.len() - 1]).is_err());
+  assert!(parse_get_tag_list_reply(&[0xD5, 0, 0x05, 0]).is_err());
+  assert_eq!(parse_get_tag_list_reply(&[0xD5, 0, 0x06, 5]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_parse_tag_value() {
+  assert_eq!(TagValue::parse("dint", "-0x10").unwrap(), TagValue::Dint(-16));
+  assert_eq!(TagValue::parse("BOOL", "1").unwrap(), TagValue::Bool(true));
+  assert_eq!(TagValue::parse("REAL", "2.5").unwrap(), TagValue::Real(2.5));
+  assert_eq!(TagValue::parse("STRING", " a b").unwrap(), TagValue::String(String::from(" a b")));
+  assert!(TagValue::parse("SINT", "200").is_err());
+  assert!(TagValue::parse("UDT", "1").is_err());
+  assert_eq!(TagValue::Udint(1).type_name(), "UDINT");
+  assert_eq!(TagValue::String(String::new()).type_name(), "STRING");
+}
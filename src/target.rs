@@ -0,0 +1,961 @@
+use std::net::{TcpListener, TcpStream, UdpSocket, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, Shutdown};
+use std::io::{Write, Result, ErrorKind};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, LittleEndian};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use socket2::SockRef;
+
+use crate::eip::{self, ConnectionTriple, ForwardOpenRequest};
+use crate::sockets::read_frame;
+use crate::identity::{self, Identity};
+use crate::{ConnectionKind, ConnectionType, OutputBuffer, TransportTrigger};
+
+// Encapsulation commands and statuses a target deals with
+const REGISTER_SESSION: u16 = 0x65;
+const UNREGISTER_SESSION: u16 = 0x66;
+const SEND_RR_DATA: u16 = 0x6F;
+const LIST_IDENTITY: u16 = 0x63;
+const STATUS_INVALID_COMMAND: u32 = 0x01;
+const STATUS_INVALID_SESSION: u32 = 0x64;
+
+// CIP services and statuses
+const FORWARD_OPEN: u8 = 0x54;
+const LARGE_FORWARD_OPEN: u8 = 0x5B;
+const FORWARD_CLOSE: u8 = 0x4E;
+const CONNECTION_FAILURE: u8 = 0x01;
+const SERVICE_NOT_SUPPORTED: u8 = 0x08;
+
+/*
+Where a Target listens and what it accepts
+originator_io_port is where T->O packets are sent on the originator's side,
+whether point-to-point or to a multicast group. Multicast connections each get
+a group of their own, counting up from multicast_base. RPIs are in
+microseconds. With an identity, the target answers ListIdentity.
+*/
+#[derive(Debug, Clone)]
+pub struct TargetConfig {
+  pub bind_addr: IpAddr,
+  pub encap_port: u16,
+  pub io_port: u16,
+  pub originator_io_port: u16,
+  pub multicast_base: Ipv4Addr,
+  pub min_rpi: u32,
+  pub max_rpi: u32,
+  pub identity: Option<Identity>,
+}
+impl Default for TargetConfig {
+  fn default() -> TargetConfig {
+    TargetConfig {
+      bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      encap_port: 44818,
+      io_port: 2222,
+      originator_io_port: 2222,
+      multicast_base: Ipv4Addr::new(239, 192, 1, 0),
+      min_rpi: 1_000,
+      max_rpi: 10_000_000,
+      identity: None,
+    }
+  }
+}
+
+/*
+Faults a target injects into its connections, to exercise error handling
+Faults are set per tag, for every connection to it, or per connection by its
+O->T connection ID; connection faults win. Counts are T->O packets of the
+connection, so a scenario plays out the same way every time.
+
+reject_forward_open: answer Forward Opens for the tag with this general and
+  extended status (only meaningful per tag)
+drop_every: drop every nth packet
+reorder: send packets in swapped pairs
+jitter: delay each packet by up to this much, drawn from jitter_seed
+truncate: cut packets to this many bytes
+stop_after: stop producing after this many packets, leaving the connection open
+reset_session_after: shut down the TCP session that opened the connection after
+  this many packets
+ignore_heartbeats: pretend O->T heartbeats don't arrive, so the connection times out
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Faults {
+  pub reject_forward_open: Option<(u8, Vec<u16>)>,
+  pub drop_every: Option<u32>,
+  pub reorder: bool,
+  pub jitter: Option<Duration>,
+  pub jitter_seed: u64,
+  pub truncate: Option<usize>,
+  pub stop_after: Option<u32>,
+  pub reset_session_after: Option<u32>,
+  pub ignore_heartbeats: bool,
+}
+
+/*
+One connection a PLC has opened to one of our produced tags or assemblies
+output is the output assembly an exclusive-owner assembly connection writes its
+O->T data to, and whether that data has a run/idle header.
+*/
+struct TargetConnection {
+  tag: String,
+  output: Option<(Arc<OutputBuffer>, bool)>,
+  peer: SocketAddr,
+  triple: ConnectionTriple,
+  to_connection_id: u32,
+  last_heard: Mutex<Instant>,
+  alive: AtomicBool,
+}
+
+/*
+State shared by all of the target's threads
+*/
+struct TargetState {
+  config: TargetConfig,
+  tags: RwLock<HashMap<String, Arc<OutputBuffer>>>,
+  assemblies: RwLock<HashMap<u16, Arc<OutputBuffer>>>,
+  connections: Mutex<HashMap<u32, Arc<TargetConnection>>>,
+  sessions: Mutex<Vec<TcpStream>>,
+  next_connection_id: AtomicU32,
+  next_multicast_group: AtomicU32,
+  next_session_handle: AtomicU32,
+  heartbeats: Mutex<HashMap<String, u64>>,
+  timeouts: AtomicU64,
+  tag_faults: RwLock<HashMap<String, Faults>>,
+  connection_faults: RwLock<HashMap<u32, Faults>>,
+  alive: AtomicBool,
+}
+
+impl TargetState {
+
+  /*
+  The faults for a connection: its own if it has any, otherwise its tag's
+  */
+  fn faults(&self, ot_connection_id: u32, tag: &str) -> Faults {
+    if let Some(faults) = self.connection_faults.read().unwrap().get(&ot_connection_id) {
+      return faults.clone();
+    }
+
+    self.tag_faults.read().unwrap().get(tag).cloned().unwrap_or_default()
+  }
+
+  /*
+  Shut down the TCP session(s) from one peer
+  */
+  fn reset_session(&self, peer: SocketAddr) {
+    self.sessions.lock().unwrap().retain(|session| {
+      if session.peer_addr().ok() == Some(peer) {
+        let _ = session.shutdown(Shutdown::Both);
+        false
+      } else {
+        true
+      }
+    });
+  }
+}
+
+/*
+An EtherNet/IP target that PLCs can consume produced tags from
+Tags are published with an OutputBuffer; every PLC that opens a connection to
+a tag gets the buffer's contents at the RPI it asked for. T->O connections can
+be point-to-point or multicast (the Logix default for consumed tags), but each
+connection is produced on its own; consumers aren't merged onto one multicast
+production.
+*/
+pub struct Target {
+  state: Arc<TargetState>,
+  encap_addr: Option<SocketAddr>,
+  io_addr: Option<SocketAddr>,
+}
+impl Target {
+  pub fn new(config: TargetConfig) -> Target {
+    Target {
+      state: Arc::new(TargetState {
+        config,
+        tags: RwLock::new(HashMap::new()),
+        assemblies: RwLock::new(HashMap::new()),
+        connections: Mutex::new(HashMap::new()),
+        sessions: Mutex::new(vec![]),
+        next_connection_id: AtomicU32::new(0x1000_0001),
+        next_multicast_group: AtomicU32::new(0),
+        next_session_handle: AtomicU32::new(1),
+        heartbeats: Mutex::new(HashMap::new()),
+        timeouts: AtomicU64::new(0),
+        tag_faults: RwLock::new(HashMap::new()),
+        connection_faults: RwLock::new(HashMap::new()),
+        alive: AtomicBool::new(true),
+      }),
+      encap_addr: None,
+      io_addr: None,
+    }
+  }
+
+  /*
+  Publish a produced tag
+  The connection size PLCs have to ask for is the buffer size plus the 2-byte
+  CIP sequence count.
+  */
+  pub fn publish(&self, tag: &str, buffer: &Arc<OutputBuffer>) {
+    self.state.tags.write().unwrap().insert(String::from(tag), Arc::clone(buffer));
+  }
+
+  /*
+  Publish an assembly instance, the way an adapter has its I/O
+  Connections through the Assembly class produce an input assembly T->O and,
+  unless they go to a heartbeat point, write O->T data into an output assembly.
+  Connections to an assembly show up as the tag "Assembly <input instance>".
+  */
+  pub fn publish_assembly(&self, instance: u16, buffer: &Arc<OutputBuffer>) {
+    self.state.assemblies.write().unwrap().insert(instance, Arc::clone(buffer));
+  }
+
+  /*
+  Stop publishing a tag; connections to it are closed
+  */
+  pub fn unpublish(&self, tag: &str) {
+    self.state.tags.write().unwrap().remove(tag);
+    self.state.connections.lock().unwrap().retain(|_, con| {
+      if con.tag == tag {
+        con.alive.store(false, Ordering::Release);
+        false
+      } else {
+        true
+      }
+    });
+  }
+
+  /*
+  The tags PLCs currently have connections to, one entry per connection
+  */
+  pub fn connected_tags(&self) -> Vec<String> {
+    self.state.connections.lock().unwrap().values().map(|con| con.tag.clone()).collect()
+  }
+
+  /*
+  How many O->T heartbeats have arrived for a tag, over all its connections
+  */
+  pub fn heartbeats(&self, tag: &str) -> u64 {
+    self.state.heartbeats.lock().unwrap().get(tag).copied().unwrap_or(0)
+  }
+
+  /*
+  How many connections were dropped because their heartbeats stopped
+  */
+  pub fn timeouts(&self) -> u64 {
+    self.state.timeouts.load(Ordering::Relaxed)
+  }
+
+  /*
+  Inject faults into every connection to a tag, including ones opened later
+  */
+  pub fn set_faults(&self, tag: &str, faults: Faults) {
+    self.state.tag_faults.write().unwrap().insert(String::from(tag), faults);
+  }
+
+  /*
+  Inject faults into one connection, by its O->T connection ID
+  */
+  pub fn set_connection_faults(&self, ot_connection_id: u32, faults: Faults) {
+    self.state.connection_faults.write().unwrap().insert(ot_connection_id, faults);
+  }
+
+  pub fn clear_faults(&self) {
+    self.state.tag_faults.write().unwrap().clear();
+    self.state.connection_faults.write().unwrap().clear();
+  }
+
+  /*
+  Shut down every open TCP session, as if the device had reset
+  */
+  pub fn reset_sessions(&self) {
+    for session in self.state.sessions.lock().unwrap().drain(..) {
+      let _ = session.shutdown(Shutdown::Both);
+    }
+  }
+
+  /*
+  Bind the encapsulation listener and I/O socket and start serving
+  */
+  pub fn start(&mut self) -> Result<()> {
+    let config = &self.state.config;
+    let listener = TcpListener::bind((config.bind_addr, config.encap_port))?;
+    listener.set_nonblocking(true)?;
+    let socket = UdpSocket::bind((config.bind_addr, config.io_port))?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+    // Multicast goes out on the interface we're pinned to, loopback included
+    if let IpAddr::V4(interface) = config.bind_addr {
+      if !interface.is_unspecified() {
+        SockRef::from(&socket).set_multicast_if_v4(&interface)?;
+      }
+    }
+
+    self.encap_addr = Some(listener.local_addr()?);
+    self.io_addr = Some(socket.local_addr()?);
+
+    let socket = Arc::new(socket);
+    start_accept_thread(&self.state, listener, &socket);
+    start_heartbeat_thread(&self.state, &socket);
+
+    Ok(())
+  }
+
+  /*
+  The address PLCs open sessions on (useful when encap_port was 0)
+  */
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.encap_addr
+  }
+
+  /*
+  The address of the I/O socket
+  */
+  pub fn io_addr(&self) -> Option<SocketAddr> {
+    self.io_addr
+  }
+
+  pub fn stop(&mut self) {
+    self.state.alive.store(false, Ordering::Release);
+
+    for con in self.state.connections.lock().unwrap().drain().map(|(_, con)| con) {
+      con.alive.store(false, Ordering::Release);
+    }
+    for session in self.state.sessions.lock().unwrap().drain(..) {
+      let _ = session.shutdown(Shutdown::Both);
+    }
+  }
+}
+impl Drop for Target {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/*
+Accept sessions until the target is stopped
+*/
+fn start_accept_thread(state: &Arc<TargetState>, listener: TcpListener, socket: &Arc<UdpSocket>) {
+  let state = Arc::clone(state);
+  let socket = Arc::clone(socket);
+
+  thread::Builder::new().name("Target accept thread".to_string()).spawn(move || {
+    while state.alive.load(Ordering::Relaxed) {
+      match listener.accept() {
+        Ok((stream, peer)) => {
+          if stream.set_nonblocking(false).is_err() {
+            continue;
+          }
+          if let Ok(clone) = stream.try_clone() {
+            state.sessions.lock().unwrap().push(clone);
+          }
+
+          let state = Arc::clone(&state);
+          let socket = Arc::clone(&socket);
+          let spawned = thread::Builder::new().name(format!("Target session for {}", peer)).spawn(move || {
+            serve_session(&state, stream, peer, &socket);
+          });
+          if let Err(e) = spawned {
+            eprintln!("Couldn't start a session thread: {}", e);
+          }
+        },
+        Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+        Err(e) => eprintln!("Couldn't accept a session: {}", e),
+      }
+    }
+  }).unwrap();
+}
+
+/*
+Note when each connection's O->T heartbeat arrives
+Data after the CIP sequence count (and run/idle header) goes to the
+connection's output assembly, if it has one.
+*/
+fn start_heartbeat_thread(state: &Arc<TargetState>, socket: &Arc<UdpSocket>) {
+  let state = Arc::clone(state);
+  let socket = Arc::clone(socket);
+
+  thread::Builder::new().name("Target heartbeat thread".to_string()).spawn(move || {
+    let mut buf = [0u8; 4096];
+    while state.alive.load(Ordering::Relaxed) {
+      let size = match socket.recv_from(&mut buf) {
+        Ok((size, _)) => size,
+        Err(_) => continue,
+      };
+      if size < 10 {
+        continue;
+      }
+
+      let ot_connection_id = LittleEndian::read_u32(&buf[6..10]);
+      if let Some(con) = state.connections.lock().unwrap().get(&ot_connection_id) {
+        if state.faults(ot_connection_id, &con.tag).ignore_heartbeats {
+          continue;
+        }
+        *con.last_heard.lock().unwrap() = Instant::now();
+        if let Some((output, run_idle)) = &con.output {
+          let start = if *run_idle { 24 } else { 20 };
+          if let Some(data) = buf[..size].get(start..) {
+            let _ = output.set(data);
+          }
+        }
+        *state.heartbeats.lock().unwrap().entry(con.tag.clone()).or_insert(0) += 1;
+      }
+    }
+  }).unwrap();
+}
+
+/*
+Answer encapsulation requests on one session until it closes
+*/
+fn serve_session(state: &Arc<TargetState>, mut stream: TcpStream, peer: SocketAddr, socket: &Arc<UdpSocket>) {
+  let mut session_handle = 0;
+
+  while let Ok(frame) = read_frame(&mut stream) {
+    let command = LittleEndian::read_u16(&frame[0..2]);
+    let handle = LittleEndian::read_u32(&frame[4..8]);
+    let context = LittleEndian::read_u64(&frame[12..20]);
+
+    let reply = match command {
+      REGISTER_SESSION => {
+        session_handle = state.next_session_handle.fetch_add(1, Ordering::Relaxed);
+        eip::build_encapsulation(REGISTER_SESSION, session_handle, 0, context, &frame[24..])
+      },
+      UNREGISTER_SESSION => break,
+      LIST_IDENTITY => match &state.config.identity {
+        Some(identity) => identity::build_list_identity_reply(context, identity),
+        None => eip::build_encapsulation(command, handle, STATUS_INVALID_COMMAND, context, &[]),
+      },
+      SEND_RR_DATA if session_handle == 0 || handle != session_handle => {
+        eip::build_encapsulation(command, handle, STATUS_INVALID_SESSION, context, &[])
+      },
+      SEND_RR_DATA => {
+        let (cip_reply, to_sockaddr) = match eip::parse_send_rr_data_reply(&frame) {
+          Ok(cip) => handle_request(state, cip, peer, socket),
+          Err(_) => (eip::build_cip_error_reply(0, SERVICE_NOT_SUPPORTED, &[], None), None),
+        };
+        let mut reply = eip::build_send_rr_data_packet(session_handle, context, &cip_reply);
+        if let Some(to_sockaddr) = to_sockaddr {
+          eip::append_cpf_item(&mut reply, eip::CPF_SOCKADDR_TO, &eip::build_sockaddr_info(to_sockaddr));
+        }
+        reply
+      },
+      _ => eip::build_encapsulation(command, handle, STATUS_INVALID_COMMAND, context, &[]),
+    };
+
+    if stream.write_all(&reply).is_err() {
+      break;
+    }
+  }
+}
+
+/*
+Answer one explicit CIP request
+Also returns where a multicast T->O connection produces to, for the reply's
+sockaddr info item.
+*/
+fn handle_request(state: &Arc<TargetState>, cip: &[u8], peer: SocketAddr, socket: &Arc<UdpSocket>) -> (Vec<u8>, Option<SocketAddrV4>) {
+  let service = cip.first().copied().unwrap_or(0);
+
+  match service {
+    FORWARD_OPEN | LARGE_FORWARD_OPEN => match eip::parse_forward_open_request(cip) {
+      Ok(request) => forward_open(state, &request, peer, socket),
+      Err(_) => (eip::build_cip_error_reply(service, CONNECTION_FAILURE, &[0x0315], None), None),
+    },
+    FORWARD_CLOSE => match eip::parse_forward_close_request(cip) {
+      Ok(triple) => (forward_close(state, &triple), None),
+      Err(_) => (eip::build_cip_error_reply(service, CONNECTION_FAILURE, &[0x0315], None), None),
+    },
+    _ => (eip::build_cip_error_reply(service, SERVICE_NOT_SUPPORTED, &[], None), None),
+  }
+}
+
+/*
+Validate a Forward Open, and start producing if it's acceptable
+Returns the reply, and the group a multicast T->O connection produces to.
+*/
+fn forward_open(state: &Arc<TargetState>, request: &ForwardOpenRequest, peer: SocketAddr, socket: &Arc<UdpSocket>) -> (Vec<u8>, Option<SocketAddrV4>) {
+  let service = if request.large { LARGE_FORWARD_OPEN } else { FORWARD_OPEN };
+  let reject = |extended_status: u16| {
+    (eip::build_cip_error_reply(service, CONNECTION_FAILURE, &[extended_status], Some(&request.triple)), None)
+  };
+
+  // Find the tag, or the input assembly and the output one it's paired with
+  let (tag, buffer, output) = if let Some(tag) = eip::parse_symbolic_path(&request.path) {
+    match state.tags.read().unwrap().get(&tag) {
+      Some(buffer) => (tag, Arc::clone(buffer), None),
+      None => return reject(0x0117),
+    }
+  } else if let Some(assembly) = eip::parse_assembly_path(&request.path) {
+    let assemblies = state.assemblies.read().unwrap();
+    let buffer = match assemblies.get(&assembly.input) {
+      Some(buffer) => Arc::clone(buffer),
+      None => return reject(0x0117),
+    };
+    let heartbeat_points = [ConnectionKind::InputOnly, ConnectionKind::ListenOnly].map(|kind| kind.heartbeat_point().map(u16::from));
+    let output = if heartbeat_points.contains(&Some(assembly.output)) {
+      None
+    } else {
+      match assemblies.get(&assembly.output) {
+        Some(output) => Some(Arc::clone(output)),
+        None => return reject(0x0117),
+      }
+    };
+    (format!("Assembly {}", assembly.input), buffer, output)
+  } else {
+    return reject(0x0315);
+  };
+  let rejection = state.tag_faults.read().unwrap().get(&tag).and_then(|faults| faults.reject_forward_open.clone());
+  if let Some((general_status, extended_status)) = rejection {
+    return (eip::build_cip_error_reply(service, general_status, &extended_status, Some(&request.triple)), None);
+  }
+
+  // Check the connection against what we can produce
+  let config = &state.config;
+  let multicast = match request.to_connection_type() {
+    ConnectionType::PointToPoint => false,
+    ConnectionType::Multicast => true,
+    _ => return reject(0x0108),
+  };
+  let rpis = config.min_rpi..=config.max_rpi;
+  if !rpis.contains(&request.to_rpi) || !rpis.contains(&request.ot_rpi) {
+    return reject(0x0111);
+  }
+  let trigger = match request.trigger() {
+    Some(trigger) => trigger,
+    None => return reject(0x0103),
+  };
+  let rpi = Duration::from_micros(request.to_rpi.into());
+  let inhibit = request.production_inhibit().unwrap_or_default();
+  if inhibit > rpi {
+    return reject(0x011B);
+  }
+  let size = 2 + buffer.get().len();
+  if request.to_size() < size || (request.to_size() > size && !request.to_variable_size()) {
+    return reject(0x0109);
+  }
+  // O->T data may come with a run/idle header
+  let output = match output {
+    Some(output) => {
+      let size = 2 + output.get().len();
+      match request.ot_size() {
+        ot_size if ot_size == size + 4 => Some((output, true)),
+        ot_size if ot_size == size => Some((output, false)),
+        _ => return reject(0x0109),
+      }
+    },
+    None => None,
+  };
+
+  let mut connections = state.connections.lock().unwrap();
+  if connections.values().any(|con| con.triple == request.triple) {
+    return reject(0x0100);
+  }
+
+  // Allocate the O->T connection ID. The originator picked the T->O one if it's
+  // point-to-point; multicast ones are ours to pick, along with the group.
+  let ot_connection_id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+  let (to_connection_id, to_sockaddr) = if multicast {
+    let group = u32::from(config.multicast_base).wrapping_add(state.next_multicast_group.fetch_add(1, Ordering::Relaxed));
+    let to_sockaddr = SocketAddrV4::new(Ipv4Addr::from(group), config.originator_io_port);
+    (state.next_connection_id.fetch_add(1, Ordering::Relaxed), Some(to_sockaddr))
+  } else {
+    (request.to_connection_id, None)
+  };
+  let con = Arc::new(TargetConnection {
+    tag,
+    output,
+    peer,
+    triple: request.triple,
+    to_connection_id,
+    last_heard: Mutex::new(Instant::now()),
+    alive: AtomicBool::new(true),
+  });
+  connections.insert(ot_connection_id, Arc::clone(&con));
+  drop(connections);
+
+  let destination = match to_sockaddr {
+    Some(to_sockaddr) => SocketAddr::V4(to_sockaddr),
+    None => SocketAddr::new(peer.ip(), config.originator_io_port),
+  };
+  let timing = ProductionTiming {
+    rpi,
+    inhibit,
+    on_change: trigger != TransportTrigger::Cyclic,
+    timeout: Duration::from_micros(request.ot_rpi.into()) * (4 << (request.timeout_multiplier & 0x07)),
+  };
+  start_production_thread(state, ot_connection_id, con, buffer, socket, destination, timing);
+
+  (eip::build_cip_forward_open_reply(request, ot_connection_id, to_connection_id), to_sockaddr)
+}
+
+/*
+Close a connection by its triple
+*/
+fn forward_close(state: &Arc<TargetState>, triple: &ConnectionTriple) -> Vec<u8> {
+  let mut connections = state.connections.lock().unwrap();
+  let ot_connection_id = connections.iter()
+    .find(|(_, con)| con.triple == *triple)
+    .map(|(id, _)| *id);
+
+  match ot_connection_id.and_then(|id| connections.remove(&id)) {
+    Some(con) => {
+      con.alive.store(false, Ordering::Release);
+      eip::build_cip_forward_close_reply(triple)
+    },
+    None => eip::build_cip_error_reply(FORWARD_CLOSE, CONNECTION_FAILURE, &[0x0107], Some(triple)),
+  }
+}
+
+/*
+When a connection produces
+Cyclic connections produce every RPI. Change-of-state ones produce when the
+data changes, no sooner than the production inhibit time after the last
+packet, and otherwise once per RPI so the originator knows we're still here.
+timeout is how long the originator's heartbeats may stay away.
+*/
+struct ProductionTiming {
+  rpi: Duration,
+  inhibit: Duration,
+  on_change: bool,
+  timeout: Duration,
+}
+
+/*
+Send T->O packets until the connection is closed or times out
+*/
+fn start_production_thread(state: &Arc<TargetState>, ot_connection_id: u32, con: Arc<TargetConnection>, buffer: Arc<OutputBuffer>, socket: &Arc<UdpSocket>, destination: SocketAddr, timing: ProductionTiming) {
+  let state = Arc::clone(state);
+  let socket = Arc::clone(socket);
+
+  // Change-of-state connections look for changes as often as they may produce
+  let poll = if timing.on_change {
+    timing.inhibit.max(Duration::from_millis(1)).min(timing.rpi)
+  } else {
+    timing.rpi
+  };
+
+  thread::Builder::new().name(format!("Target production for {}", con.tag)).spawn(move || {
+    let mut sequence_count: u32 = 0;
+    let mut cip_sequence_count: u16 = 0;
+    let mut sent_version = None;
+    let mut last_sent = Instant::now();
+    let mut packets: u32 = 0;
+    let mut held: Option<Vec<u8>> = None;
+    let mut rng: Option<StdRng> = None;
+    let mut delayed = None;
+    let send = |msg: &[u8]| {
+      if let Err(e) = socket.send_to(msg, destination) {
+        eprintln!("Couldn't produce {} to {}: {}", con.tag, destination, e);
+      }
+    };
+
+    while con.alive.load(Ordering::Relaxed) && state.alive.load(Ordering::Relaxed) {
+      thread::sleep(poll);
+
+      // The originator stopped sending heartbeats
+      if con.last_heard.lock().unwrap().elapsed() > timing.timeout {
+        state.connections.lock().unwrap().remove(&ot_connection_id);
+        state.timeouts.fetch_add(1, Ordering::Relaxed);
+        break;
+      }
+
+      let (data, _, version) = buffer.snapshot();
+      let changed = sent_version != Some(version);
+      if timing.on_change && !changed && last_sent.elapsed() < timing.rpi {
+        continue;
+      }
+      if changed {
+        cip_sequence_count = cip_sequence_count.wrapping_add(1);
+        sent_version = Some(version);
+      }
+      last_sent = Instant::now();
+
+      sequence_count = sequence_count.wrapping_add(1);
+      let mut msg = eip::build_output_packet(con.to_connection_id, sequence_count, cip_sequence_count, None, &data);
+
+      // Injected faults
+      let faults = state.faults(ot_connection_id, &con.tag);
+      packets = packets.saturating_add(1);
+      if faults.reset_session_after == Some(packets) {
+        state.reset_session(con.peer);
+      }
+      if faults.stop_after.is_some_and(|count| packets > count) {
+        continue;
+      }
+      if faults.drop_every.is_some_and(|n| n > 0 && packets.is_multiple_of(n)) {
+        continue;
+      }
+      if let Some(len) = faults.truncate {
+        msg.truncate(len);
+      }
+      let msgs = if faults.reorder {
+        match held.take() {
+          Some(earlier) => vec![msg, earlier],
+          None => {
+            held = Some(msg);
+            vec![]
+          }
+        }
+      } else {
+        vec![msg]
+      };
+
+      for msg in msgs {
+        match faults.jitter {
+          // Delayed packets go out on their own, so the RPI keeps its pace
+          Some(jitter) => {
+            let rng = rng.get_or_insert_with(|| StdRng::seed_from_u64(faults.jitter_seed));
+            let send_at = Instant::now() + rng.gen_range(Duration::ZERO..=jitter);
+            let delayed = delayed.get_or_insert_with(|| start_delay_thread(&socket, destination, &con.tag));
+            let _ = delayed.send((send_at, msg));
+          },
+          None => send(&msg),
+        }
+      }
+    }
+  }).unwrap();
+}
+
+/*
+Send packets at the times they're given, in whatever order that makes
+The thread stops when the sender is dropped, taking any packets still waiting
+with it.
+*/
+fn start_delay_thread(socket: &Arc<UdpSocket>, destination: SocketAddr, tag: &str) -> mpsc::Sender<(Instant, Vec<u8>)> {
+  let socket = Arc::clone(socket);
+  let tag = String::from(tag);
+  let (sender, receiver) = mpsc::channel::<(Instant, Vec<u8>)>();
+
+  thread::Builder::new().name(format!("Target jitter for {}", tag)).spawn(move || {
+    let mut waiting = vec![];
+    loop {
+      let next = waiting.iter().map(|(send_at, _)| *send_at).min();
+      let timeout = next.map_or(Duration::from_secs(1), |send_at: Instant| send_at.saturating_duration_since(Instant::now()));
+      match receiver.recv_timeout(timeout) {
+        Ok(packet) => waiting.push(packet),
+        Err(mpsc::RecvTimeoutError::Timeout) => (),
+        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+      }
+
+      let now = Instant::now();
+      waiting.retain(|(send_at, msg)| {
+        if *send_at > now {
+          return true;
+        }
+        if let Err(e) = socket.send_to(msg, destination) {
+          eprintln!("Couldn't produce {} to {}: {}", tag, destination, e);
+        }
+        false
+      });
+    }
+  }).unwrap();
+
+  sender
+}
+
+#[cfg(test)]
+fn target_for_test(originator_io_port: u16) -> Target {
+  let mut target = Target::new(TargetConfig {
+    bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+    encap_port: 0,
+    io_port: 0,
+    originator_io_port,
+    ..TargetConfig::default()
+  });
+  target.start().unwrap();
+  target
+}
+
+#[cfg(test)]
+fn send_for_test(stream: &mut TcpStream, msg: &[u8]) -> Vec<u8> {
+  stream.write_all(msg).unwrap();
+  read_frame(stream).unwrap()
+}
+
+#[test]
+fn test_target_produces_published_tag() {
+  use crate::ConsumerHint;
+
+  let originator = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  originator.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  let target = target_for_test(originator.local_addr().unwrap().port());
+
+  let buffer = Arc::new(OutputBuffer::new(4));
+  buffer.set(&[1, 2, 3, 4]).unwrap();
+  target.publish("Count", &buffer);
+
+  // Register a session
+  let mut stream = TcpStream::connect(target.local_addr().unwrap()).unwrap();
+  let reply = send_for_test(&mut stream, &eip::build_register_session());
+  let session_handle = LittleEndian::read_u32(&reply[4..8]);
+  assert_ne!(session_handle, 0);
+
+  // Open a connection to the tag
+  let hint = ConsumerHint {
+    tag: String::from("Count"),
+    data_size: 6,
+    rpi: 10_000,
+    otrpi: 1_000_000,
+    ..ConsumerHint::default()
+  };
+  let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 5, &hint.forward_open_spec()).unwrap();
+  let reply = eip::parse_forward_open_reply(&send_for_test(&mut stream, &msg)).unwrap();
+  assert_eq!(target.connected_tags(), vec![String::from("Count")]);
+
+  // The tag's data arrives on the T->O connection
+  let mut buf = [0u8; 64];
+  let (size, _) = originator.recv_from(&mut buf).unwrap();
+  assert_eq!(LittleEndian::read_u32(&buf[6..10]), reply.to_connection_id);
+  assert_eq!(buf[20..size], [1, 2, 3, 4]);
+
+  // Close it again
+  let msg = eip::build_forward_close_packet(&crate::CipRoute::backplane(0), session_handle, 6, &reply.triple());
+  let cip = send_for_test(&mut stream, &msg);
+  assert_eq!(eip::parse_send_rr_data_reply(&cip).unwrap()[0..3], [0xCE, 0, 0]);
+  assert!(target.connected_tags().is_empty());
+}
+
+#[test]
+fn test_target_produces_multicast() {
+  use crate::{ConnectionType, ConsumerHint, ForwardOpenParams};
+
+  // Multicast only reaches sockets bound to the unspecified address
+  let originator = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+  originator.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  let target = target_for_test(originator.local_addr().unwrap().port());
+  target.publish("Count", &Arc::new(OutputBuffer::new(4)));
+
+  let mut stream = TcpStream::connect(target.local_addr().unwrap()).unwrap();
+  let reply = send_for_test(&mut stream, &eip::build_register_session());
+  let session_handle = LittleEndian::read_u32(&reply[4..8]);
+
+  // Logix consumed tags ask for multicast T->O by default
+  let hint = ConsumerHint {
+    tag: String::from("Count"),
+    data_size: 6,
+    rpi: 10_000,
+    otrpi: 1_000_000,
+    params: ForwardOpenParams::builder().to_connection_type(ConnectionType::Multicast).build(),
+    ..ConsumerHint::default()
+  };
+  let open = |stream: &mut TcpStream| {
+    let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+    eip::parse_forward_open_reply(&send_for_test(stream, &msg)).unwrap()
+  };
+  let first = open(&mut stream);
+  let second = open(&mut stream);
+  let to_sockaddr = first.to_sockaddr.unwrap();
+  assert!(to_sockaddr.ip().is_multicast());
+  assert_eq!(to_sockaddr.port(), originator.local_addr().unwrap().port());
+  assert_ne!(second.to_sockaddr, first.to_sockaddr);
+  assert_ne!(second.to_connection_id, first.to_connection_id);
+
+  // The tag's data arrives through the group
+  originator.join_multicast_v4(to_sockaddr.ip(), &Ipv4Addr::LOCALHOST).unwrap();
+  let mut buf = [0u8; 64];
+  loop {
+    let (size, _) = originator.recv_from(&mut buf).unwrap();
+    if LittleEndian::read_u32(&buf[6..10]) == first.to_connection_id {
+      assert_eq!(size, 24);
+      break;
+    }
+  }
+}
+
+#[test]
+fn test_target_rejects_bad_forward_open() {
+  use crate::{ConsumerHint, CipError};
+
+  let target = target_for_test(2222);
+  target.publish("Count", &Arc::new(OutputBuffer::new(4)));
+
+  let mut stream = TcpStream::connect(target.local_addr().unwrap()).unwrap();
+  let reply = send_for_test(&mut stream, &eip::build_register_session());
+  let session_handle = LittleEndian::read_u32(&reply[4..8]);
+
+  let forward_open = |tag: &str, data_size: usize, rpi: usize| {
+    let hint = ConsumerHint {
+      tag: String::from(tag),
+      data_size,
+      rpi,
+      otrpi: rpi,
+      ..ConsumerHint::default()
+    };
+    let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+    let error = eip::parse_forward_open_reply(&send_for_test(&mut stream.try_clone().unwrap(), &msg)).unwrap_err();
+    CipError::from_io(&error).unwrap().extended()
+  };
+
+  assert_eq!(forward_open("Missing", 6, 10_000), Some(0x0117));
+  assert_eq!(forward_open("Count", 8, 10_000), Some(0x0109));
+  assert_eq!(forward_open("Count", 6, 100), Some(0x0111));
+
+  // A T->O connection with nothing to carry
+  let hint = ConsumerHint {
+    tag: String::from("Count"),
+    data_size: 6,
+    rpi: 10_000,
+    otrpi: 10_000,
+    params: crate::ForwardOpenParams::builder()
+      .to_connection_type(crate::ConnectionType::Null)
+      .build(),
+    ..ConsumerHint::default()
+  };
+  let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+  let error = eip::parse_forward_open_reply(&send_for_test(&mut stream.try_clone().unwrap(), &msg)).unwrap_err();
+  assert_eq!(CipError::from_io(&error).unwrap().extended(), Some(0x0108));
+
+  // A production inhibit time longer than the RPI
+  let hint = ConsumerHint {
+    tag: String::from("Count"),
+    data_size: 6,
+    rpi: 10_000,
+    otrpi: 10_000,
+    params: crate::ForwardOpenParams::builder()
+      .trigger(TransportTrigger::ChangeOfState)
+      .production_inhibit(20)
+      .build(),
+    ..ConsumerHint::default()
+  };
+  let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+  let error = eip::parse_forward_open_reply(&send_for_test(&mut stream, &msg)).unwrap_err();
+  assert_eq!(CipError::from_io(&error).unwrap().extended(), Some(0x011B));
+}
+
+#[test]
+fn test_target_change_of_state() {
+  use crate::{ConsumerHint, ForwardOpenParams};
+
+  let originator = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let target = target_for_test(originator.local_addr().unwrap().port());
+  let buffer = Arc::new(OutputBuffer::new(2));
+  target.publish("Level", &buffer);
+
+  let mut stream = TcpStream::connect(target.local_addr().unwrap()).unwrap();
+  let reply = send_for_test(&mut stream, &eip::build_register_session());
+  let session_handle = LittleEndian::read_u32(&reply[4..8]);
+
+  // A slow RPI, so anything that arrives quickly was produced on change
+  let hint = ConsumerHint {
+    tag: String::from("Level"),
+    data_size: 4,
+    rpi: 5_000_000,
+    otrpi: 5_000_000,
+    params: ForwardOpenParams::builder()
+      .trigger(TransportTrigger::ChangeOfState)
+      .production_inhibit(5)
+      .build(),
+    ..ConsumerHint::default()
+  };
+  let msg = eip::build_forward_open_packet(&crate::CipRoute::backplane(0), session_handle, 1, &hint.forward_open_spec()).unwrap();
+  eip::parse_forward_open_reply(&send_for_test(&mut stream, &msg)).unwrap();
+
+  originator.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+  let mut buf = [0u8; 64];
+  let (size, _) = originator.recv_from(&mut buf).unwrap();
+  assert_eq!(buf[20..size], [0, 0]);
+
+  buffer.set(&[7, 0]).unwrap();
+  let (size, _) = originator.recv_from(&mut buf).unwrap();
+  assert_eq!(buf[20..size], [7, 0]);
+}